@@ -0,0 +1,72 @@
+//! The STDIN/STDOUT protocol between broai and a plugin, for plugins that
+//! support `"mode": "daemon"`. See ADDING_A_PLUGIN.md.
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Read, Write};
+
+/// Answer broai: one request read to the end of STDIN in one-shot mode, or
+/// one JSON-RPC 2.0 request per line until STDIN closes in daemon mode.
+/// The first line tells them apart. `fallback` stands in for a one-shot
+/// request that doesn't parse.
+pub fn run<Req, Resp>(fallback: impl FnOnce() -> Req, mut handle: impl FnMut(Req) -> Resp)
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    let mut first = String::new();
+    io::stdin().lock().read_line(&mut first).unwrap_or(0);
+    if serde_json::from_str::<Value>(&first)
+        .map(|v| v.get("jsonrpc").is_some())
+        .unwrap_or(false)
+    {
+        return serve(first, handle);
+    }
+    let mut input = first;
+    io::stdin().read_to_string(&mut input).unwrap_or(0);
+    let req = serde_json::from_str::<Req>(&input).unwrap_or_else(|_| fallback());
+    println!("{}", response(&handle(req)));
+}
+
+/// `"mode": "daemon"`: answer `run` and `ping`, flushing after each line.
+fn serve<Req, Resp>(first: String, mut handle: impl FnMut(Req) -> Resp)
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    let stdin = io::stdin();
+    let mut out = io::stdout().lock();
+    for line in std::iter::once(Ok(first)).chain(stdin.lock().lines()) {
+        let Ok(line) = line else { break };
+        let Ok(rpc) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        let mut reply = match rpc["method"].as_str() {
+            Some("run") => match serde_json::from_value::<Req>(rpc["params"].clone()) {
+                Ok(req) => json!({ "result": response(&handle(req)) }),
+                Err(e) => json!({"error": {"code": -32602, "message": e.to_string()}}),
+            },
+            Some("ping") => json!({"result": "pong"}),
+            _ => json!({"error": {"code": -32601, "message": "Method not found"}}),
+        };
+        reply["jsonrpc"] = json!("2.0");
+        reply["id"] = rpc["id"].clone();
+        if writeln!(out, "{}", reply)
+            .and_then(|_| out.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// `resp` as JSON, or a failed response if it can't be serialized.
+fn response<Resp: Serialize>(resp: &Resp) -> Value {
+    serde_json::to_value(resp).unwrap_or_else(|e| {
+        json!({
+            "success": false,
+            "result": null,
+            "error": format!("cannot serialize the response: {}", e),
+        })
+    })
+}
@@ -8,12 +8,12 @@ use std::{io::{self, Read}, process::Command, time::{SystemTime, UNIX_EPOCH}};
 fn main(){let mut i=String::new();io::stdin().read_to_string(&mut i).unwrap_or(0);let r=serde_json::from_str::<PluginRequest>(&i).unwrap_or(PluginRequest{action:"make-xlsx".into(),payload:json!({})});println!("{}",serde_json::to_string(&handle(r)).unwrap());}
 
 fn handle(req: PluginRequest)->PluginResponse{
- let cmd=req.payload.get("command").and_then(|v|v.as_str()).unwrap_or(req.action.as_str());
+ let cmd=req.action.as_str();
  match cmd {
   "sheet-template" => ok(json!({"templates":["inventory","timesheet","report"],"usage":"/make-xlsx <titolo>"})),
   "make-xlsx" => {
-    let title=req.payload.get("args").and_then(|v|v.as_str()).unwrap_or("Generated Sheet");
-    let name=format!("generated_{}.xlsx", now());
+    let title=req.payload.get("title").and_then(|v|v.as_str()).unwrap_or("Generated Sheet");
+    let name=format!("/var/lib/broai/data/generated_{}.xlsx", now());
     let py=format!(r#"import zipfile
 name={name:?}
 title={title:?}
@@ -16,7 +16,8 @@ struct PluginResponse {
     error: Option<String>,
 }
 
-// Only allow reading from these safe directories
+// Only allow reading from these safe directories, unless broai passes the
+// plugin's fs_read grants in BROAI_FS_READ
 const ALLOWED_DIRS: &[&str] = &[
     "/home/pi/documents",
     "/home/pi/data",
@@ -66,20 +67,29 @@ fn is_path_allowed(path: &Path) -> Result<PathBuf, String> {
         .map_err(|e| format!("Cannot resolve path '{}': {}", path.display(), e))?;
 
     // Check against whitelist
-    let allowed = ALLOWED_DIRS.iter().any(|dir| {
+    let allowed_dirs = allowed_dirs();
+    let allowed = allowed_dirs.iter().any(|dir| {
         canonical.starts_with(dir)
     });
 
     if !allowed {
+        let dirs: Vec<String> = allowed_dirs.iter().map(|d| d.display().to_string()).collect();
         return Err(format!(
             "Access denied. Allowed directories: {}",
-            ALLOWED_DIRS.join(", ")
+            dirs.join(", ")
         ));
     }
 
     Ok(canonical)
 }
 
+fn allowed_dirs() -> Vec<PathBuf> {
+    match std::env::var_os("BROAI_FS_READ") {
+        Some(dirs) if !dirs.is_empty() => std::env::split_paths(&dirs).collect(),
+        _ => ALLOWED_DIRS.iter().map(PathBuf::from).collect(),
+    }
+}
+
 fn read_file(payload: &Value) -> PluginResponse {
     let path_str = match payload.get("path").and_then(|v| v.as_str()) {
         Some(p) => p,
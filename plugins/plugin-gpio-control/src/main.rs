@@ -3,22 +3,23 @@ use serde_json::{json, Value};
 use std::{io::{self, Read}, path::Path, process::Command};
 
 #[derive(Debug, Deserialize)]
-struct PluginRequest { action: String, payload: Value }
+struct PluginRequest { payload: Value }
 #[derive(Debug, Serialize)]
 struct PluginResponse { success: bool, result: Value, error: Option<String> }
 
 fn main() {
     let mut input = String::new();
     io::stdin().read_to_string(&mut input).unwrap_or(0);
-    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{action:"gpio".into(),payload:json!({})});
+    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{payload:json!({})});
     println!("{}", serde_json::to_string(&handle(req)).unwrap());
 }
 
 fn handle(req: PluginRequest) -> PluginResponse {
-    let args = req.payload.get("args").and_then(|v| v.as_str()).unwrap_or("");
-    let parts: Vec<&str> = args.split_whitespace().collect();
-    let sub = parts.first().copied().unwrap_or("read");
-    let pin = parts.get(1).and_then(|s| s.parse::<u8>().ok()).unwrap_or(17);
+    let sub = req.payload.get("state").and_then(|v| v.as_str()).unwrap_or("read");
+    let pin = match req.payload.get("pin").and_then(|v| v.as_u64()).unwrap_or(17) {
+        p if p <= 53 => p as u8,
+        p => return err(&format!("Invalid GPIO pin {p}")),
+    };
 
     if Path::new("/usr/bin/raspi-gpio").exists() {
         let cmd = match sub {
@@ -8,8 +8,8 @@ use std::{io::{self, Read}, net::{TcpStream, ToSocketAddrs}, process::Command, t
 fn main(){let mut i=String::new();io::stdin().read_to_string(&mut i).unwrap_or(0);let r=serde_json::from_str::<PluginRequest>(&i).unwrap_or(PluginRequest{action:"ping".into(),payload:json!({})});println!("{}",serde_json::to_string(&handle(r)).unwrap());}
 
 fn handle(req: PluginRequest)->PluginResponse{
- let cmd=req.payload.get("command").and_then(|v|v.as_str()).unwrap_or(req.action.as_str());
- let args=req.payload.get("args").and_then(|v|v.as_str()).unwrap_or("");
+ let cmd=req.action.as_str();
+ let args=req.payload.get("target").and_then(|v|v.as_str()).unwrap_or("");
  let target=if args.is_empty(){"8.8.8.8:53"}else{args};
  let result=match cmd{
   "ping"=>json!({"target":target,"reachable":tcp(target).is_ok()}),
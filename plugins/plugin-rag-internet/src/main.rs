@@ -15,8 +15,8 @@ fn main() {
 }
 
 fn handle(req: PluginRequest) -> PluginResponse {
-    let command = req.payload.get("command").and_then(|v| v.as_str()).unwrap_or(req.action.as_str());
-    let q = req.payload.get("args").and_then(|v| v.as_str()).unwrap_or("").trim();
+    let command = req.action.as_str();
+    let q = req.payload.get("query").and_then(|v| v.as_str()).unwrap_or("").trim();
     if q.is_empty() { return err("Usage: /web-search <query> or /web-rag <query>"); }
 
     match search(q) {
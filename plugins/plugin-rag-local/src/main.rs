@@ -1,42 +1,44 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{collections::HashMap, fs, io::{self, Read}, path::{Path, PathBuf}};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, time::SystemTime};
 
-const KB_DIR: &str = "./docs";
+const KB_DIR: &str = "/var/lib/broai/docs";
 
 #[derive(Debug, Deserialize)]
 struct PluginRequest { action: String, payload: Value }
 #[derive(Debug, Serialize)]
 struct PluginResponse { success: bool, result: Value, error: Option<String> }
 
+/// An embedded document, kept until the file's modification time changes.
+struct Doc { modified: Option<SystemTime>, snippet: String, vector: HashMap<String, f64> }
+
+/// Documents embedded so far. In daemon mode it lives across calls, so only
+/// new or changed files are read again.
+#[derive(Default)]
+struct Index { docs: HashMap<PathBuf, Doc> }
+
 fn main() {
-    let mut input = String::new();
-    io::stdin().read_to_string(&mut input).unwrap_or(0);
-    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest { action: "kb".into(), payload: json!({}) });
-    println!("{}", serde_json::to_string(&handle(req)).unwrap());
+    let mut index = Index::default();
+    broai_plugin::run(|| PluginRequest { action: "kb".into(), payload: json!({}) }, |req| handle(req, &mut index));
 }
 
-fn handle(req: PluginRequest) -> PluginResponse {
-    let cmd = req.payload.get("command").and_then(|v| v.as_str()).unwrap_or(req.action.as_str());
+fn handle(req: PluginRequest, index: &mut Index) -> PluginResponse {
+    let cmd = req.action.as_str();
     match cmd {
         "kb" => {
             let found = list_files(Path::new(KB_DIR)).into_iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
             ok(json!({"kb_path": KB_DIR, "documents": found.len(), "files": found}))
         }
         "search-doc" => {
-            let q = req.payload.get("args").and_then(|v| v.as_str()).unwrap_or("").trim();
+            let q = req.payload.get("query").and_then(|v| v.as_str()).unwrap_or("").trim();
             if q.is_empty() { return err("Usage: /search-doc <query>"); }
             let qv = embed(q);
-            let mut scored = vec![];
-            for path in list_files(Path::new(KB_DIR)) {
-                if let Ok(content) = fs::read_to_string(&path) {
-                    scored.push(json!({
-                        "path": path.display().to_string(),
-                        "score": cosine(&qv, &embed(&content)),
-                        "snippet": content.chars().take(220).collect::<String>()
-                    }));
-                }
-            }
+            index.refresh();
+            let mut scored = index.docs.iter().map(|(path, doc)| json!({
+                "path": path.display().to_string(),
+                "score": cosine(&qv, &doc.vector),
+                "snippet": doc.snippet
+            })).collect::<Vec<_>>();
             scored.sort_by(|a, b| b["score"].as_f64().partial_cmp(&a["score"].as_f64()).unwrap());
             ok(json!({"query": q, "results": scored.into_iter().take(5).collect::<Vec<_>>() }))
         }
@@ -44,6 +46,25 @@ fn handle(req: PluginRequest) -> PluginResponse {
     }
 }
 
+impl Index {
+    /// Match the index to the files now in `KB_DIR`.
+    fn refresh(&mut self) {
+        let files = list_files(Path::new(KB_DIR));
+        self.docs.retain(|path, _| files.contains(path));
+        for path in files {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            if self.docs.get(&path).is_some_and(|doc| modified.is_some() && doc.modified == modified) { continue; }
+            match fs::read_to_string(&path) {
+                Ok(content) => {
+                    let doc = Doc { modified, snippet: content.chars().take(220).collect(), vector: embed(&content) };
+                    self.docs.insert(path, doc);
+                }
+                Err(_) => { self.docs.remove(&path); }
+            }
+        }
+    }
+}
+
 fn list_files(root: &Path) -> Vec<PathBuf> {
     let mut out = vec![];
     if !root.exists() { return out; }
@@ -2,9 +2,9 @@ use chrono::Utc;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{io::{self, Read}, path::Path};
+use std::path::Path;
 
-const DB_PATH: &str = "./scheduler.db";
+const DB_PATH: &str = "/var/lib/broai/scheduler/scheduler.db";
 
 #[derive(Debug, Deserialize)]
 struct PluginRequest { action: String, payload: Value }
@@ -12,21 +12,23 @@ struct PluginRequest { action: String, payload: Value }
 struct PluginResponse { success: bool, result: Value, error: Option<String> }
 
 fn main() {
-    let mut input = String::new();
-    io::stdin().read_to_string(&mut input).unwrap_or(0);
-    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{action:"remind".into(),payload:json!({})});
-    println!("{}", serde_json::to_string(&handle(req)).unwrap());
+    // Opened on first use; in daemon mode it stays open across calls
+    let mut db = None;
+    broai_plugin::run(|| PluginRequest{action:"remind".into(),payload:json!({})}, |req| handle(req, &mut db));
 }
 
-fn handle(req: PluginRequest) -> PluginResponse {
-    let conn = match init_db() {
-        Ok(c) => c,
-        Err(e) => return PluginResponse{success:false,result:Value::Null,error:Some(e)},
+fn handle(req: PluginRequest, db: &mut Option<Connection>) -> PluginResponse {
+    let conn = match db {
+        Some(c) => c,
+        None => match init_db() {
+            Ok(c) => db.insert(c),
+            Err(e) => return PluginResponse{success:false,result:Value::Null,error:Some(e)},
+        },
     };
-    let command = req.payload.get("command").and_then(|v| v.as_str()).unwrap_or(req.action.as_str());
+    let command = req.action.as_str();
     match command {
         "remind" => {
-            let text = req.payload.get("args").and_then(|v| v.as_str()).unwrap_or("").trim();
+            let text = req.payload.get("text").and_then(|v| v.as_str()).unwrap_or("").trim();
             if text.is_empty() { return err("Usage: /remind <text>"); }
             let now = Utc::now().to_rfc3339();
             if let Err(e) = conn.execute("INSERT INTO jobs(task, created_at, done) VALUES (?1, ?2, 0)", params![text, now]) {
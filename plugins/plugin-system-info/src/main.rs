@@ -3,18 +3,18 @@ use serde_json::{json, Value};
 use std::{fs, io::{self, Read}, process::Command};
 
 #[derive(Debug, Deserialize)]
-struct PluginRequest { action: String, payload: Value }
+struct PluginRequest { action: String }
 #[derive(Debug, Serialize)]
 struct PluginResponse { success: bool, result: Value, error: Option<String> }
 
 fn main() {
     let mut input = String::new(); io::stdin().read_to_string(&mut input).unwrap_or(0);
-    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{action:"sysinfo".into(),payload:json!({})});
+    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{action:"sysinfo".into()});
     println!("{}", serde_json::to_string(&handle(req)).unwrap());
 }
 
 fn handle(req: PluginRequest) -> PluginResponse {
-    let command = req.payload.get("command").and_then(|v| v.as_str()).unwrap_or(req.action.as_str());
+    let command = req.action.as_str();
     let mem = read_meminfo();
     let disks = Command::new("bash").args(["-lc", "df -B1 --output=target,size,avail | tail -n +2"]).output().ok().map(|o| String::from_utf8_lossy(&o.stdout).to_string()).unwrap_or_default();
     let result = match command {
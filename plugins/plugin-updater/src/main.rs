@@ -3,19 +3,19 @@ use serde_json::{json, Value};
 use std::{io::{self, Read}, process::Command};
 
 #[derive(Debug, Deserialize)]
-struct PluginRequest { action: String, payload: Value }
+struct PluginRequest { action: String }
 #[derive(Debug, Serialize)]
 struct PluginResponse { success: bool, result: Value, error: Option<String> }
 
 fn main() {
     let mut input = String::new();
     io::stdin().read_to_string(&mut input).unwrap_or(0);
-    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{action:"update-check".into(), payload: json!({})});
+    let req = serde_json::from_str::<PluginRequest>(&input).unwrap_or(PluginRequest{action:"update-check".into()});
     println!("{}", serde_json::to_string(&handle(req)).unwrap());
 }
 
 fn handle(req: PluginRequest) -> PluginResponse {
-    let command = req.payload.get("command").and_then(|v| v.as_str()).unwrap_or(req.action.as_str());
+    let command = req.action.as_str();
     match command {
         "update-check" => ok(json!({
             "safe_mode": true,
@@ -8,12 +8,12 @@ use std::{io::{self, Read}, process::Command, time::{SystemTime, UNIX_EPOCH}};
 fn main(){let mut i=String::new();io::stdin().read_to_string(&mut i).unwrap_or(0);let r=serde_json::from_str::<PluginRequest>(&i).unwrap_or(PluginRequest{action:"make-docx".into(),payload:json!({})});println!("{}",serde_json::to_string(&handle(r)).unwrap());}
 
 fn handle(req: PluginRequest)->PluginResponse{
- let cmd=req.payload.get("command").and_then(|v|v.as_str()).unwrap_or(req.action.as_str());
+ let cmd=req.action.as_str();
  match cmd {
   "doc-template" => ok(json!({"templates":["report","verbale","lettera"],"usage":"/make-docx <contenuto>"})),
   "make-docx" => {
-    let text=req.payload.get("args").and_then(|v|v.as_str()).unwrap_or("Documento generato da BroAi");
-    let name=format!("generated_{}.docx", now());
+    let text=req.payload.get("text").and_then(|v|v.as_str()).unwrap_or("Documento generato da BroAi");
+    let name=format!("/var/lib/broai/data/generated_{}.docx", now());
     let py = format!(r#"import zipfile
 name={name:?}
 text={text:?}
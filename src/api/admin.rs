@@ -0,0 +1,13 @@
+use axum::{extract::State, Json};
+use tracing::info;
+
+use crate::api::AppState;
+use crate::errors::AppError;
+use crate::plugins::ReloadReport;
+
+/// POST /admin/plugins/reload — rescan the plugin directory now. Needs the
+/// `admin` scope, checked by the auth layer.
+pub async fn reload_plugins(State(state): State<AppState>) -> Result<Json<ReloadReport>, AppError> {
+    info!("Plugin reload requested via admin API");
+    Ok(Json(state.plugins.reload(&state.memory, "admin").await))
+}
@@ -0,0 +1,290 @@
+use std::fmt;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::RngCore;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::errors::AppError;
+use crate::security::{constant_time_eq, sha256};
+
+/// Every generated key starts with this, so a leaked one is easy to spot.
+const KEY_PREFIX: &str = "broai_";
+
+// ─── Scopes ──────────────────────────────────────────────────────────────────
+
+/// Something an API key is allowed to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    /// Model inference through /v1/chat/completions.
+    Chat,
+    /// Run the named plugin, or every plugin with `plugins:*`.
+    Plugin(String),
+    /// The /admin endpoints.
+    Admin,
+    /// Scraping /metrics.
+    Metrics,
+}
+
+impl Scope {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text {
+            "chat" => Ok(Self::Chat),
+            "admin" => Ok(Self::Admin),
+            "metrics" => Ok(Self::Metrics),
+            _ => match text.strip_prefix("plugins:") {
+                Some(name) if name == "*" || is_plugin_name(name) => Ok(Self::Plugin(name.to_string())),
+                _ => Err(format!("unknown scope `{}`; use chat, admin, metrics, plugins:<name> or plugins:*", text)),
+            },
+        }
+    }
+
+    /// Whether holding `self` grants `wanted`.
+    fn grants(&self, wanted: &Scope) -> bool {
+        match (self, wanted) {
+            (Self::Plugin(held), Self::Plugin(name)) => held == "*" || held == name,
+            _ => self == wanted,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Chat => f.write_str("chat"),
+            Self::Plugin(name) => write!(f, "plugins:{}", name),
+            Self::Admin => f.write_str("admin"),
+            Self::Metrics => f.write_str("metrics"),
+        }
+    }
+}
+
+fn is_plugin_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parse a comma- or space-separated scope list.
+pub fn parse_scopes(text: &str) -> Result<Vec<Scope>, String> {
+    let scopes = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(Scope::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if scopes.is_empty() {
+        return Err("at least one scope is required".into());
+    }
+    Ok(scopes)
+}
+
+// ─── Callers ─────────────────────────────────────────────────────────────────
+
+/// Who sent a request; [`require_key`] attaches it as a request extension.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// Key name, `ADMIN_TOKEN`, or `anonymous` when `API_AUTH` is off.
+    pub name: String,
+    scopes: Vec<Scope>,
+}
+
+impl Caller {
+    pub const ANONYMOUS: &'static str = "anonymous";
+
+    /// Requests without a key when `API_AUTH` is off: chat, every plugin and
+    /// metrics, no admin.
+    fn anonymous() -> Self {
+        Self {
+            name: Self::ANONYMOUS.into(),
+            scopes: vec![Scope::Chat, Scope::Plugin("*".into()), Scope::Metrics],
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new(name: &str, scopes: Vec<Scope>) -> Self {
+        Self { name: name.into(), scopes }
+    }
+
+    pub fn allows(&self, scope: &Scope) -> bool {
+        self.scopes.iter().any(|held| held.grants(scope))
+    }
+
+    pub fn may_run(&self, plugin: &str) -> bool {
+        self.allows(&Scope::Plugin(plugin.to_string()))
+    }
+}
+
+// ─── Keys ────────────────────────────────────────────────────────────────────
+
+/// A new random key: `broai_` followed by 32 random bytes in hex.
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", KEY_PREFIX, hex::encode(bytes))
+}
+
+/// What the database stores in place of a key. Keys are random and long, so
+/// a plain SHA-256 is enough; there is nothing to brute-force.
+pub fn hash_key(key: &str) -> String {
+    hex::encode(sha256(key.as_bytes()))
+}
+
+// ─── Middleware ──────────────────────────────────────────────────────────────
+
+/// Authenticate `Authorization: Bearer <key>` and attach the [`Caller`].
+/// /admin and /metrics need their scope here; the chat handler checks the
+/// `chat` and `plugins:*` scopes once it knows what the request does.
+pub async fn require_key(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let path = req.uri().path().to_string();
+    let caller = match authenticate(&state, req.headers()).await {
+        Ok(caller) => caller,
+        Err(e) => {
+            warn!(path = %path, error = %e, "Request rejected");
+            audit(&state, "auth_denied", json!({ "path": path, "reason": e.to_string() })).await;
+            return Err(e);
+        }
+    };
+    if let Some(scope) = route_scope(&path) {
+        authorize(&state, &caller, &scope).await?;
+    }
+    req.extensions_mut().insert(caller);
+    Ok(next.run(req).await)
+}
+
+/// Check that `caller` holds `scope`. Denials and admin access go to the audit
+/// log; routine allowed requests don't, or it would grow with every chat turn.
+pub async fn authorize(state: &AppState, caller: &Caller, scope: &Scope) -> Result<(), AppError> {
+    let allowed = caller.allows(scope);
+    if allowed && *scope != Scope::Admin {
+        return Ok(());
+    }
+    let event = if allowed { "scope_allowed" } else { "scope_denied" };
+    audit(state, event, json!({ "key": caller.name, "scope": scope.to_string() })).await;
+    if allowed {
+        return Ok(());
+    }
+    warn!(key = %caller.name, scope = %scope, "Request denied: missing scope");
+    Err(AppError::SecurityError(format!("key `{}` does not have the `{}` scope", caller.name, scope)))
+}
+
+/// The scope a whole route needs, if it is not checked by its handler.
+fn route_scope(path: &str) -> Option<Scope> {
+    if path.starts_with("/admin/") {
+        Some(Scope::Admin)
+    } else if path == "/metrics" {
+        Some(Scope::Metrics)
+    } else {
+        None
+    }
+}
+
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Caller, AppError> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    let Some(token) = token else {
+        if !state.api_auth {
+            return Ok(Caller::anonymous());
+        }
+        return Err(AppError::Unauthorized("missing API key; send Authorization: Bearer <key>".into()));
+    };
+
+    if let Some(admin) = &state.admin_token {
+        if constant_time_eq(token.as_bytes(), admin.as_bytes()) {
+            return Ok(Caller { name: "ADMIN_TOKEN".into(), scopes: vec![Scope::Admin] });
+        }
+    }
+    match state.memory.find_api_key(&hash_key(token)).await? {
+        // Scopes were validated when the key was created.
+        Some((name, scopes)) => Ok(Caller {
+            name,
+            scopes: scopes.split_whitespace().filter_map(|s| Scope::parse(s).ok()).collect(),
+        }),
+        None => Err(AppError::Unauthorized("invalid or revoked API key".into())),
+    }
+}
+
+async fn audit(state: &AppState, event: &str, payload: Value) {
+    if let Err(e) = state.memory.log_audit(event, Some(&payload.to_string())).await {
+        warn!(error = %e, event, "Failed to write audit log");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str) -> Scope {
+        Scope::Plugin(name.into())
+    }
+
+    #[test]
+    fn parses_every_scope_and_round_trips_it() {
+        for text in ["chat", "admin", "metrics", "plugins:*", "plugins:my-plugin_2"] {
+            assert_eq!(Scope::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_scopes_and_bad_plugin_names() {
+        for text in ["Chat", "plugins", "plugins:", "plugins:a/b", "plugins:**", "plugin:x", "*"] {
+            assert!(Scope::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn scope_lists_take_commas_or_spaces() {
+        assert_eq!(parse_scopes("chat, plugins:*  metrics").unwrap(), [Scope::Chat, plugin("*"), Scope::Metrics]);
+        assert!(parse_scopes(" , ").is_err());
+        assert!(parse_scopes("chat,nope").is_err());
+    }
+
+    #[test]
+    fn plugin_wildcard_grants_every_plugin_only() {
+        let caller = Caller::new("k", vec![plugin("*")]);
+        assert!(caller.may_run("weather"));
+        assert!(caller.may_run("datetime"));
+        assert!(!caller.allows(&Scope::Chat));
+
+        let caller = Caller::new("k", vec![Scope::Chat, plugin("weather")]);
+        assert!(caller.may_run("weather"));
+        assert!(!caller.may_run("datetime"));
+        // A plugin literally named `*` is not a wildcard request.
+        assert!(!caller.allows(&plugin("*")));
+    }
+
+    #[test]
+    fn admin_is_never_implied() {
+        let caller = Caller::new("k", vec![Scope::Chat, plugin("*"), Scope::Metrics]);
+        assert!(!caller.allows(&Scope::Admin));
+        let admin = Caller::new("k", vec![Scope::Admin]);
+        assert!(!admin.allows(&Scope::Chat));
+        assert!(!admin.may_run("weather"));
+    }
+
+    #[test]
+    fn anonymous_callers_get_everything_but_admin() {
+        let caller = Caller::anonymous();
+        assert_eq!(caller.name, Caller::ANONYMOUS);
+        assert!(caller.allows(&Scope::Chat));
+        assert!(caller.allows(&Scope::Metrics));
+        assert!(caller.may_run("weather"));
+        assert!(!caller.allows(&Scope::Admin));
+    }
+
+    #[test]
+    fn only_admin_and_metrics_routes_are_scoped_by_path() {
+        assert_eq!(route_scope("/admin/keys"), Some(Scope::Admin));
+        assert_eq!(route_scope("/metrics"), Some(Scope::Metrics));
+        assert_eq!(route_scope("/v1/chat/completions"), None);
+        assert_eq!(route_scope("/administrator"), None);
+    }
+}
@@ -1,13 +1,34 @@
-use axum::{extract::State, Json};
-use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{extract::{ConnectInfo, State}, Extension, Json};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use chrono::Utc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 use tracing::{info, warn, instrument};
 
 use crate::api::AppState;
+use crate::api::auth::{authorize, Caller, Scope};
+use crate::api::context::{fit_to_context, Summarizer};
+use crate::api::limits::Meter;
+use crate::api::tools::{
+    prompt_turn, run_agent, with_tools_prompt, Tool, ToolCall, ToolChoice, ToolSet, TOOL_RESPONSE_OPEN,
+};
 use crate::errors::AppError;
+use crate::llm::{
+    FinishReason, Grammar, InferEvent, InferParams, InferStream, LlmActor, Priority, QueueOptions,
+    QueuePosition, SamplingOverrides, TokenUsage,
+};
 use crate::memory::ConversationEntry;
-use crate::plugins::{PluginRequest, PluginRunner};
+use crate::metrics;
+use crate::plugins::{PluginManifest, PluginMode, PluginRegistry, PluginRequest, PluginResponse, PluginRunner};
 
 // ─── Request / Response types ─────────────────────────────────────────────────
 
@@ -17,21 +38,127 @@ pub struct ChatRequest {
     pub messages: Vec<ChatMessage>,
     #[serde(default = "default_max_tokens")]
     pub max_tokens: u32,
-    #[serde(default = "default_temperature")]
-    pub temperature: f32,
+    /// temperature, top_p, top_k, min_p, penalties, seed and mirostat settings;
+    /// unset fields use the server's per-model defaults.
+    #[serde(flatten)]
+    pub sampling: SamplingOverrides,
     #[serde(default)]
     pub stream: bool,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
     pub session_id: Option<String>,
+    /// Rebuild earlier turns of `session_id` from server-side memory, so the
+    /// client only needs to send the new message.
+    #[serde(default)]
+    pub use_memory: bool,
+    /// When history has to be trimmed to fit the context window, replace the
+    /// dropped turns with a summary instead of forgetting them.
+    #[serde(default)]
+    pub context_summary: bool,
+    /// Up to four sequences that end generation (not included in the output).
+    #[serde(default)]
+    pub stop: Option<StopSequences>,
+    /// Constrain the answer to JSON, optionally matching a schema.
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Raw GBNF grammar the answer must match (instead of `response_format`).
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// Functions the model may call; calls are returned in `tool_calls`.
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    /// Offer every registered plugin as a tool and run the calls server-side
+    /// until the model answers.
+    #[serde(default)]
+    pub agent: bool,
+    /// `interactive` (default), `batch` or `background`.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Seconds to wait for the model when the queue is full, instead of
+    /// failing at once; also bounds the time spent queued.
+    #[serde(default)]
+    pub max_queue_wait: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JsonSchemaFormat {
+    pub schema: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct StreamOptions {
+    /// Send a final chunk with an empty `choices` array and the `usage` block.
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+/// OpenAI accepts `stop` as either a single string or an array of strings.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSequences::One(s) => vec![s],
+            StopSequences::Many(v) => v,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChatMessage {
     pub role: String,
+    /// `null` in assistant messages that only carry tool calls.
+    #[serde(default, deserialize_with = "null_as_empty", serialize_with = "empty_as_null")]
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Set on `tool` messages: the call this is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
+impl ChatMessage {
+    pub fn new(role: &str, content: impl Into<String>) -> Self {
+        Self { role: role.to_string(), content: content.into(), tool_calls: None, tool_call_id: None }
+    }
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(d)?.unwrap_or_default())
+}
+
+fn empty_as_null<S: Serializer>(content: &str, s: S) -> Result<S::Ok, S::Error> {
+    if content.is_empty() { s.serialize_none() } else { s.serialize_str(content) }
+}
+
+/// Same limit as the OpenAI API.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// How many stored turns `use_memory` pulls back into the prompt.
+const MEMORY_HISTORY_TURNS: u32 = 20;
+/// With `context_summary`, how far back `use_memory` looks for turns the
+/// summary doesn't cover yet, so they are folded in rather than forgotten.
+const MEMORY_SUMMARY_TURNS: u32 = 200;
+
+/// Upper bound for `max_queue_wait`.
+const MAX_QUEUE_WAIT_SECS: u64 = 300;
+
 fn default_max_tokens() -> u32 { 512 }
-fn default_temperature() -> f32 { 0.7 }
 
 #[derive(Debug, Serialize)]
 pub struct ChatResponse {
@@ -57,72 +184,193 @@ pub struct Usage {
     pub total_tokens: u32,
 }
 
+impl From<TokenUsage> for Usage {
+    fn from(u: TokenUsage) -> Self {
+        Self {
+            prompt_tokens: u.prompt_tokens,
+            completion_tokens: u.completion_tokens,
+            total_tokens: u.total_tokens(),
+        }
+    }
+}
+
+/// One SSE frame of a streamed completion (`stream: true`).
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkChoice {
+    pub index: u32,
+    pub delta: ChatDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ToolCallDelta {
+    pub index: u32,
+    #[serde(flatten)]
+    pub call: ToolCall,
+}
+
+/// A complete answer, ready to be sent as JSON or as a one-shot SSE stream.
+struct Reply {
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    finish_reason: &'static str,
+    usage: TokenUsage,
+    /// Where the model call stood in the queue; `None` for plugin replies and agent runs.
+    queued: Option<QueuePosition>,
+}
+
 // ─── Handler ─────────────────────────────────────────────────────────────────
 
-#[instrument(skip(state, req), fields(model = %req.model))]
+#[instrument(skip(state, caller, req), fields(model = %req.model, key = %caller.name))]
 pub async fn chat_completions(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     Json(req): Json<ChatRequest>,
-) -> Result<Json<ChatResponse>, AppError> {
+) -> Result<Response, AppError> {
+    // Scope first, so a refused request doesn't use up the caller's quota.
+    let plugins = state.plugins.current();
+    if let Some(scope) = required_scope(&plugins, &req) {
+        authorize(&state, &caller, &scope).await?;
+    }
+    let subject = state.limits.subject(&caller, req.session_id.as_deref(), client.ip());
+    let (quota, meter) = state.limits.admit(&subject).await?;
+    let mut response = complete(state, plugins, caller, req, meter).await.into_response();
+    quota.write_headers(response.headers_mut());
+    Ok(response)
+}
+
+/// The scope `req` needs: `chat` for inference, the plugin's own scope for a
+/// plugin command, none for `/help` and unknown commands.
+fn required_scope(plugins: &PluginRegistry, req: &ChatRequest) -> Option<Scope> {
+    match extract_command(&req.messages) {
+        None => Some(Scope::Chat),
+        Some((command, _)) if command == "help" => None,
+        Some((command, args)) => plugins
+            .resolve(&command, &args)
+            .map(|route| Scope::Plugin(route.manifest.name.clone())),
+    }
+}
+
+/// Answer `req`, whose scope [`required_scope`] has already checked against `plugins`.
+async fn complete(
+    state: AppState,
+    plugins: Arc<PluginRegistry>,
+    caller: Caller,
+    req: ChatRequest,
+    meter: Meter,
+) -> Result<Response, AppError> {
     if req.messages.is_empty() {
         return Err(AppError::InvalidRequest("messages cannot be empty".into()));
     }
-    if req.stream {
-        return Err(AppError::InvalidRequest(
-            "Streaming not yet supported. Set stream=false.".into(),
-        ));
+    if req.use_memory && req.session_id.is_none() {
+        return Err(AppError::InvalidRequest("use_memory requires a session_id".into()));
+    }
+    // A summary is only worth writing if a later request can find it.
+    if req.context_summary && req.session_id.is_none() {
+        return Err(AppError::InvalidRequest("context_summary requires a session_id".into()));
+    }
+    let sampling = state.llm.sampling_defaults().with_overrides(&req.sampling)?;
+    let mut stop = req.stop.clone().map(StopSequences::into_vec).unwrap_or_default();
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(AppError::InvalidRequest(format!(
+            "stop accepts at most {} sequences",
+            MAX_STOP_SEQUENCES
+        )));
+    }
+    if stop.iter().any(|s| s.is_empty()) {
+        return Err(AppError::InvalidRequest("stop sequences cannot be empty".into()));
+    }
+    if req.max_queue_wait.is_some_and(|w| w > MAX_QUEUE_WAIT_SECS) {
+        return Err(AppError::InvalidRequest(format!(
+            "max_queue_wait is at most {} seconds",
+            MAX_QUEUE_WAIT_SECS
+        )));
     }
+    let tools = if req.agent {
+        if !req.tools.is_empty() || req.tool_choice.is_some() {
+            return Err(AppError::InvalidRequest(
+                "agent mode uses the plugin registry; do not send tools".into(),
+            ));
+        }
+        ToolSet::for_plugins(&plugins, &caller)
+    } else {
+        ToolSet::from_request(&req.tools, req.tool_choice.as_ref())?
+    };
+    let grammar = match (request_grammar(&req)?, &tools) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::InvalidRequest(
+                "tools cannot be combined with response_format or grammar".into(),
+            ))
+        }
+        (grammar, None) => grammar,
+        (None, Some(tools)) => tools.grammar()?,
+    };
 
-    let session_id = req.session_id.clone()
-        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let session_id = session_key(&caller, &req.session_id.clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string()));
+    // Only client-chosen ids are worth a KV-cache slot or a summary; generated ones never recur.
+    let named_session = req.session_id.as_ref().map(|_| session_id.as_str());
 
-    info!(session_id = %session_id, "Processing chat request");
+    info!(session_id = %session_id, priority = req.priority.as_str(), "Processing chat request");
 
     // ── Check if the last user message is a /command ──────────────────────
     if let Some((command, args)) = extract_command(&req.messages) {
-
-        // Special built-in: /help — lists all registered plugins
+        // Special built-in: /help — lists the plugins this key may run
         if command == "help" {
-            let lines: Vec<String> = state.plugins.commands()
+            let lines: Vec<String> = plugins.commands()
                 .iter()
-                .map(|(cmd, desc)| format!("  /{:<20} {}", cmd, desc))
+                .filter(|(_, m)| caller.may_run(&m.name))
+                .map(|(cmd, m)| format!("  {:<32} {}", m.usage(cmd), m.description))
                 .collect();
             let content = format!(
                 "🦀 **BroAi — Available Commands**\n\n{}\n\n\
+                 <arg> is required, [arg] optional; arguments go in order or as name=value.\n\
                  All other messages are sent to the LLM for inference.",
                 lines.join("\n")
             );
-            return ok_response(content, req.model, session_id, &state, &req.messages).await;
+            return reply(Reply::plugin(content), &req, session_id, &state, &meter).await;
         }
 
         // Look up command in the plugin registry (fully dynamic — no hardcoding)
-        if let Some(manifest) = state.plugins.resolve(&command) {
-            info!(plugin = %manifest.name, command = %command, "Dispatching to plugin");
-
-            let payload = if manifest.payload_from_args && !args.is_empty() {
-                serde_json::json!({ "command": command, "args": args, "city": args, "expression": args, "path": args })
-            } else {
-                serde_json::json!({ "command": command })
+        if let Some(route) = plugins.resolve(&command, &args) {
+            let manifest = route.manifest;
+            info!(plugin = %manifest.name, command = %route.command, action = %route.action, "Dispatching to plugin");
+
+            let content = match manifest.payload(route.command, route.args) {
+                Err(usage) => format!("⚠️ {}", usage),
+                Ok(payload) => match run_plugin(&state, manifest, route.action, payload).await {
+                    Ok(r) if r.success => manifest.render(&r),
+                    Ok(r) => format!("⚠️ Plugin error: {}", r.error.unwrap_or_else(|| "unknown".into())),
+                    Err(e) => {
+                        warn!(error = %e, plugin = %manifest.name, "Plugin execution failed");
+                        format!("⚠️ Plugin failed: {}", e)
+                    }
+                },
             };
 
-            let plugin_req = PluginRequest {
-                action: manifest.default_action.clone(),
-                payload,
-            };
-
-            let plugin_dir = state.plugins.plugin_dir().to_string_lossy().to_string();
-            let runner = PluginRunner::new(plugin_dir);
-
-            let content = match runner.run(&manifest.name, &plugin_req, &state.device) {
-                Ok(r) if r.success => format_result(&manifest.name, &r.result),
-                Ok(r) => format!("⚠️ Plugin error: {}", r.error.unwrap_or_else(|| "unknown".into())),
-                Err(e) => {
-                    warn!(error = %e, plugin = %manifest.name, "Plugin execution failed");
-                    format!("⚠️ Plugin failed: {}", e)
-                }
-            };
-
-            return ok_response(content, req.model, session_id, &state, &req.messages).await;
+            return reply(Reply::plugin(content), &req, session_id, &state, &meter).await;
         }
 
         // Unknown command — helpful error
@@ -130,37 +378,258 @@ pub async fn chat_completions(
             "⚠️ Unknown command `/{}`.\nType `/help` to see all available commands.",
             command
         );
-        return ok_response(content, req.model, session_id, &state, &req.messages).await;
+        return reply(Reply::plugin(content), &req, session_id, &state, &meter).await;
     }
 
     // ── Standard LLM inference ────────────────────────────────────────────
-    let prompt = build_prompt(&req.messages);
-    let response_text = state.llm
-        .infer(prompt.clone(), req.max_tokens, req.temperature)
-        .await?;
+    let mut messages = if req.use_memory {
+        let turns = if req.context_summary { MEMORY_SUMMARY_TURNS } else { MEMORY_HISTORY_TURNS };
+        with_history(&state, &session_id, &req.messages, turns).await?
+    } else {
+        req.messages.clone()
+    };
+    if let Some(tools) = &tools {
+        messages = with_tools_prompt(messages, tools);
+        stop.push(TOOL_RESPONSE_OPEN.to_string());
+    }
+    let params = InferParams {
+        max_tokens: req.max_tokens,
+        sampling,
+        session_id: named_session.map(str::to_string),
+        stop,
+        grammar,
+        queue: QueueOptions {
+            priority: req.priority,
+            fair_key: meter.subject().to_string(),
+            max_wait: req.max_queue_wait.map(Duration::from_secs),
+        },
+    };
+
+    if let Some(tools) = tools.as_ref().filter(|_| req.agent) {
+        let answer = run_agent(&state, &caller, named_session, messages, params, tools).await?;
+        let result = Reply {
+            content: answer.text,
+            tool_calls: Vec::new(),
+            finish_reason: answer.finish_reason.as_str(),
+            usage: answer.usage,
+            queued: None,
+        };
+        return reply(result, &req, session_id, &state, &meter).await;
+    }
 
-    let prompt_tokens     = estimate_tokens(&prompt);
-    let completion_tokens = estimate_tokens(&response_text);
-    let user_msg = req.messages.last().map(|m| m.content.clone()).unwrap_or_default();
+    let summarizer = req.context_summary.then_some(Summarizer { queue: &params.queue, meter: &meter });
+    let messages = fit_to_context(&state, named_session, messages, req.max_tokens, summarizer).await?;
+    let prompt = build_prompt(&state.llm, &messages);
 
-    persist(&state, session_id, user_msg, response_text.clone(), req.model.clone()).await;
+    // Tool calls can only be recognised once the answer is complete, so those
+    // requests are not streamed token by token.
+    if req.stream && tools.is_none() {
+        let stream = state.llm.infer_stream(prompt, params).await?;
+        return Ok(stream_response(stream, req, session_id, state, meter));
+    }
 
-    Ok(Json(ChatResponse {
-        id: format!("chatcmpl-{}", Uuid::new_v4()),
-        object: "chat.completion".into(),
-        created: Utc::now().timestamp(),
-        model: req.model,
-        choices: vec![Choice {
+    let completion = state.llm.infer(prompt, params).await?;
+    let (content, tool_calls) = match &tools {
+        Some(tools) => tools.parse_calls(&completion.text),
+        None => (completion.text, Vec::new()),
+    };
+    let finish_reason = if tool_calls.is_empty() { completion.finish_reason.as_str() } else { "tool_calls" };
+    let result = Reply { content, tool_calls, finish_reason, usage: completion.usage, queued: Some(completion.queued) };
+    reply(result, &req, session_id, &state, &meter).await
+}
+
+// ─── Streaming ───────────────────────────────────────────────────────────────
+
+/// Relay worker tokens to the client as OpenAI `chat.completion.chunk` SSE frames,
+/// followed by a final chunk carrying `finish_reason`, the usage chunk if the
+/// client asked for it, and a `[DONE]` frame. A failure mid-stream sends the
+/// error body in place of the final chunks, still followed by `[DONE]`.
+fn stream_response(
+    mut stream: InferStream,
+    req: ChatRequest,
+    session_id: String,
+    state: AppState,
+    meter: Meter,
+) -> Response {
+    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(32);
+    let queued = stream.queued();
+
+    tokio::spawn(async move {
+        let id = format!("chatcmpl-{}", Uuid::new_v4());
+        let created = Utc::now().timestamp();
+        let frame = |delta: ChatDelta, finish_reason: Option<&str>| {
+            chunk_event(&id, created, &req.model, Some(delta), finish_reason, None)
+        };
+
+        let opening = ChatDelta { role: Some("assistant".into()), ..Default::default() };
+        if tx.send(Ok(frame(opening, None))).await.is_err() {
+            return;
+        }
+
+        let mut response_text = String::new();
+        let (finish_reason, usage) = loop {
+            match stream.next_event().await {
+                InferEvent::Queued(position) => {
+                    if tx.send(Ok(queue_comment(position))).await.is_err() {
+                        return;
+                    }
+                }
+                InferEvent::Token(piece) => {
+                    response_text.push_str(&piece);
+                    let delta = ChatDelta { content: Some(piece), ..Default::default() };
+                    if tx.send(Ok(frame(delta, None))).await.is_err() {
+                        return;
+                    }
+                }
+                InferEvent::Done { finish_reason, usage } => {
+                    meter.charge(usage.completion_tokens).await;
+                    break (finish_reason, usage);
+                }
+                InferEvent::Failed(e) => {
+                    warn!(error = %e, "Streaming inference failed");
+                    let _ = tx.send(Ok(Event::default().data(e.body().to_string()))).await;
+                    let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                    return;
+                }
+            }
+        };
+
+        let user_msg = last_user_message(&req.messages);
+        persist(&state, session_id, user_msg, response_text.trim_end().to_string(), req.model.clone()).await;
+
+        let _ = tx.send(Ok(frame(ChatDelta::default(), Some(finish_reason.as_str())))).await;
+        if include_usage(&req) {
+            let usage_chunk = chunk_event(&id, created, &req.model, None, None, Some(usage.into()));
+            let _ = tx.send(Ok(usage_chunk)).await;
+        }
+        let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+    });
+
+    let mut response = Sse::new(ReceiverStream::new(rx))
+        .keep_alive(KeepAlive::default())
+        .into_response();
+    write_queue_headers(queued, response.headers_mut());
+    response
+}
+
+/// SSE comment telling a streaming client its new place in the queue;
+/// OpenAI clients ignore comments.
+fn queue_comment(position: QueuePosition) -> Event {
+    let wait = position.wait_secs.map(|s| format!(", about {}s", s)).unwrap_or_default();
+    Event::default().comment(format!("queued: {} ahead{}", position.ahead, wait))
+}
+
+/// Stream an already-complete reply (plugin output, /help, tool calls) as a single chunk.
+fn single_chunk_response(result: Reply, req: &ChatRequest) -> Response {
+    let id = format!("chatcmpl-{}", Uuid::new_v4());
+    let created = Utc::now().timestamp();
+    let model = &req.model;
+    let tool_calls: Vec<ToolCallDelta> = result
+        .tool_calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| ToolCallDelta { index: index as u32, call })
+        .collect();
+    let delta = ChatDelta {
+        role: Some("assistant".into()),
+        content: Some(result.content).filter(|c| !c.is_empty()),
+        tool_calls: Some(tool_calls).filter(|c| !c.is_empty()),
+    };
+    let finish_reason = Some(result.finish_reason);
+    let mut frames: Vec<Result<Event, Infallible>> = vec![
+        Ok(chunk_event(&id, created, model, Some(delta), None, None)),
+        Ok(chunk_event(&id, created, model, Some(ChatDelta::default()), finish_reason, None)),
+    ];
+    if include_usage(req) {
+        let usage = Some(result.usage.into());
+        frames.push(Ok(chunk_event(&id, created, model, None, None, usage)));
+    }
+    frames.push(Ok(Event::default().data("[DONE]")));
+    Sse::new(tokio_stream::iter(frames)).into_response()
+}
+
+/// Build one chunk frame. The usage chunk has no `delta` and an empty `choices` array.
+fn chunk_event(
+    id: &str,
+    created: i64,
+    model: &str,
+    delta: Option<ChatDelta>,
+    finish_reason: Option<&str>,
+    usage: Option<Usage>,
+) -> Event {
+    let choices = delta
+        .map(|delta| ChunkChoice {
             index: 0,
-            message: ChatMessage { role: "assistant".into(), content: response_text },
-            finish_reason: "stop".into(),
-        }],
-        usage: Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens },
-    }))
+            delta,
+            finish_reason: finish_reason.map(str::to_string),
+        })
+        .into_iter()
+        .collect();
+    let chunk = ChatCompletionChunk {
+        id: id.to_string(),
+        object: "chat.completion.chunk".into(),
+        created,
+        model: model.to_string(),
+        choices,
+        usage,
+    };
+    Event::default().data(serde_json::to_string(&chunk).unwrap_or_default())
+}
+
+fn include_usage(req: &ChatRequest) -> bool {
+    req.stream_options.as_ref().is_some_and(|o| o.include_usage)
 }
 
 // ─── Helpers ─────────────────────────────────────────────────────────────────
 
+/// The name a session is stored and cached under. Sessions belong to the key
+/// that created them, so another tenant can't read or reuse one by its id.
+fn session_key(caller: &Caller, session_id: &str) -> String {
+    format!("key:{}/{}", caller.name, session_id)
+}
+
+/// Splice the last `turns` stored turns of `session_id` between the client's
+/// system messages and the rest of what it sent.
+async fn with_history(
+    state: &AppState,
+    session_id: &str,
+    messages: &[ChatMessage],
+    turns: u32,
+) -> Result<Vec<ChatMessage>, AppError> {
+    // Stored newest-first; the prompt needs oldest-first.
+    let history = state.memory.get_session_history(session_id, turns).await?;
+
+    let (system, rest): (Vec<&ChatMessage>, Vec<&ChatMessage>) =
+        messages.iter().partition(|m| m.role == "system");
+
+    let mut merged: Vec<ChatMessage> = system.into_iter().cloned().collect();
+    for (user, assistant) in history.into_iter().rev() {
+        merged.push(ChatMessage::new("user", user));
+        merged.push(ChatMessage::new("assistant", assistant));
+    }
+    merged.extend(rest.into_iter().cloned());
+    Ok(merged)
+}
+
+/// Grammar for `response_format` or a raw `grammar`; they are mutually exclusive.
+fn request_grammar(req: &ChatRequest) -> Result<Option<Grammar>, AppError> {
+    let format = match &req.response_format {
+        Some(ResponseFormat::Text) | None => None,
+        Some(format) => Some(format),
+    };
+    match (format, &req.grammar) {
+        (Some(_), Some(_)) => Err(AppError::InvalidRequest(
+            "use either response_format or grammar, not both".into(),
+        )),
+        (None, Some(gbnf)) => Grammar::parse(gbnf).map(Some),
+        (Some(ResponseFormat::JsonSchema { json_schema }), None) => {
+            Grammar::from_json_schema(&json_schema.schema).map(Some)
+        }
+        (Some(_), None) => Grammar::json_object().map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
 /// If the last user message starts with '/', returns (command, rest_of_line).
 fn extract_command(messages: &[ChatMessage]) -> Option<(String, String)> {
     let last = messages.iter().rev().find(|m| m.role == "user")?;
@@ -174,83 +643,72 @@ fn extract_command(messages: &[ChatMessage]) -> Option<(String, String)> {
     Some((cmd, args))
 }
 
-/// Format plugin JSON result into human-readable markdown.
-/// Plugins that want rich formatting can ship a "format" hint in their manifest
-/// (future). For now we pattern-match on known plugin names as a fallback,
-/// and emit a generic pretty-print for unknown ones.
-fn format_result(plugin_name: &str, result: &serde_json::Value) -> String {
-    match plugin_name {
-        "plugin-datetime" => format!(
-            "🕐 **Date & Time**\n📅 Date: {}\n🕐 Time: {}\n📆 Day: {}\n🌍 Zone: {}",
-            result["date"].as_str().unwrap_or("—"),
-            result["time"].as_str().unwrap_or("—"),
-            result["day_of_week"].as_str().unwrap_or("—"),
-            result["timezone"].as_str().unwrap_or("—"),
-        ),
-        "plugin-weather" => {
-            let mut out = format!(
-                "🌍 **Weather — {}**\n🌤️ {}\n🌡️ Temp: {}\n🤔 Feels: {}\n💧 Humidity: {}\n💨 Wind: {}",
-                result["location"].as_str().unwrap_or("—"),
-                result["condition"].as_str().unwrap_or("—"),
-                result["temperature"].as_str().unwrap_or("—"),
-                result["feels_like"].as_str().unwrap_or("—"),
-                result["humidity"].as_str().unwrap_or("—"),
-                result["wind"].as_str().unwrap_or("—"),
-            );
-            if let Some(days) = result["forecast"].as_array() {
-                out.push_str("\n\n📅 **3-Day Forecast**");
-                for d in days {
-                    out.push_str(&format!(
-                        "\n  {} → max {:.0}°C / min {:.0}°C / rain {:.1}mm",
-                        d["date"].as_str().unwrap_or(""),
-                        d["max_temp"].as_f64().unwrap_or(0.0),
-                        d["min_temp"].as_f64().unwrap_or(0.0),
-                        d["rain_mm"].as_f64().unwrap_or(0.0),
-                    ));
-                }
-            }
-            out
+impl Reply {
+    /// An answer produced without the model, so no model tokens were used.
+    fn plugin(content: String) -> Self {
+        Self {
+            content,
+            tool_calls: Vec::new(),
+            finish_reason: FinishReason::Stop.as_str(),
+            usage: TokenUsage::default(),
+            queued: None,
         }
-        "plugin-calculator" => format!(
-            "🧮 **Calculator**\n📝 Expression: `{}`\n✅ Result: **{}**",
-            result["expression"].as_str().unwrap_or("—"),
-            result["result_str"].as_str().unwrap_or("—"),
-        ),
-        "plugin-file-reader" => format!(
-            "📄 **File: {}**\n📏 Lines: {} | Size: {} bytes{}\n```\n{}\n```",
-            result["path"].as_str().unwrap_or("—"),
-            result["lines"].as_u64().or(result["total_lines"].as_u64()).unwrap_or(0),
-            result["size_bytes"].as_u64().unwrap_or(0),
-            if result["truncated"].as_bool().unwrap_or(false) { " (truncated)" } else { "" },
-            result["content"].as_str().unwrap_or("(empty)"),
-        ),
-        // Generic fallback for future plugins — pretty-print the JSON
-        _ => serde_json::to_string_pretty(result).unwrap_or_else(|_| result.to_string()),
     }
 }
 
-async fn ok_response(
-    content: String,
-    model: String,
+/// Charge and persist a complete answer and send it in the form the client asked for.
+async fn reply(
+    result: Reply,
+    req: &ChatRequest,
     session_id: String,
     state: &AppState,
-    messages: &[ChatMessage],
-) -> Result<Json<ChatResponse>, AppError> {
-    let user_msg = messages.last().map(|m| m.content.clone()).unwrap_or_default();
-    persist(state, session_id, user_msg, content.clone(), model.clone()).await;
-    let t = estimate_tokens(&content);
-    Ok(Json(ChatResponse {
+    meter: &Meter,
+) -> Result<Response, AppError> {
+    meter.charge(result.usage.completion_tokens).await;
+    let model = req.model.clone();
+    let user_msg = last_user_message(&req.messages);
+    let message = ChatMessage {
+        tool_calls: Some(result.tool_calls.clone()).filter(|c| !c.is_empty()),
+        ..ChatMessage::new("assistant", result.content.clone())
+    };
+    let (_, stored) = prompt_turn(&message, &[]);
+    persist(state, session_id, user_msg, stored, model.clone()).await;
+    let queued = result.queued;
+    let mut response = if req.stream {
+        single_chunk_response(result, req)
+    } else {
+        Json(ChatResponse {
         id: format!("chatcmpl-{}", Uuid::new_v4()),
         object: "chat.completion".into(),
         created: Utc::now().timestamp(),
         model,
         choices: vec![Choice {
             index: 0,
-            message: ChatMessage { role: "assistant".into(), content },
-            finish_reason: "stop".into(),
+            message,
+            finish_reason: result.finish_reason.into(),
         }],
-        usage: Usage { prompt_tokens: t, completion_tokens: t, total_tokens: t * 2 },
-    }))
+        usage: result.usage.into(),
+    }).into_response()
+    };
+    if let Some(position) = queued {
+        write_queue_headers(position, response.headers_mut());
+    }
+    Ok(response)
+}
+
+/// `x-queue-position` (requests ahead when queued) and, once the server has
+/// timed a request, `x-queue-wait-estimate` in seconds.
+fn write_queue_headers(position: QueuePosition, headers: &mut HeaderMap) {
+    headers.insert("x-queue-position", position.ahead.into());
+    if let Some(wait) = position.wait_secs {
+        headers.insert("x-queue-wait-estimate", wait.into());
+    }
+}
+
+/// What the user said this turn. In tool and agent flows the last message is
+/// often a `tool` or `assistant` one, so it is not simply the last.
+fn last_user_message(messages: &[ChatMessage]) -> String {
+    messages.iter().rev().find(|m| m.role == "user").map(|m| m.content.clone()).unwrap_or_default()
 }
 
 async fn persist(state: &AppState, session_id: String, user: String, assistant: String, model: String) {
@@ -265,20 +723,33 @@ async fn persist(state: &AppState, session_id: String, user: String, assistant:
     }
 }
 
-fn build_prompt(messages: &[ChatMessage]) -> String {
-    let mut p = String::new();
-    for m in messages {
-        match m.role.as_str() {
-            "system"    => p.push_str(&format!("<|system|>\n{}\n", m.content)),
-            "user"      => p.push_str(&format!("<|user|>\n{}\n", m.content)),
-            "assistant" => p.push_str(&format!("<|assistant|>\n{}\n", m.content)),
-            _           => p.push_str(&format!("{}: {}\n", m.role, m.content)),
-        }
-    }
-    p.push_str("<|assistant|>\n");
-    p
+/// Render messages in the prompt format of the loaded model.
+pub(crate) fn build_prompt(llm: &LlmActor, messages: &[ChatMessage]) -> String {
+    let turns: Vec<(&str, String)> = messages.iter().map(|m| prompt_turn(m, messages)).collect();
+    llm.build_prompt(turns.iter().map(|(role, content)| (*role, content.as_str())))
 }
 
-fn estimate_tokens(text: &str) -> u32 {
-    (text.len() / 4).max(1) as u32
+/// Run `manifest`'s binary for `action` with a payload built by [`PluginManifest::payload`].
+pub(crate) async fn run_plugin(
+    state: &AppState,
+    manifest: &PluginManifest,
+    action: &str,
+    payload: serde_json::Value,
+) -> Result<PluginResponse, AppError> {
+    let plugin_req = PluginRequest {
+        action: action.to_string(),
+        payload,
+    };
+
+    let plugin_dir = state.plugins.current().plugin_dir().to_string_lossy().to_string();
+    let runner = PluginRunner::new(plugin_dir, state.plugin_sandbox);
+    let started = Instant::now();
+    let result = match manifest.mode {
+        PluginMode::Oneshot => runner.run(manifest, &plugin_req).await,
+        PluginMode::Daemon => state.plugins.daemons().call(&runner, manifest, &plugin_req).await,
+    };
+    let ok = matches!(&result, Ok(response) if response.success);
+    metrics::global().record_plugin(&manifest.name, started.elapsed(), ok);
+    result
 }
+
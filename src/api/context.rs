@@ -0,0 +1,307 @@
+use tracing::{info, warn};
+
+use crate::api::chat::{build_prompt, ChatMessage};
+use crate::api::limits::Meter;
+use crate::api::AppState;
+use crate::errors::AppError;
+use crate::llm::{InferParams, QueueOptions, SamplingParams};
+use crate::security::sha256;
+
+/// Output budget for the summary of trimmed turns.
+const SUMMARY_MAX_TOKENS: u32 = 128;
+
+// ─── Context window fitting ──────────────────────────────────────────────────
+
+/// How the summary of trimmed turns is queued and billed: like the request
+/// it is written for.
+pub struct Summarizer<'a> {
+    pub queue: &'a QueueOptions,
+    pub meter: &'a Meter,
+}
+
+/// Trim the oldest non-system turns until the prompt leaves room for
+/// `max_tokens` of output inside the model's context window.
+/// The newest message is never dropped.
+///
+/// With a `summarizer`, the dropped turns are replaced by an LLM-written
+/// summary that is kept per session in SQLite and extended as more turns fall
+/// out. Turns the stored summary already covers are left to it.
+pub async fn fit_to_context(
+    state: &AppState,
+    session_id: Option<&str>,
+    messages: Vec<ChatMessage>,
+    max_tokens: u32,
+    summarizer: Option<Summarizer<'_>>,
+) -> Result<Vec<ChatMessage>, AppError> {
+    let llm = &state.llm;
+    let reserved = max_tokens.clamp(1, llm.max_generation_tokens());
+    let budget = llm.context_size().saturating_sub(reserved) as usize;
+
+    let (system, turns): (Vec<ChatMessage>, Vec<ChatMessage>) =
+        messages.into_iter().partition(|m| m.role == "system");
+
+    let stored = match (&summarizer, session_id) {
+        (Some(_), Some(id)) => state.memory.get_session_summary(id).await?,
+        _ => None,
+    };
+    let covered = stored.as_ref().map_or(0, |(_, last)| covered_turns(&turns, last));
+    let mut summary = stored.map(|(text, _)| text);
+
+    let mut dropped = first_fit(state, &system, summary.as_deref(), &turns, covered, budget)?;
+    if dropped == covered {
+        return Ok(assemble(&system, summary.as_deref(), &turns[dropped..]));
+    }
+
+    if let Some(summarizer) = &summarizer {
+        match summarize_turns(state, session_id, summary.as_deref(), &turns[covered..dropped], summarizer).await {
+            Ok(text) => {
+                // The summary costs tokens too; trim further if it pushed us over.
+                dropped = first_fit(state, &system, Some(&text), &turns, dropped, budget)?;
+                summary = Some(text);
+            }
+            Err(e) => warn!(error = %e, "Context summary failed — trimming without it"),
+        }
+    }
+
+    info!(
+        dropped_messages = dropped,
+        kept_messages = turns.len() - dropped,
+        summarized = summary.is_some(),
+        "Trimmed conversation to fit context window"
+    );
+
+    Ok(assemble(&system, summary.as_deref(), &turns[dropped..]))
+}
+
+/// Smallest number of leading turns (at least `start`) to drop so the prompt fits.
+fn first_fit(
+    state: &AppState,
+    system: &[ChatMessage],
+    summary: Option<&str>,
+    turns: &[ChatMessage],
+    start: usize,
+    budget: usize,
+) -> Result<usize, AppError> {
+    let fits = |dropped: usize| -> Result<bool, AppError> {
+        let candidate = assemble(system, summary, &turns[dropped..]);
+        Ok(state.llm.count_tokens(&build_prompt(&state.llm, &candidate))? <= budget)
+    };
+    cut_point(start, turns.len(), fits)?.ok_or_else(|| {
+        AppError::InvalidRequest(format!(
+            "Prompt does not fit the {}-token context window even after dropping history; \
+             shorten the last message or lower max_tokens",
+            state.llm.context_size()
+        ))
+    })
+}
+
+/// Smallest `dropped` in `start..turns` for which `fits(dropped)`, never
+/// dropping the last turn; with no turns, `fits(0)` decides. Dropping turns
+/// never lengthens the prompt, so this binary-searches instead of
+/// re-tokenizing the prompt once per turn.
+fn cut_point(
+    start: usize,
+    turns: usize,
+    mut fits: impl FnMut(usize) -> Result<bool, AppError>,
+) -> Result<Option<usize>, AppError> {
+    let last = turns.saturating_sub(1);
+    if start > last || !fits(last)? {
+        return Ok(None);
+    }
+    // Most prompts fit as they are.
+    let (mut lo, mut hi) = (start, last);
+    if lo < hi {
+        if fits(lo)? {
+            return Ok(Some(lo));
+        }
+        lo += 1;
+    }
+    // Invariant: `hi` fits, everything below `lo` was ruled out.
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if fits(mid)? {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Ok(Some(lo))
+}
+
+fn assemble(system: &[ChatMessage], summary: Option<&str>, turns: &[ChatMessage]) -> Vec<ChatMessage> {
+    let mut out = system.to_vec();
+    if let Some(text) = summary {
+        out.push(ChatMessage::new(
+            "system",
+            format!("Summary of the earlier conversation: {}", text),
+        ));
+    }
+    out.extend_from_slice(turns);
+    out
+}
+
+// ─── Summaries ───────────────────────────────────────────────────────────────
+
+/// Fold `new_turns` into the `previous` summary, in batches that fit the
+/// context window. Each batch is saved with the identity of its last turn, so
+/// later requests know where the summary ends however their history is cut.
+async fn summarize_turns(
+    state: &AppState,
+    session_id: Option<&str>,
+    previous: Option<&str>,
+    new_turns: &[ChatMessage],
+    summarizer: &Summarizer<'_>,
+) -> Result<String, AppError> {
+    let llm = &state.llm;
+    let budget = llm.context_size().saturating_sub(SUMMARY_MAX_TOKENS) as usize;
+    let mut summary = previous.map(str::to_string);
+    let mut rest = new_turns;
+
+    while !rest.is_empty() {
+        // Always take at least one turn, even one too long to fit.
+        let mut used = llm.count_tokens(&summary_prompt(state, summary.as_deref(), ""))?;
+        let mut take = 0;
+        for turn in rest {
+            used += llm.count_tokens(&transcript_line(turn))?;
+            if take > 0 && used > budget {
+                break;
+            }
+            take += 1;
+        }
+        let (batch, remaining) = rest.split_at(take);
+        let transcript: String = batch.iter().map(transcript_line).collect();
+
+        let params = InferParams {
+            max_tokens: SUMMARY_MAX_TOKENS,
+            sampling: SamplingParams {
+                temperature: 0.2,
+                seed: None,
+                ..llm.sampling_defaults().clone()
+            },
+            session_id: None,
+            stop: Vec::new(),
+            grammar: None,
+            queue: summarizer.queue.clone(),
+        };
+        let completion = llm.infer(summary_prompt(state, summary.as_deref(), &transcript), params).await?;
+        summarizer.meter.charge(completion.usage.completion_tokens).await;
+
+        if let (Some(id), Some(last)) = (session_id, batch.last()) {
+            if let Err(e) = state.memory.save_session_summary(id, &completion.text, &turn_hash(last)).await {
+                warn!(error = %e, "Failed to persist context summary");
+            }
+        }
+        summary = Some(completion.text);
+        rest = remaining;
+    }
+    Ok(summary.unwrap_or_default())
+}
+
+fn summary_prompt(state: &AppState, previous: Option<&str>, transcript: &str) -> String {
+    let instruction = match previous {
+        None => format!(
+            "Summarise this conversation in a few sentences. \
+             Keep names, facts and decisions.\n\n{}",
+            transcript
+        ),
+        Some(previous) => format!(
+            "Update this conversation summary with the new turns below. \
+             Keep names, facts and decisions.\n\nSummary so far:\n{}\n\nNew turns:\n{}",
+            previous, transcript
+        ),
+    };
+    build_prompt(&state.llm, &[ChatMessage::new("user", instruction)])
+}
+
+fn transcript_line(turn: &ChatMessage) -> String {
+    format!("{}: {}\n", turn.role, turn.content)
+}
+
+/// Leading turns covered by a summary that ends at the turn hashed `last`:
+/// up to its latest occurrence, never the newest message. 0 when that
+/// turn isn't in `turns`, i.e. everything sent is newer than the summary.
+fn covered_turns(turns: &[ChatMessage], last: &str) -> usize {
+    let older = &turns[..turns.len().saturating_sub(1)];
+    older.iter().rposition(|m| turn_hash(m) == last).map_or(0, |i| i + 1)
+}
+
+/// Identity of a turn that survives the history being cut or shifted.
+fn turn_hash(turn: &ChatMessage) -> String {
+    hex::encode(sha256(format!("{}\n{}", turn.role, turn.content).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cut_point` over turns of the given sizes, with the number of `fits` calls.
+    fn cut(sizes: &[usize], start: usize, budget: usize) -> (Option<usize>, usize) {
+        let mut calls = 0;
+        let fits = |dropped: usize| {
+            calls += 1;
+            Ok(sizes[dropped.min(sizes.len())..].iter().sum::<usize>() <= budget)
+        };
+        let cut = cut_point(start, sizes.len(), fits).unwrap();
+        (cut, calls)
+    }
+
+    #[test]
+    fn cut_point_drops_the_fewest_turns() {
+        let sizes = [5, 5, 5, 5, 5];
+        assert_eq!(cut(&sizes, 0, 25).0, Some(0));
+        assert_eq!(cut(&sizes, 0, 24).0, Some(1));
+        assert_eq!(cut(&sizes, 0, 10).0, Some(3));
+        assert_eq!(cut(&sizes, 0, 5).0, Some(4));
+        assert_eq!(cut(&sizes, 2, 25).0, Some(2));
+        assert_eq!(cut(&[1, 9, 1, 1], 0, 3).0, Some(2));
+    }
+
+    #[test]
+    fn cut_point_never_drops_the_last_turn() {
+        assert_eq!(cut(&[5, 5, 6], 0, 5).0, None);
+        assert_eq!(cut(&[5, 5], 2, 100).0, None);
+        assert_eq!(cut(&[], 0, 0).0, Some(0));
+    }
+
+    #[test]
+    fn cut_point_takes_logarithmic_tokenizer_passes() {
+        let sizes = vec![1; 200];
+        let (dropped, calls) = cut(&sizes, 0, 7);
+        assert_eq!(dropped, Some(193));
+        assert!(calls <= 10, "{} calls", calls);
+        assert_eq!(cut(&sizes, 0, 200).1, 2);
+    }
+
+    fn turns(texts: &[&str]) -> Vec<ChatMessage> {
+        texts.iter().enumerate().map(|(i, t)| ChatMessage::new(["user", "assistant"][i % 2], *t)).collect()
+    }
+
+    #[test]
+    fn summary_covers_up_to_its_last_turn() {
+        let history = turns(&["a", "b", "c", "d", "e"]);
+        assert_eq!(covered_turns(&history, &turn_hash(&history[1])), 2);
+        // The client cut its history: the same turn is found further up.
+        assert_eq!(covered_turns(&history[1..], &turn_hash(&history[1])), 1);
+    }
+
+    #[test]
+    fn repeated_turns_match_their_latest_occurrence() {
+        let history = turns(&["ok", "x", "ok", "y", "z"]);
+        assert_eq!(covered_turns(&history, &turn_hash(&history[2])), 3);
+    }
+
+    #[test]
+    fn summary_never_covers_the_newest_turn() {
+        let history = turns(&["a", "b", "c"]);
+        assert_eq!(covered_turns(&history, &turn_hash(&history[2])), 0);
+        assert_eq!(covered_turns(&[], "anything"), 0);
+    }
+
+    #[test]
+    fn unknown_last_turn_covers_nothing() {
+        let history = turns(&["a", "b", "c"]);
+        assert_eq!(covered_turns(&history, &turn_hash(&ChatMessage::new("user", "gone"))), 0);
+        // Same text under another role is another turn.
+        assert_eq!(covered_turns(&history, &turn_hash(&ChatMessage::new("assistant", "a"))), 0);
+    }
+}
@@ -0,0 +1,399 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{HeaderMap, HeaderName};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::api::auth::Caller;
+use crate::errors::AppError;
+use crate::memory::MemoryStore;
+
+const DAY_SECS: f64 = 86_400.0;
+/// How often buckets idle for a day are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// ─── Policy ──────────────────────────────────────────────────────────────────
+
+/// What a client's limits are counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitBy {
+    /// The API key; keyless requests (`API_AUTH=off`) fall back to the client IP.
+    Key,
+    /// The request's `session_id` within its key (or IP), which is limited too.
+    Session,
+    /// The client IP address.
+    Ip,
+}
+
+impl FromStr for LimitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "key" => Ok(Self::Key),
+            "session" => Ok(Self::Session),
+            "ip" => Ok(Self::Ip),
+            other => Err(format!("unknown rate limit subject `{}`; use key, session or ip", other)),
+        }
+    }
+}
+
+/// Limits applied to every subject. Zero turns a limit off.
+#[derive(Debug, Clone)]
+pub struct RateLimitPolicy {
+    pub by: LimitBy,
+    /// Chat requests per minute, refilled continuously.
+    pub requests_per_minute: u32,
+    /// Generated (completion) tokens per day, refilled continuously.
+    pub tokens_per_day: u32,
+}
+
+// ─── Limiter ─────────────────────────────────────────────────────────────────
+
+/// Token buckets per subject, kept in SQLite so restarts don't reset them.
+#[derive(Clone)]
+pub struct RateLimiter {
+    policy: RateLimitPolicy,
+    memory: Arc<MemoryStore>,
+}
+
+/// A bucket's state after this request, for the `x-ratelimit-*` headers.
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset_secs: u64,
+}
+
+/// What [`RateLimiter::admit`] found, reported back in response headers.
+#[derive(Debug, Default)]
+pub struct Quota {
+    requests: Option<Level>,
+    tokens: Option<Level>,
+}
+
+/// Charges generated tokens to the subject a request was admitted for.
+#[derive(Clone)]
+pub struct Meter {
+    limiter: RateLimiter,
+    subject: String,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy, memory: Arc<MemoryStore>) -> Self {
+        Self { policy, memory }
+    }
+
+    pub fn policy(&self) -> &RateLimitPolicy {
+        &self.policy
+    }
+
+    /// The name limits are counted under, e.g. `key:laptop`, `ip:192.168.1.20`
+    /// or `key:laptop/session:abc`. Sessions are scoped to their key or IP so
+    /// a client can't start over by picking a new `session_id`.
+    pub fn subject(&self, caller: &Caller, session_id: Option<&str>, ip: IpAddr) -> String {
+        let client = match self.policy.by {
+            LimitBy::Key | LimitBy::Session if caller.name != Caller::ANONYMOUS => format!("key:{}", caller.name),
+            _ => format!("ip:{}", ip),
+        };
+        match (self.policy.by, session_id) {
+            (LimitBy::Session, Some(session)) => format!("{}/session:{}", client, session),
+            _ => client,
+        }
+    }
+
+    /// Take one request from the buckets of `subject` and, for a session, of
+    /// the client it belongs to. Fails while either is out of requests or has
+    /// used up its generated tokens.
+    pub async fn admit(&self, subject: &str) -> Result<(Quota, Meter), AppError> {
+        let now = now();
+        let meter = Meter { limiter: self.clone(), subject: subject.to_string() };
+        let mut quota = Quota::default();
+
+        if let Some(bucket) = self.tokens_bucket() {
+            for counted in counted_under(subject) {
+                // Tokens are charged after generation, so only refuse once in debt.
+                let level = self.memory.update_bucket(counted, "tokens", now, |stored| bucket.peek(stored, now)).await?;
+                if level < 1.0 {
+                    let retry_after = bucket.wait(level, 1.0);
+                    debug!(subject = counted, retry_after, "Daily token quota used up");
+                    return Err(AppError::RateLimited(
+                        format!("{} tokens per day used up", self.policy.tokens_per_day),
+                        retry_after,
+                    ));
+                }
+                quota.tokens = lowest(quota.tokens, bucket.level(level));
+            }
+        }
+
+        if let Some(bucket) = self.requests_bucket() {
+            let mut taken_from = Vec::new();
+            for counted in counted_under(subject) {
+                let (level, taken) = self
+                    .memory
+                    .update_bucket(counted, "requests", now, |stored| {
+                        let level = bucket.peek(stored, now).0;
+                        if level >= 1.0 { (level - 1.0, (level - 1.0, true)) } else { (level, (level, false)) }
+                    })
+                    .await?;
+                if !taken {
+                    // Give back what the client's bucket already paid for a request that won't run.
+                    for refund in taken_from {
+                        self.memory.update_bucket(refund, "requests", now, |stored| (bucket.peek(stored, now).0 + 1.0, ())).await?;
+                    }
+                    let retry_after = bucket.wait(level, 1.0);
+                    debug!(subject = counted, retry_after, "Request rate limit hit");
+                    return Err(AppError::RateLimited(
+                        format!("{} requests per minute", self.policy.requests_per_minute),
+                        retry_after,
+                    ));
+                }
+                taken_from.push(counted);
+                quota.requests = lowest(quota.requests, bucket.level(level));
+            }
+        }
+        Ok((quota, meter))
+    }
+
+    /// Prune idle buckets now and then every hour.
+    pub fn spawn_pruning(&self) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                sweep.tick().await;
+                limiter.prune().await;
+            }
+        });
+    }
+
+    /// Forget subjects idle for a full day; their buckets would be full again.
+    async fn prune(&self) {
+        match self.memory.prune_buckets(now() - DAY_SECS).await {
+            Ok(0) => {}
+            Ok(n) => debug!(removed = n, "Pruned idle rate limit buckets"),
+            Err(e) => warn!(error = %e, "Failed to prune rate limit buckets"),
+        }
+    }
+
+    fn requests_bucket(&self) -> Option<Bucket> {
+        Bucket::new(self.policy.requests_per_minute, 60.0)
+    }
+
+    fn tokens_bucket(&self) -> Option<Bucket> {
+        Bucket::new(self.policy.tokens_per_day, DAY_SECS)
+    }
+}
+
+impl Meter {
+    /// The subject this request counts against; also its fairness key in the queue.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Debit `tokens` from the daily quota. The bucket may go negative, which
+    /// blocks further requests until it has refilled.
+    pub async fn charge(&self, tokens: u32) {
+        let Some(bucket) = self.limiter.tokens_bucket() else { return };
+        if tokens == 0 {
+            return;
+        }
+        let now = now();
+        for counted in counted_under(&self.subject) {
+            let result = self
+                .limiter
+                .memory
+                .update_bucket(counted, "tokens", now, |stored| (bucket.peek(stored, now).0 - tokens as f64, ()))
+                .await;
+            if let Err(e) = result {
+                warn!(error = %e, subject = counted, "Failed to charge tokens");
+            }
+        }
+    }
+}
+
+impl Quota {
+    /// `x-ratelimit-{limit,remaining,reset}-{requests,tokens}`, as in the
+    /// OpenAI API; reset is in seconds until the bucket is full.
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        for (kind, level) in [("requests", self.requests), ("tokens", self.tokens)] {
+            let Some(level) = level else { continue };
+            for (field, value) in [("limit", level.limit as u64), ("remaining", level.remaining as u64), ("reset", level.reset_secs)] {
+                if let Ok(name) = HeaderName::try_from(format!("x-ratelimit-{}-{}", field, kind)) {
+                    headers.insert(name, value.into());
+                }
+            }
+        }
+    }
+}
+
+// ─── Buckets ─────────────────────────────────────────────────────────────────
+
+/// Holds up to `capacity` and refills at `capacity / period` per second.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    capacity: f64,
+    per_sec: f64,
+}
+
+impl Bucket {
+    fn new(capacity: u32, period_secs: f64) -> Option<Self> {
+        (capacity > 0).then(|| Self { capacity: capacity as f64, per_sec: capacity as f64 / period_secs })
+    }
+
+    /// Level at `now` after refilling since it was stored. New subjects start full.
+    /// Returns it twice: once to store, once to hand back.
+    fn peek(&self, stored: Option<(f64, f64)>, now: f64) -> (f64, f64) {
+        let level = match stored {
+            Some((level, at)) => (level + (now - at).max(0.0) * self.per_sec).min(self.capacity),
+            None => self.capacity,
+        };
+        (level, level)
+    }
+
+    /// Whole seconds until the bucket holds `wanted`.
+    fn wait(&self, level: f64, wanted: f64) -> u64 {
+        ((wanted - level) / self.per_sec).ceil().max(1.0) as u64
+    }
+
+    fn level(&self, level: f64) -> Level {
+        Level {
+            limit: self.capacity as u32,
+            remaining: level.max(0.0).floor() as u32,
+            reset_secs: ((self.capacity - level) / self.per_sec).ceil().max(0.0) as u64,
+        }
+    }
+}
+
+/// The buckets a subject draws from: the client's, then the session's if any.
+fn counted_under(subject: &str) -> impl Iterator<Item = &str> {
+    let client = subject.split_once('/').map(|(client, _)| client);
+    client.into_iter().chain(std::iter::once(subject))
+}
+
+/// Whichever of two levels has less left, for the response headers.
+fn lowest(current: Option<Level>, level: Level) -> Option<Level> {
+    match current {
+        Some(current) if current.remaining <= level.remaining => Some(current),
+        _ => Some(level),
+    }
+}
+
+fn now() -> f64 {
+    chrono::Utc::now().timestamp_millis() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::Scope;
+
+    fn limiter(by: LimitBy, requests_per_minute: u32, tokens_per_day: u32) -> RateLimiter {
+        let memory = Arc::new(MemoryStore::open(":memory:").unwrap());
+        RateLimiter::new(RateLimitPolicy { by, requests_per_minute, tokens_per_day }, memory)
+    }
+
+    fn retry_after(result: Result<(Quota, Meter), AppError>) -> u64 {
+        match result {
+            Err(AppError::RateLimited(_, secs)) => secs,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("admitted"),
+        }
+    }
+
+    #[test]
+    fn bucket_refills_continuously_up_to_capacity() {
+        let bucket = Bucket::new(60, 60.0).unwrap();
+        assert_eq!(bucket.peek(None, 100.0).0, 60.0);
+        assert_eq!(bucket.peek(Some((10.0, 100.0)), 105.0).0, 15.0);
+        assert_eq!(bucket.peek(Some((59.0, 100.0)), 200.0).0, 60.0);
+        // A clock step backwards never drains it.
+        assert_eq!(bucket.peek(Some((10.0, 100.0)), 90.0).0, 10.0);
+        assert_eq!(bucket.wait(-2.5, 1.0), 4);
+        assert_eq!(bucket.wait(0.9, 1.0), 1);
+        assert!(Bucket::new(0, 60.0).is_none());
+    }
+
+    #[test]
+    fn level_reports_whole_requests_and_seconds_to_full() {
+        let level = Bucket::new(30, 60.0).unwrap().level(-3.0);
+        assert_eq!((level.limit, level.remaining, level.reset_secs), (30, 0, 66));
+        let level = Bucket::new(30, 60.0).unwrap().level(29.5);
+        assert_eq!((level.remaining, level.reset_secs), (29, 1));
+    }
+
+    #[test]
+    fn sessions_count_under_their_client() {
+        assert_eq!(counted_under("key:a").collect::<Vec<_>>(), ["key:a"]);
+        assert_eq!(counted_under("key:a/session:s").collect::<Vec<_>>(), ["key:a", "key:a/session:s"]);
+    }
+
+    #[test]
+    fn subject_follows_the_policy() {
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        let laptop = Caller::new("laptop", vec![Scope::Chat]);
+        let anonymous = Caller::new(Caller::ANONYMOUS, vec![Scope::Chat]);
+
+        let by_key = limiter(LimitBy::Key, 1, 0);
+        assert_eq!(by_key.subject(&laptop, Some("s"), ip), "key:laptop");
+        assert_eq!(by_key.subject(&anonymous, None, ip), "ip:10.0.0.7");
+
+        let by_session = limiter(LimitBy::Session, 1, 0);
+        assert_eq!(by_session.subject(&laptop, Some("s"), ip), "key:laptop/session:s");
+        assert_eq!(by_session.subject(&anonymous, Some("s"), ip), "ip:10.0.0.7/session:s");
+        assert_eq!(by_session.subject(&laptop, None, ip), "key:laptop");
+
+        assert_eq!(limiter(LimitBy::Ip, 1, 0).subject(&laptop, Some("s"), ip), "ip:10.0.0.7");
+    }
+
+    #[tokio::test]
+    async fn requests_per_minute_are_enforced() {
+        let limits = limiter(LimitBy::Key, 2, 0);
+        let (quota, _) = limits.admit("key:a").await.unwrap();
+        assert_eq!(quota.requests.unwrap().remaining, 1);
+        limits.admit("key:a").await.unwrap();
+        assert_eq!(retry_after(limits.admit("key:a").await), 30);
+        // Other subjects have their own bucket.
+        limits.admit("key:b").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn new_sessions_draw_from_the_same_client_bucket() {
+        let limits = limiter(LimitBy::Session, 2, 0);
+        limits.admit("key:a/session:1").await.unwrap();
+        limits.admit("key:a/session:2").await.unwrap();
+        retry_after(limits.admit("key:a/session:3").await);
+    }
+
+    #[tokio::test]
+    async fn refused_session_refunds_its_client() {
+        let limits = limiter(LimitBy::Session, 5, 0);
+        let empty = |_| (0.0, ());
+        limits.memory.update_bucket("key:a/session:1", "requests", now(), empty).await.unwrap();
+        retry_after(limits.admit("key:a/session:1").await);
+        // Session 1 took nothing from the client, so session 2 finds it full.
+        let (quota, _) = limits.admit("key:a/session:2").await.unwrap();
+        assert_eq!(quota.requests.unwrap().remaining, 4);
+    }
+
+    #[tokio::test]
+    async fn token_quota_refuses_once_in_debt() {
+        let limits = limiter(LimitBy::Session, 0, 100);
+        let (quota, meter) = limits.admit("key:a/session:1").await.unwrap();
+        assert!(quota.requests.is_none());
+        assert_eq!(quota.tokens.unwrap().remaining, 100);
+
+        meter.charge(99).await;
+        limits.admit("key:a/session:1").await.unwrap();
+        meter.charge(5).await;
+        // 4 tokens in debt: back to 1 after 5 tokens at 864 s each.
+        let secs = retry_after(limits.admit("key:a/session:2").await);
+        assert!((4318..=4320).contains(&secs), "{}", secs);
+        limits.admit("key:b").await.unwrap();
+    }
+}
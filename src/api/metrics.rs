@@ -0,0 +1,12 @@
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+use crate::api::AppState;
+use crate::metrics;
+
+/// GET /metrics — Prometheus text exposition format. Needs the `metrics` scope.
+pub async fn prometheus(State(state): State<AppState>) -> impl IntoResponse {
+    let body = metrics::global().render(state.llm.queue_depth());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], body)
+}
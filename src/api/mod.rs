@@ -1,27 +1,48 @@
+pub mod admin;
+pub mod auth;
 pub mod chat;
+pub mod context;
 pub mod health;
+pub mod limits;
+pub mod metrics;
 pub mod models;
+pub mod tools;
 
-use axum::{Router, routing::{get, post}};
+use axum::{Router, middleware, routing::{get, post}};
 use std::sync::Arc;
 use crate::llm::LlmActor;
 use crate::memory::MemoryStore;
 use crate::security::DeviceIdentity;
-use crate::plugins::PluginRegistry;
+use crate::plugins::PluginHandle;
+use limits::RateLimiter;
 
 #[derive(Clone)]
 pub struct AppState {
     pub llm:     Arc<LlmActor>,
     pub memory:  Arc<MemoryStore>,
     pub device:  Arc<DeviceIdentity>,
-    pub plugins: Arc<PluginRegistry>,
+    pub plugins: PluginHandle,
+    /// Bearer token with the `admin` scope, for when no admin key exists yet.
+    pub admin_token: Option<Arc<str>>,
+    /// Require an API key on every route except /health (`API_AUTH`, on by default).
+    pub api_auth: bool,
+    /// Run plugins in the Linux sandbox (`PLUGIN_SANDBOX`, on by default).
+    pub plugin_sandbox: bool,
+    /// Per-client request and token limits on chat completions.
+    pub limits: RateLimiter,
 }
 
 pub fn router(state: AppState) -> Router {
-    Router::new()
+    let protected = Router::new()
         .route("/v1/chat/completions", post(chat::chat_completions))
         .route("/v1/models",           get(models::list_models))
+        .route("/admin/plugins/reload", post(admin::reload_plugins))
+        .route("/metrics",             get(metrics::prometheus))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_key));
+
+    Router::new()
         .route("/health",              get(health::health_check))
         .route("/health/ready",        get(health::readiness_check))
+        .merge(protected)
         .with_state(state)
 }
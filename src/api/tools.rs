@@ -0,0 +1,408 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::api::chat::{build_prompt, run_plugin, ChatMessage};
+use crate::api::context::fit_to_context;
+use crate::api::AppState;
+use crate::api::auth::{authorize, Caller, Scope};
+use crate::errors::AppError;
+use crate::llm::{FinishReason, Grammar, InferParams, TokenUsage};
+use crate::plugins::PluginRegistry;
+
+/// Rounds of plugin calls agent mode allows before it asks for a final answer.
+const MAX_AGENT_STEPS: usize = 5;
+/// Plugin output fed back to the model is cut to this many characters.
+const MAX_TOOL_RESULT_CHARS: usize = 4000;
+
+const TOOL_CALL_OPEN: &str = "<tool_call>";
+const TOOL_CALL_CLOSE: &str = "</tool_call>";
+/// Opens a tool result. Also a stop sequence: models tend to invent one
+/// right after calling a tool.
+pub(crate) const TOOL_RESPONSE_OPEN: &str = "<tool_response>";
+const TOOL_RESPONSE_CLOSE: &str = "</tool_response>";
+
+// ─── Request / Response types ────────────────────────────────────────────────
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDef,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FunctionDef {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// JSON Schema of the arguments object.
+    #[serde(default = "empty_parameters")]
+    pub parameters: Value,
+}
+
+fn empty_parameters() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+/// `"none"`, `"auto"`, `"required"` or `{"type": "function", "function": {"name": ...}}`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: FunctionName },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON-encoded arguments object, as in the OpenAI API.
+    pub arguments: String,
+}
+
+// ─── Tool sets ───────────────────────────────────────────────────────────────
+
+enum ToolMode {
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model must call one of the tools.
+    Required,
+    /// The model must call this tool.
+    Named(String),
+}
+
+/// Tools offered to the model for one request.
+///
+/// Local models have no native tool API, so the tools are described in the
+/// system prompt and calls come back as `<tool_call>{...}</tool_call>` blocks
+/// (the Hermes / Qwen convention, which most instruct models follow).
+pub(crate) struct ToolSet {
+    functions: Vec<FunctionDef>,
+    mode: ToolMode,
+}
+
+impl ToolSet {
+    /// Tools sent by the client. `None` when there are none or `tool_choice` is `"none"`.
+    pub(crate) fn from_request(tools: &[Tool], choice: Option<&ToolChoice>) -> Result<Option<Self>, AppError> {
+        if tools.is_empty() {
+            return match choice {
+                None => Ok(None),
+                Some(_) => Err(AppError::InvalidRequest("tool_choice requires tools".into())),
+            };
+        }
+
+        let mut functions: Vec<FunctionDef> = Vec::with_capacity(tools.len());
+        for tool in tools {
+            if tool.kind != "function" {
+                return Err(AppError::InvalidRequest(format!(
+                    "unsupported tool type {:?}; only \"function\" is supported",
+                    tool.kind
+                )));
+            }
+            let name = &tool.function.name;
+            let valid = !name.is_empty()
+                && name.len() <= 64
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !valid {
+                return Err(AppError::InvalidRequest(format!(
+                    "invalid tool name {:?}: use 1-64 letters, digits, '_' or '-'",
+                    name
+                )));
+            }
+            if functions.iter().any(|f| &f.name == name) {
+                return Err(AppError::InvalidRequest(format!("duplicate tool name {:?}", name)));
+            }
+            if !tool.function.parameters.is_object() {
+                return Err(AppError::InvalidRequest(format!(
+                    "parameters of tool {:?} must be a JSON Schema object",
+                    name
+                )));
+            }
+            functions.push(tool.function.clone());
+        }
+
+        let mode = match choice {
+            None => ToolMode::Auto,
+            Some(ToolChoice::Mode(mode)) => match mode.as_str() {
+                "none" => return Ok(None),
+                "auto" => ToolMode::Auto,
+                "required" => ToolMode::Required,
+                other => {
+                    return Err(AppError::InvalidRequest(format!(
+                        "tool_choice must be \"none\", \"auto\", \"required\" or a function, got {:?}",
+                        other
+                    )))
+                }
+            },
+            Some(ToolChoice::Function { function }) => {
+                if !functions.iter().any(|f| f.name == function.name) {
+                    return Err(AppError::InvalidRequest(format!(
+                        "tool_choice names unknown tool {:?}",
+                        function.name
+                    )));
+                }
+                ToolMode::Named(function.name.clone())
+            }
+        };
+
+        Ok(Some(Self { functions, mode }))
+    }
+
+    /// Every plugin `caller` may run as a tool, for agent mode. `None` without plugins.
+    pub(crate) fn for_plugins(registry: &PluginRegistry, caller: &Caller) -> Option<Self> {
+        let functions: Vec<FunctionDef> = registry
+            .manifests()
+            .into_iter()
+            .filter(|m| caller.may_run(&m.name))
+            .map(|m| FunctionDef {
+                name: m.name.clone(),
+                description: Some(m.description.clone()),
+                parameters: m.tool_parameters(),
+            })
+            .collect();
+        if functions.is_empty() {
+            return None;
+        }
+        Some(Self { functions, mode: ToolMode::Auto })
+    }
+
+    /// Instructions appended to the system prompt.
+    fn system_prompt(&self) -> String {
+        let specs: Vec<String> = self
+            .functions
+            .iter()
+            .map(|f| {
+                serde_json::json!({
+                    "name": f.name,
+                    "description": f.description.as_deref().unwrap_or(""),
+                    "parameters": f.parameters,
+                })
+                .to_string()
+            })
+            .collect();
+        let rule = match &self.mode {
+            ToolMode::Auto => "Call a tool only when it helps; otherwise answer directly.".to_string(),
+            ToolMode::Required => "You must call at least one tool.".to_string(),
+            ToolMode::Named(name) => format!("You must call the `{}` tool.", name),
+        };
+        format!(
+            "You can call these tools:\n<tools>\n{}\n</tools>\n\n\
+             To call a tool, reply with a JSON object holding its name and arguments inside \
+             <tool_call></tool_call> tags, one block per call:\n\
+             <tool_call>{{\"name\": \"<tool name>\", \"arguments\": {{...}}}}</tool_call>\n\
+             Tool results are returned inside <tool_response></tool_response> tags. {}",
+            specs.join("\n"),
+            rule
+        )
+    }
+
+    /// Grammar forcing a call when `tool_choice` requires one.
+    pub(crate) fn grammar(&self) -> Result<Option<Grammar>, AppError> {
+        let only = match &self.mode {
+            ToolMode::Auto => return Ok(None),
+            ToolMode::Required => None,
+            ToolMode::Named(name) => Some(name),
+        };
+        let tools = self
+            .functions
+            .iter()
+            .filter(|f| only.is_none_or(|name| &f.name == name))
+            .map(|f| (f.name.as_str(), &f.parameters));
+        Grammar::tool_call(tools).map(Some)
+    }
+
+    /// Split model output into plain text and tool calls. Blocks that are not
+    /// valid calls to one of these tools are left in the text.
+    pub(crate) fn parse_calls(&self, output: &str) -> (String, Vec<ToolCall>) {
+        let mut text = String::new();
+        let mut calls = Vec::new();
+        let mut rest = output;
+
+        while let Some(start) = rest.find(TOOL_CALL_OPEN) {
+            let body_start = start + TOOL_CALL_OPEN.len();
+            // Generation may have ended right before the closing tag.
+            let (body, next) = match rest[body_start..].find(TOOL_CALL_CLOSE) {
+                Some(end) => (&rest[body_start..body_start + end], body_start + end + TOOL_CALL_CLOSE.len()),
+                None => (&rest[body_start..], rest.len()),
+            };
+            match self.parse_call(body) {
+                Some(call) => {
+                    text.push_str(&rest[..start]);
+                    calls.push(call);
+                }
+                None => text.push_str(&rest[..next]),
+            }
+            rest = &rest[next..];
+        }
+        text.push_str(rest);
+        (text.trim().to_string(), calls)
+    }
+
+    fn parse_call(&self, body: &str) -> Option<ToolCall> {
+        let call: Value = serde_json::from_str(body.trim()).ok()?;
+        let name = call.get("name")?.as_str()?;
+        if !self.functions.iter().any(|f| f.name == name) {
+            return None;
+        }
+        let arguments = match call.get("arguments") {
+            Some(Value::Object(args)) => Value::Object(args.clone()).to_string(),
+            // Some models double-encode the arguments.
+            Some(Value::String(args)) if serde_json::from_str::<Value>(args).is_ok_and(|v| v.is_object()) => {
+                args.clone()
+            }
+            None => "{}".to_string(),
+            _ => return None,
+        };
+        Some(ToolCall {
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            kind: "function".into(),
+            function: FunctionCall { name: name.to_string(), arguments },
+        })
+    }
+}
+
+/// Add the tool instructions to the first system message, or a new one.
+pub(crate) fn with_tools_prompt(mut messages: Vec<ChatMessage>, tools: &ToolSet) -> Vec<ChatMessage> {
+    let prompt = tools.system_prompt();
+    match messages.iter_mut().find(|m| m.role == "system") {
+        Some(system) => {
+            system.content.push_str("\n\n");
+            system.content.push_str(&prompt);
+        }
+        None => messages.insert(0, ChatMessage::new("system", prompt)),
+    }
+    messages
+}
+
+// ─── Prompt rendering ────────────────────────────────────────────────────────
+
+/// Role and text of `message` as the model should see it: assistant tool calls
+/// as `<tool_call>` blocks, tool results as `<tool_response>` blocks in a user turn.
+pub(crate) fn prompt_turn<'a>(message: &'a ChatMessage, all: &[ChatMessage]) -> (&'a str, String) {
+    if message.role == "tool" {
+        let name = message.tool_call_id.as_deref().and_then(|id| {
+            all.iter()
+                .flat_map(|m| m.tool_calls.iter().flatten())
+                .find(|c| c.id == id)
+                .map(|c| c.function.name.as_str())
+        });
+        let result = serde_json::json!({ "name": name.unwrap_or("tool"), "content": message.content });
+        return ("user", format!("{}\n{}\n{}", TOOL_RESPONSE_OPEN, result, TOOL_RESPONSE_CLOSE));
+    }
+
+    let mut content = message.content.clone();
+    for call in message.tool_calls.iter().flatten() {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&render_call(call));
+    }
+    (message.role.as_str(), content)
+}
+
+fn render_call(call: &ToolCall) -> String {
+    let arguments: Value = serde_json::from_str(&call.function.arguments)
+        .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+    let body = serde_json::json!({ "name": call.function.name, "arguments": arguments });
+    format!("{}{}{}", TOOL_CALL_OPEN, body, TOOL_CALL_CLOSE)
+}
+
+// ─── Agent mode ──────────────────────────────────────────────────────────────
+
+/// Final answer of an agent run.
+pub(crate) struct AgentAnswer {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    /// Summed over every model call of the run.
+    pub usage: TokenUsage,
+}
+
+/// Let the model call plugins until it answers: each round runs the requested
+/// plugins and appends their results to the conversation.
+pub(crate) async fn run_agent(
+    state: &AppState,
+    caller: &Caller,
+    session_id: Option<&str>,
+    mut messages: Vec<ChatMessage>,
+    params: InferParams,
+    tools: &ToolSet,
+) -> Result<AgentAnswer, AppError> {
+    let mut usage = TokenUsage::default();
+    let mut step = 0;
+    loop {
+        let last = step == MAX_AGENT_STEPS;
+        if last {
+            messages.push(ChatMessage::new(
+                "system",
+                "No more tool calls are possible. Answer with the information you have.",
+            ));
+        }
+
+        let fitted = fit_to_context(state, session_id, messages.clone(), params.max_tokens, None).await?;
+        let completion = state.llm.infer(build_prompt(&state.llm, &fitted), params.clone()).await?;
+        usage.prompt_tokens += completion.usage.prompt_tokens;
+        usage.completion_tokens += completion.usage.completion_tokens;
+
+        let (text, calls) = tools.parse_calls(&completion.text);
+        if calls.is_empty() || last {
+            return Ok(AgentAnswer { text, finish_reason: completion.finish_reason, usage });
+        }
+
+        let mut results = Vec::with_capacity(calls.len());
+        for call in &calls {
+            results.push(ChatMessage {
+                tool_call_id: Some(call.id.clone()),
+                ..ChatMessage::new("tool", call_plugin(state, caller, call).await)
+            });
+        }
+        messages.push(ChatMessage { tool_calls: Some(calls), ..ChatMessage::new("assistant", text) });
+        messages.extend(results);
+        step += 1;
+    }
+}
+
+/// Run the plugin behind a tool call. Failures are reported to the model as
+/// the tool result so it can recover.
+async fn call_plugin(state: &AppState, caller: &Caller, call: &ToolCall) -> String {
+    let name = &call.function.name;
+    let Some(manifest) = state.plugins.current().manifests().into_iter().find(|m| &m.name == name).cloned() else {
+        return format!("error: unknown tool {}", name);
+    };
+    if let Err(e) = authorize(state, caller, &Scope::Plugin(manifest.name.clone())).await {
+        return format!("error: {}", e);
+    }
+    let mut arguments: Value = serde_json::from_str(&call.function.arguments).unwrap_or(Value::Null);
+    let (command, action) = match manifest.tool_route(&mut arguments) {
+        Ok(route) => route,
+        Err(e) => return format!("error: {}", e),
+    };
+    let payload = match manifest.tool_payload(&command, &arguments) {
+        Ok(payload) => payload,
+        Err(e) => return format!("error: {}", e),
+    };
+
+    info!(plugin = %manifest.name, action, payload = %payload, "Agent calling plugin");
+    let result = match run_plugin(state, &manifest, action, payload).await {
+        Ok(r) if r.success => r.result.to_string(),
+        Ok(r) => format!("error: {}", r.error.unwrap_or_else(|| "unknown".into())),
+        Err(e) => format!("error: {}", e),
+    };
+    if result.starts_with("error:") {
+        warn!(plugin = %name, result = %result, "Agent plugin call failed");
+    }
+    result.chars().take(MAX_TOOL_RESULT_CHARS).collect()
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+
+use crate::api::auth::{generate_key, hash_key, parse_scopes};
+use crate::config::{Config, DEFAULT_CONFIG_PATH};
+use crate::memory::MemoryStore;
+use crate::plugins::{check_signature, PluginManifest};
+use crate::security::{self, DeviceIdentity, SignaturePolicy, TrustStore};
+
+const USAGE: &str = "\
+Usage:
+  broai                                            Run the server
+  broai plugin keygen <publisher> [--out <dir>]    Create a publisher signing key
+  broai plugin sign <dir> --key <publisher.key>    Write <plugin>.sig for each plugin in <dir>
+  broai plugin verify <dir> [--trust-dir <dir>]    Check the signatures in <dir> like a device would
+  broai plugin pack <dir> [--out <dir>]            Bundle each signed plugin into <name>-<version>.tar.gz
+  broai key create <name> --scopes <list> [--db <path>]
+                                                   Create an API key; scopes are chat, admin, metrics,
+                                                   plugins:<name> or plugins:*, comma-separated
+  broai key list [--db <path>]                     List API keys and their scopes
+  broai key revoke <name> [--db <path>]            Revoke an API key
+  broai config check [--config <path>]             Print the effective configuration and any errors";
+
+/// Run `broai <args>` as a command-line tool and return the exit code.
+pub async fn run(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["plugin", "keygen", rest @ ..] => keygen(rest),
+        ["plugin", "sign", rest @ ..] => sign(rest),
+        ["plugin", "verify", rest @ ..] => verify(rest),
+        ["plugin", "pack", rest @ ..] => pack(rest),
+        ["key", "create", rest @ ..] => key_create(rest).await,
+        ["key", "list", rest @ ..] => key_list(rest).await,
+        ["key", "revoke", rest @ ..] => key_revoke(rest).await,
+        ["config", "check", rest @ ..] => config_check(rest),
+        ["help" | "--help" | "-h"] => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            1
+        }
+    }
+}
+
+// ─── Commands ────────────────────────────────────────────────────────────────
+
+/// `<publisher>.key` stays with the publisher; `<publisher>.pub` goes into
+/// `TRUST_DIR` on every device that should run their plugins.
+fn keygen(args: &[&str]) -> Result<()> {
+    let (publisher, options) = parse_args(args, &["--out"])?;
+    if publisher.is_empty() || !publisher.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("publisher name may only contain letters, digits, `-` and `_`");
+    }
+    let out = PathBuf::from(options.get("--out").copied().unwrap_or("."));
+    let key_path = out.join(format!("{}.key", publisher));
+    let pub_path = out.join(format!("{}.pub", publisher));
+    for path in [&key_path, &pub_path] {
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+    }
+
+    let key = DeviceIdentity::generate(&key_path.to_string_lossy())
+        .with_context(|| format!("cannot write {}", key_path.display()))?;
+    fs::write(&pub_path, format!("{}\n", key.public_key_hex()))
+        .with_context(|| format!("cannot write {}", pub_path.display()))?;
+
+    println!("Private key: {} (keep it secret)", key_path.display());
+    println!("Public key:  {}", pub_path.display());
+    println!("Copy {} into TRUST_DIR on each device to trust plugins signed with this key.", pub_path.display());
+    Ok(())
+}
+
+fn sign(args: &[&str]) -> Result<()> {
+    let (dir, options) = parse_args(args, &["--key"])?;
+    let key_path = options.get("--key").context("--key <publisher.key> is required")?;
+    let key = DeviceIdentity::load(key_path).with_context(|| format!("cannot load {}", key_path))?;
+
+    for plugin in local_plugins(Path::new(dir))? {
+        let sig = security::sign_plugin(&key, &plugin.binary, plugin.manifest_text.as_bytes());
+        let sig_path = plugin.sig_path(dir);
+        fs::write(&sig_path, serde_json::to_string_pretty(&sig)? + "\n")
+            .with_context(|| format!("cannot write {}", sig_path.display()))?;
+        println!("signed  {} {}", plugin.manifest.name, plugin.manifest.version);
+    }
+    Ok(())
+}
+
+/// Same checks as the registry with `REQUIRE_SIGNED_PLUGINS=true`.
+fn verify(args: &[&str]) -> Result<()> {
+    let (dir, options) = parse_args(args, &["--trust-dir"])?;
+    let trust_dir = match options.get("--trust-dir") {
+        Some(d) => d.to_string(),
+        None => Config::layered(None).config.plugins.trust_dir,
+    };
+    let policy = SignaturePolicy { trust_dir: trust_dir.into(), require_signed: true };
+    let trust = TrustStore::load(&policy.trust_dir);
+
+    let plugins = local_plugins(Path::new(dir))?;
+    let mut failed = 0;
+    for plugin in &plugins {
+        let name = &plugin.manifest.name;
+        match check_signature(Path::new(dir), name, &plugin.binary, &plugin.manifest_text, &trust, &policy) {
+            Ok(publisher) => println!(
+                "ok      {} {} signed by {}",
+                name,
+                plugin.manifest.version,
+                publisher.as_deref().unwrap_or("?")
+            ),
+            Err(e) => {
+                println!("FAILED  {} {}: {}", name, plugin.manifest.version, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} plugins failed verification against {}", failed, plugins.len(), policy.trust_dir.display());
+    }
+    Ok(())
+}
+
+/// One archive per plugin, holding a `<name>-<version>/` directory with the
+/// binary, manifest, signature and a `metadata.json` describing them.
+fn pack(args: &[&str]) -> Result<()> {
+    let (dir, options) = parse_args(args, &["--out"])?;
+    let out = PathBuf::from(options.get("--out").copied().unwrap_or("."));
+
+    for plugin in local_plugins(Path::new(dir))? {
+        let manifest = &plugin.manifest;
+        let sig_path = plugin.sig_path(dir);
+        if !sig_path.exists() {
+            bail!("{} is not signed — run `broai plugin sign` first", manifest.name);
+        }
+        let sig_text = fs::read_to_string(&sig_path).with_context(|| format!("cannot read {}", sig_path.display()))?;
+        let sig = security::verify_self_signed(&plugin.binary, plugin.manifest_text.as_bytes(), &sig_text)
+            .map_err(|e| anyhow::anyhow!("{}: {} — sign it again", sig_path.display(), e))?;
+
+        let metadata = serde_json::to_string_pretty(&json!({
+            "name": manifest.name,
+            "version": manifest.version,
+            "description": manifest.description,
+            "commands": manifest.commands,
+            "publisher_key": sig.key,
+            "binary_sha256": hex::encode(security::sha256(&plugin.binary)),
+            "manifest_sha256": hex::encode(security::sha256(plugin.manifest_text.as_bytes())),
+            "packed_at": chrono::Utc::now().to_rfc3339(),
+            "packed_by": format!("broai {}", env!("CARGO_PKG_VERSION")),
+        }))? + "\n";
+
+        let root = format!("{}-{}", manifest.name, manifest.version);
+        let archive_path = out.join(format!("{}.tar.gz", root));
+        let file = fs::File::create(&archive_path)
+            .with_context(|| format!("cannot create {}", archive_path.display()))?;
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()));
+        let entries: [(String, &[u8], u32); 4] = [
+            (manifest.name.clone(), &plugin.binary, 0o755),
+            (format!("{}.json", manifest.name), plugin.manifest_text.as_bytes(), 0o644),
+            (format!("{}.sig", manifest.name), sig_text.as_bytes(), 0o644),
+            ("metadata.json".into(), metadata.as_bytes(), 0o644),
+        ];
+        let mtime = chrono::Utc::now().timestamp() as u64;
+        for (name, data, mode) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(mtime);
+            tar.append_data(&mut header, format!("{}/{}", root, name), data)?;
+        }
+        tar.into_inner()?.finish()?;
+        println!("packed  {}", archive_path.display());
+    }
+    Ok(())
+}
+
+/// The key is printed once; only its hash is stored.
+async fn key_create(args: &[&str]) -> Result<()> {
+    let (name, options) = parse_args(args, &["--scopes", "--db"])?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("key name may only contain letters, digits, `-` and `_`");
+    }
+    let scopes = parse_scopes(options.get("--scopes").context("--scopes <list> is required")?)
+        .map_err(anyhow::Error::msg)?;
+    let scopes = scopes.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ");
+
+    let memory = open_db(&options)?;
+    if memory.list_api_keys().await?.iter().any(|k| k.name == name && k.revoked_at.is_none()) {
+        bail!("a key named `{}` already exists — revoke it first", name);
+    }
+    let key = generate_key();
+    memory.create_api_key(name, &hash_key(&key), &scopes).await?;
+    memory
+        .log_audit("api_key_created", Some(&json!({ "key": name, "scopes": scopes }).to_string()))
+        .await?;
+
+    println!("{}", key);
+    eprintln!("Created key `{}` with scopes: {}. It is not shown again.", name, scopes);
+    Ok(())
+}
+
+async fn key_list(args: &[&str]) -> Result<()> {
+    let (positional, options) = parse_options(args, &["--db"])?;
+    if !positional.is_empty() {
+        bail!("unexpected argument {}\n\n{}", positional[0], USAGE);
+    }
+    let keys = open_db(&options)?.list_api_keys().await?;
+    if keys.is_empty() {
+        println!("no API keys — create one with `broai key create`");
+    }
+    for key in keys {
+        let status = match &key.revoked_at {
+            Some(at) => format!("revoked {}", at),
+            None => "active".into(),
+        };
+        println!("{:<20} {:<20} {:<28} {}", key.name, key.created_at, status, key.scopes);
+    }
+    Ok(())
+}
+
+async fn key_revoke(args: &[&str]) -> Result<()> {
+    let (name, options) = parse_args(args, &["--db"])?;
+    let memory = open_db(&options)?;
+    if !memory.revoke_api_key(name).await? {
+        bail!("no active key named `{}`", name);
+    }
+    memory.log_audit("api_key_revoked", Some(&json!({ "key": name }).to_string())).await?;
+    println!("revoked {}", name);
+    Ok(())
+}
+
+/// Print the merged configuration as the server would see it, then every
+/// problem with it. Fails if there is any.
+fn config_check(args: &[&str]) -> Result<()> {
+    let (positional, options) = parse_options(args, &["--config"])?;
+    if !positional.is_empty() {
+        bail!("unexpected argument {}\n\n{}", positional[0], USAGE);
+    }
+    let layered = Config::layered(options.get("--config").map(Path::new));
+    match &layered.file {
+        Some(file) => println!("# defaults < {} < environment", file.display()),
+        None => println!("# defaults < environment ({} not found)", DEFAULT_CONFIG_PATH),
+    }
+    println!();
+    print!("{}", layered.config.to_toml());
+
+    if layered.errors.is_empty() {
+        eprintln!("configuration OK");
+        return Ok(());
+    }
+    for error in &layered.errors {
+        eprintln!("error: {}", error);
+    }
+    bail!("{} problem(s) in the configuration", layered.errors.len())
+}
+
+// ─── Helpers ─────────────────────────────────────────────────────────────────
+
+/// The server's memory database: `--db`, else `memory.db_path` from the
+/// configuration.
+fn open_db(options: &HashMap<&str, &str>) -> Result<MemoryStore> {
+    let path = match options.get("--db") {
+        Some(p) => p.to_string(),
+        None => Config::layered(None).config.memory.db_path,
+    };
+    MemoryStore::open(&path).with_context(|| format!("cannot open {}", path))
+}
+
+/// A plugin staged in a directory: `<name>.json` and the `<name>` binary.
+struct LocalPlugin {
+    manifest: PluginManifest,
+    manifest_text: String,
+    binary: Vec<u8>,
+}
+
+impl LocalPlugin {
+    fn sig_path(&self, dir: &str) -> PathBuf {
+        Path::new(dir).join(format!("{}.sig", self.manifest.name))
+    }
+}
+
+/// Every manifest in `dir` with its binary. Unlike the registry, which skips
+/// broken plugins, any problem here is an error.
+fn local_plugins(dir: &Path) -> Result<Vec<LocalPlugin>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("cannot read {}", dir.display()))?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("json"))
+        .collect();
+    paths.sort();
+
+    let mut plugins = Vec::new();
+    for path in paths {
+        let manifest_text = fs::read_to_string(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        let mut manifest: PluginManifest = serde_json::from_str(&manifest_text)
+            .with_context(|| format!("invalid manifest {}", path.display()))?;
+        manifest.check().map_err(anyhow::Error::msg).with_context(|| format!("invalid manifest {}", path.display()))?;
+        let bin = dir.join(&manifest.name);
+        let binary = fs::read(&bin).with_context(|| format!("cannot read binary {}", bin.display()))?;
+        plugins.push(LocalPlugin { manifest, manifest_text, binary });
+    }
+    if plugins.is_empty() {
+        bail!("no plugin manifests (*.json) in {}", dir.display());
+    }
+    Ok(plugins)
+}
+
+/// Split `args` into exactly one positional argument and `--flag value` pairs.
+fn parse_args<'a>(args: &[&'a str], flags: &[&str]) -> Result<(&'a str, HashMap<&'a str, &'a str>)> {
+    let (positional, options) = parse_options(args, flags)?;
+    match positional.as_slice() {
+        [one] => Ok((one, options)),
+        _ => bail!("expected one argument\n\n{}", USAGE),
+    }
+}
+
+/// Split `args` into positional arguments and `--flag value` pairs.
+fn parse_options<'a>(args: &[&'a str], flags: &[&str]) -> Result<(Vec<&'a str>, HashMap<&'a str, &'a str>)> {
+    let mut positional = Vec::new();
+    let mut options = HashMap::new();
+    let mut rest = args.iter();
+    while let Some(&arg) = rest.next() {
+        if arg.starts_with("--") {
+            if !flags.contains(&arg) {
+                bail!("unknown option {}\n\n{}", arg, USAGE);
+            }
+            let value = rest.next().with_context(|| format!("{} needs a value", arg))?;
+            options.insert(arg, *value);
+        } else {
+            positional.push(arg);
+        }
+    }
+    Ok((positional, options))
+}
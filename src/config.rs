@@ -0,0 +1,405 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::api::limits::{LimitBy, RateLimitPolicy};
+use crate::errors::AppError;
+use crate::llm::{ChatTemplate, SamplingOverrides, SamplingParams};
+use crate::memory::DEFAULT_DB_PATH;
+use crate::plugins::{Capabilities, PluginSettings, MAX_PLUGIN_TIMEOUT_SECS, PLUGIN_TIMEOUT_SECS};
+use crate::security::DEFAULT_TRUST_DIR;
+
+/// Read when `BROAI_CONFIG` is unset. Unlike a named file, it may be missing.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/broai/broai.toml";
+
+/// Smallest context window worth running a chat model with.
+const MIN_CONTEXT_SIZE: u32 = 256;
+
+// ─── Sections ────────────────────────────────────────────────────────────────
+
+/// Server settings: built-in defaults, then the TOML file, then environment
+/// variables. Each field notes the variable that overrides it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub model: ModelConfig,
+    /// Sampler defaults for every model; `<model>.sampling.json` and request
+    /// fields override them.
+    #[serde(deserialize_with = "SamplingOverrides::deserialize_strict")]
+    pub sampling: SamplingOverrides,
+    pub queue: QueueConfig,
+    pub memory: MemoryConfig,
+    pub plugins: PluginsConfig,
+    pub security: SecurityConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `HOST`
+    pub host: String,
+    /// `PORT`
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    /// `MODEL_PATH`; inference is mocked if the file does not exist.
+    pub path: String,
+    /// `CHAT_TEMPLATE`; detected from the GGUF metadata when unset.
+    pub chat_template: Option<ChatTemplate>,
+    /// `CONTEXT_SIZE`: tokens per session.
+    pub context_size: u32,
+    /// `LLM_THREADS`; 0 uses every core.
+    pub threads: u32,
+    /// `MAX_GENERATION_TOKENS`: upper bound on any request's `max_tokens`.
+    pub max_generation_tokens: u32,
+    /// `INFERENCE_TIMEOUT_SECS`
+    pub inference_timeout_secs: u64,
+    /// `SESSION_CACHE_SIZE`: KV-cache sessions kept for reuse; 0 disables.
+    pub session_cache_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// `QUEUE_CAPACITY`: requests that may wait for the model at once.
+    pub capacity: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// `DB_PATH`
+    pub db_path: String,
+    /// `MEMORY_RETENTION_DAYS`: conversations older than this are deleted; 0 keeps them.
+    pub retention_days: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginsConfig {
+    /// `PLUGIN_DIR`
+    pub dir: String,
+    /// `TRUST_DIR`: publisher keys plugin signatures are checked against.
+    pub trust_dir: String,
+    /// `REQUIRE_SIGNED_PLUGINS`
+    pub require_signed: bool,
+    /// `PLUGIN_SANDBOX`
+    pub sandbox: bool,
+    /// `PLUGIN_TIMEOUT_SECS`: run time for manifests without `timeout_secs`.
+    pub timeout_secs: u64,
+    /// Replaces a plugin's `fs_read` capability, by plugin name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fs_read: BTreeMap<String, Vec<PathBuf>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// `KEY_PATH`: the device identity key.
+    pub key_path: String,
+    /// `API_AUTH`
+    pub api_auth: bool,
+    /// `ADMIN_TOKEN`
+    pub admin_token: Option<String>,
+    /// `RATE_LIMIT_BY`
+    pub rate_limit_by: LimitBy,
+    /// `RATE_LIMIT_RPM`; 0 disables.
+    pub rate_limit_rpm: u32,
+    /// `TOKEN_QUOTA_PER_DAY`; 0 disables.
+    pub token_quota_per_day: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { host: "0.0.0.0".into(), port: 8080 }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            path: "/opt/broai/models/model.gguf".into(),
+            chat_template: None,
+            context_size: 2048,
+            threads: 0,
+            max_generation_tokens: 512,
+            inference_timeout_secs: 300,
+            session_cache_size: 4,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self { capacity: 32 }
+    }
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self { db_path: DEFAULT_DB_PATH.into(), retention_days: 0 }
+    }
+}
+
+impl Default for PluginsConfig {
+    fn default() -> Self {
+        Self {
+            dir: "/opt/broai/plugins".into(),
+            trust_dir: DEFAULT_TRUST_DIR.into(),
+            require_signed: false,
+            sandbox: true,
+            timeout_secs: PLUGIN_TIMEOUT_SECS,
+            fs_read: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            key_path: "/var/lib/broai/device.key".into(),
+            api_auth: true,
+            admin_token: None,
+            rate_limit_by: LimitBy::Key,
+            rate_limit_rpm: 0,
+            token_quota_per_day: 0,
+        }
+    }
+}
+
+// ─── Loading ─────────────────────────────────────────────────────────────────
+
+/// The merged configuration and every problem found while building it.
+pub struct Layered {
+    pub config: Config,
+    /// The file that was read, if any.
+    pub file: Option<PathBuf>,
+    pub errors: Vec<String>,
+}
+
+impl Config {
+    /// Merge the layers and validate the result. `path` takes precedence over
+    /// `BROAI_CONFIG`; a file named either way must exist.
+    pub fn layered(path: Option<&Path>) -> Layered {
+        let mut errors = Vec::new();
+        let named = path.map(Path::to_path_buf).or_else(|| env_value("BROAI_CONFIG").map(PathBuf::from));
+        let path = named.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let (mut config, file) = match std::fs::read_to_string(&path) {
+            Ok(text) => match toml::from_str::<Config>(&text) {
+                Ok(config) => (config, Some(path)),
+                Err(e) => {
+                    let line = e.span().map(|span| text[..span.start].matches('\n').count() + 1).unwrap_or(1);
+                    errors.push(format!("{} line {}: {}", path.display(), line, e.message()));
+                    (Config::default(), Some(path))
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && named.is_none() => (Config::default(), None),
+            Err(e) => {
+                errors.push(format!("cannot read {}: {}", path.display(), e));
+                (Config::default(), None)
+            }
+        };
+        config.apply_env(&mut errors);
+        errors.extend(config.validate());
+        Layered { config, file, errors }
+    }
+
+    /// The server's configuration; any problem is a `ConfigError`.
+    pub fn load() -> Result<Self, AppError> {
+        let layered = Self::layered(None);
+        if !layered.errors.is_empty() {
+            return Err(AppError::ConfigError(layered.errors.join("; ")));
+        }
+        match &layered.file {
+            Some(file) => info!(file = %file.display(), "Configuration loaded"),
+            None => info!("No config file at {} — using defaults and environment", DEFAULT_CONFIG_PATH),
+        }
+        Ok(layered.config)
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        let mut env = Env { errors };
+        env.parse("HOST", &mut self.server.host);
+        env.parse("PORT", &mut self.server.port);
+
+        env.parse("MODEL_PATH", &mut self.model.path);
+        env.optional("CHAT_TEMPLATE", &mut self.model.chat_template);
+        env.parse("CONTEXT_SIZE", &mut self.model.context_size);
+        env.parse("LLM_THREADS", &mut self.model.threads);
+        env.parse("MAX_GENERATION_TOKENS", &mut self.model.max_generation_tokens);
+        env.parse("INFERENCE_TIMEOUT_SECS", &mut self.model.inference_timeout_secs);
+        env.parse("SESSION_CACHE_SIZE", &mut self.model.session_cache_size);
+
+        env.parse("QUEUE_CAPACITY", &mut self.queue.capacity);
+
+        env.parse("DB_PATH", &mut self.memory.db_path);
+        env.parse("MEMORY_RETENTION_DAYS", &mut self.memory.retention_days);
+
+        env.parse("PLUGIN_DIR", &mut self.plugins.dir);
+        env.parse("TRUST_DIR", &mut self.plugins.trust_dir);
+        env.flag("REQUIRE_SIGNED_PLUGINS", &mut self.plugins.require_signed);
+        env.flag("PLUGIN_SANDBOX", &mut self.plugins.sandbox);
+        env.parse("PLUGIN_TIMEOUT_SECS", &mut self.plugins.timeout_secs);
+
+        env.parse("KEY_PATH", &mut self.security.key_path);
+        env.flag("API_AUTH", &mut self.security.api_auth);
+        env.optional("ADMIN_TOKEN", &mut self.security.admin_token);
+        env.parse("RATE_LIMIT_BY", &mut self.security.rate_limit_by);
+        env.parse("RATE_LIMIT_RPM", &mut self.security.rate_limit_rpm);
+        env.parse("TOKEN_QUOTA_PER_DAY", &mut self.security.token_quota_per_day);
+    }
+
+    /// Every value that parsed but cannot be used, as `section.key: reason`.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, msg: String| {
+            if !ok {
+                errors.push(msg);
+            }
+        };
+
+        check(self.bind_addr().is_ok(), format!("server.host: `{}` is not an IP address", self.server.host));
+        check(!self.model.path.is_empty(), "model.path: must not be empty".into());
+        check(
+            self.model.context_size >= MIN_CONTEXT_SIZE,
+            format!("model.context_size: must be at least {}", MIN_CONTEXT_SIZE),
+        );
+        check(
+            (1..self.model.context_size).contains(&self.model.max_generation_tokens),
+            "model.max_generation_tokens: must be at least 1 and below model.context_size".into(),
+        );
+        check(self.model.inference_timeout_secs > 0, "model.inference_timeout_secs: must be at least 1".into());
+        if let Err(e) = SamplingParams::default().with_overrides(&self.sampling) {
+            check(false, format!("sampling: {}", reason(e)));
+        }
+        check(self.queue.capacity > 0, "queue.capacity: must be at least 1".into());
+        check(!self.memory.db_path.is_empty(), "memory.db_path: must not be empty".into());
+        check(
+            (1..=MAX_PLUGIN_TIMEOUT_SECS).contains(&self.plugins.timeout_secs),
+            format!("plugins.timeout_secs: must be between 1 and {}", MAX_PLUGIN_TIMEOUT_SECS),
+        );
+        for (plugin, paths) in &self.plugins.fs_read {
+            let caps = Capabilities { fs_read: paths.clone(), ..Default::default() };
+            if let Err(e) = caps.check() {
+                check(false, format!("plugins.fs_read.{}: {}", plugin, e));
+            }
+        }
+        check(!self.security.key_path.is_empty(), "security.key_path: must not be empty".into());
+        errors
+    }
+
+    pub fn bind_addr(&self) -> Result<SocketAddr, AppError> {
+        format!("{}:{}", self.server.host, self.server.port)
+            .parse()
+            .map_err(|_| AppError::ConfigError(format!("invalid bind address {}:{}", self.server.host, self.server.port)))
+    }
+
+    pub fn rate_limits(&self) -> RateLimitPolicy {
+        RateLimitPolicy {
+            by: self.security.rate_limit_by,
+            requests_per_minute: self.security.rate_limit_rpm,
+            tokens_per_day: self.security.token_quota_per_day,
+        }
+    }
+
+    pub fn plugin_settings(&self) -> PluginSettings {
+        PluginSettings { timeout_secs: self.plugins.timeout_secs, fs_read: self.plugins.fs_read.clone() }
+    }
+
+    /// The configuration as TOML, with sampler defaults filled in and the admin
+    /// token hidden.
+    pub fn to_toml(&self) -> String {
+        let mut shown = self.clone();
+        if shown.security.admin_token.is_some() {
+            shown.security.admin_token = Some("<redacted>".into());
+        }
+        if let Ok(sampling) = SamplingParams::default().with_overrides(&self.sampling) {
+            shown.sampling = (&sampling).into();
+        }
+        let mut table = match toml::Table::try_from(&shown) {
+            Ok(table) => table,
+            Err(e) => return format!("# cannot render configuration: {}\n", e),
+        };
+        // Sampler values are f32; print 0.7 rather than 0.699999988079071.
+        if let Some(toml::Value::Table(sampling)) = table.get_mut("sampling") {
+            for (_, value) in sampling.iter_mut() {
+                if let toml::Value::Float(f) = value {
+                    *f = (*f as f32).to_string().parse().unwrap_or(*f);
+                }
+            }
+        }
+        table.to_string()
+    }
+}
+
+/// The message of a sampling error, without the "Invalid request" prefix.
+fn reason(e: AppError) -> String {
+    match e {
+        AppError::InvalidRequest(msg) => msg,
+        other => other.to_string(),
+    }
+}
+
+// ─── Environment ─────────────────────────────────────────────────────────────
+
+/// Overrides fields from environment variables, collecting parse errors.
+/// Empty variables count as unset.
+struct Env<'a> {
+    errors: &'a mut Vec<String>,
+}
+
+impl Env<'_> {
+    fn parse<T: FromStr>(&mut self, name: &str, slot: &mut T)
+    where
+        T::Err: Display,
+    {
+        if let Some(value) = self.value(name, |v| v.parse::<T>().map_err(|e| e.to_string())) {
+            *slot = value;
+        }
+    }
+
+    fn optional<T: FromStr>(&mut self, name: &str, slot: &mut Option<T>)
+    where
+        T::Err: Display,
+    {
+        if let Some(value) = self.value(name, |v| v.parse::<T>().map_err(|e| e.to_string())) {
+            *slot = Some(value);
+        }
+    }
+
+    fn flag(&mut self, name: &str, slot: &mut bool) {
+        let parse = |v: &str| match v.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err("expected true or false".to_string()),
+        };
+        if let Some(value) = self.value(name, parse) {
+            *slot = value;
+        }
+    }
+
+    fn value<T>(&mut self, name: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Option<T> {
+        let raw = env_value(name)?;
+        match parse(raw.trim()) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("{}: invalid value `{}`: {}", name, raw, e));
+                None
+            }
+        }
+    }
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
@@ -15,12 +15,20 @@ pub enum AppError {
     #[error("Security error: {0}")]
     SecurityError(String),
 
+    /// No API key, or one that is unknown or revoked.
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
     #[error("Queue full - server overloaded")]
     QueueFull,
 
+    /// A per-client limit was hit; retry after the given number of seconds.
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(String, u64),
+
     #[error("Inference timeout after {0}s")]
     Timeout(u64),
 
@@ -37,26 +45,41 @@ pub enum AppError {
     SerdeError(#[from] serde_json::Error),
 }
 
+impl AppError {
+    /// OpenAI-style error body, shared by JSON responses and SSE error frames.
+    pub fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "message": self.to_string(),
+                "type": "edge_runtime_error"
+            }
+        })
+    }
+}
+
 impl axum::response::IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         use axum::http::StatusCode;
         use axum::Json;
 
-        let (status, message) = match &self {
-            AppError::QueueFull => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
-            AppError::Timeout(_) => (StatusCode::GATEWAY_TIMEOUT, self.to_string()),
-            AppError::InvalidRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            AppError::SecurityError(_) => (StatusCode::FORBIDDEN, self.to_string()),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+        let status = match &self {
+            AppError::QueueFull | AppError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::SecurityError(_) => StatusCode::FORBIDDEN,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        let body = serde_json::json!({
-            "error": {
-                "message": message,
-                "type": "edge_runtime_error"
-            }
-        });
-
-        (status, Json(body)).into_response()
+        let mut response = (status, Json(self.body())).into_response();
+        if let AppError::RateLimited(_, retry_after) = &self {
+            response.headers_mut().insert(axum::http::header::RETRY_AFTER, (*retry_after).into());
+        }
+        if let AppError::Unauthorized(_) = &self {
+            response
+                .headers_mut()
+                .insert(axum::http::header::WWW_AUTHENTICATE, axum::http::HeaderValue::from_static("Bearer"));
+        }
+        response
     }
 }
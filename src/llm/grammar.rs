@@ -0,0 +1,598 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use llama_cpp::grammar::LlamaGrammar;
+use llama_cpp::standard_sampler::SamplerStage;
+use serde_json::Value;
+
+use crate::errors::AppError;
+
+/// Largest GBNF grammar accepted, whether sent by a client or compiled from a schema.
+const MAX_GRAMMAR_BYTES: usize = 64 * 1024;
+/// Deepest schema nesting the compiler follows before giving up.
+const MAX_SCHEMA_DEPTH: usize = 32;
+/// Array lengths beyond this are not spelled out as bounded repetitions.
+const MAX_EXPANDED_REPEAT: u64 = 64;
+
+/// Building blocks for JSON. Every value rule consumes its trailing whitespace;
+/// `ws` is bounded so a model cannot pad forever.
+const JSON_RULES: &[(&str, &str)] = &[
+    ("ws", r#"( [ \t\n] ( [ \t\n] ( [ \t\n] ( [ \t\n] ( [ \t\n] ( [ \t\n] ( [ \t\n] [ \t\n]? )? )? )? )? )? )? )?"#),
+    ("value", "object | array | string | number | boolean | null"),
+    ("object", r#""{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws"#),
+    ("array", r#""[" ws ( value ( "," ws value )* )? "]" ws"#),
+    ("char", r#"[^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] )"#),
+    ("string", r#""\"" char* "\"" ws"#),
+    ("integer", r#""-"? ( [0-9] | [1-9] [0-9]* ) ws"#),
+    ("number", r#""-"? ( [0-9] | [1-9] [0-9]* ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws"#),
+    ("boolean", r#"( "true" | "false" ) ws"#),
+    ("null", r#""null" ws"#),
+];
+
+/// A GBNF grammar that constrains sampling, e.g. to JSON matching a schema.
+#[derive(Clone)]
+pub struct Grammar {
+    source: String,
+    compiled: LlamaGrammar,
+}
+
+impl fmt::Debug for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Grammar").field("bytes", &self.source.len()).finish()
+    }
+}
+
+impl Grammar {
+    /// Parse a client-supplied GBNF grammar. It must define `root` and every
+    /// rule it references.
+    pub fn parse(gbnf: &str) -> Result<Self, AppError> {
+        if gbnf.len() > MAX_GRAMMAR_BYTES {
+            return Err(AppError::InvalidRequest(format!(
+                "grammar exceeds {} bytes",
+                MAX_GRAMMAR_BYTES
+            )));
+        }
+        // llama.cpp does not check rule references itself and would read past
+        // the rule table on an undefined one.
+        check_rule_refs(gbnf).map_err(|e| AppError::InvalidRequest(format!("Invalid grammar: {}", e)))?;
+        let compiled = LlamaGrammar::from_str(gbnf)
+            .map_err(|e| AppError::InvalidRequest(format!("Invalid grammar: {}", e)))?;
+        Ok(Self { source: gbnf.to_string(), compiled })
+    }
+
+    /// Any JSON object (`response_format: {"type": "json_object"}`).
+    pub fn json_object() -> Result<Self, AppError> {
+        let mut c = SchemaCompiler::new(&Value::Null);
+        c.use_rule("object");
+        Self::parse(&c.finish("object"))
+    }
+
+    /// JSON matching `schema` (`response_format: {"type": "json_schema", ...}`).
+    ///
+    /// Covers `type`, `properties`/`required`, `additionalProperties`, `items`,
+    /// `minItems`/`maxItems`, `minLength`/`maxLength`, `enum`, `const`,
+    /// `anyOf`/`oneOf` and local `$ref`s. Value constraints such as
+    /// `minimum` or `pattern` are not enforced.
+    pub fn from_json_schema(schema: &Value) -> Result<Self, AppError> {
+        let mut c = SchemaCompiler::new(schema);
+        let root = c
+            .compile(schema, "root", 0)
+            .map_err(|e| AppError::InvalidRequest(format!("Unsupported JSON schema: {}", e)))?;
+        Self::parse(&c.finish(&root))
+    }
+
+    /// One `<tool_call>{"name": ..., "arguments": {...}}</tool_call>` block
+    /// calling one of `tools` (name, JSON Schema of the arguments).
+    pub fn tool_call<'t>(tools: impl IntoIterator<Item = (&'t str, &'t Value)>) -> Result<Self, AppError> {
+        let calls: Vec<Value> = tools
+            .into_iter()
+            .map(|(name, parameters)| {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": { "name": { "const": name }, "arguments": parameters },
+                    "required": ["name", "arguments"],
+                })
+            })
+            .collect();
+        let schema = serde_json::json!({ "anyOf": calls });
+        let mut c = SchemaCompiler::new(&schema);
+        let call = c
+            .compile(&schema, "call", 0)
+            .map_err(|e| AppError::InvalidRequest(format!("Unsupported tool parameters: {}", e)))?;
+        Self::parse(&c.finish(&format!(r#""<tool_call>" ws {} "</tool_call>""#, call)))
+    }
+
+    /// A fresh sampler stage; grammar state is per completion.
+    pub(super) fn sampler_stage(&self) -> SamplerStage {
+        // `None`: start constraining at the end of the prompt.
+        SamplerStage::from_grammar(self.compiled.clone(), None)
+    }
+}
+
+// ─── JSON Schema → GBNF ──────────────────────────────────────────────────────
+
+struct SchemaCompiler<'a> {
+    /// Whole schema, for resolving `$ref`s.
+    root: &'a Value,
+    /// Rules in definition order.
+    rules: Vec<(String, String)>,
+    names: HashSet<String>,
+    /// Identical bodies share one rule.
+    by_body: HashMap<String, String>,
+    /// `$ref` target → rule name, assigned before compiling so recursion terminates.
+    refs: HashMap<String, String>,
+    /// `$ref`s entered since the last object or array. Reaching one of these
+    /// again would be left recursion, which llama.cpp cannot handle.
+    unguarded: Vec<String>,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            rules: Vec::new(),
+            names: HashSet::new(),
+            by_body: HashMap::new(),
+            refs: HashMap::new(),
+            unguarded: Vec::new(),
+        }
+    }
+
+    /// Emit the grammar with `root` as its start rule.
+    fn finish(mut self, root: &str) -> String {
+        let mut out = format!("root ::= {}\n", root);
+        self.use_rule("ws");
+        for (name, body) in &self.rules {
+            out.push_str(&format!("{} ::= {}\n", name, body));
+        }
+        out
+    }
+
+    /// Make one of the built-in JSON rules (and those it depends on) available.
+    fn use_rule(&mut self, name: &str) -> String {
+        if self.names.contains(name) {
+            return name.to_string();
+        }
+        let deps: &[&str] = match name {
+            "value" => &["object", "array", "string", "number", "boolean", "null"],
+            "object" => &["string", "value"],
+            "array" => &["value"],
+            "string" => &["char"],
+            _ => &[],
+        };
+        self.names.insert(name.to_string());
+        if let Some((_, body)) = JSON_RULES.iter().find(|(n, _)| *n == name) {
+            self.rules.push((name.to_string(), body.to_string()));
+        }
+        for dep in deps {
+            self.use_rule(dep);
+        }
+        name.to_string()
+    }
+
+    /// Add a rule named after `hint`, or reuse an identical one.
+    fn add_rule(&mut self, hint: &str, body: String) -> String {
+        if let Some(name) = self.by_body.get(&body) {
+            return name.clone();
+        }
+        let name = self.fresh_name(hint);
+        self.by_body.insert(body.clone(), name.clone());
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn fresh_name(&mut self, hint: &str) -> String {
+        let base: String = hint
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+            .collect::<String>()
+            .trim_matches('-')
+            .to_string();
+        let base = if base.is_empty() || base == "root" { "item".to_string() } else { base };
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.names.contains(&name) || JSON_RULES.iter().any(|(r, _)| *r == name) {
+            n += 1;
+            name = format!("{}-{}", base, n);
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    /// Compile `schema` into a rule and return its name.
+    fn compile(&mut self, schema: &Value, hint: &str, depth: usize) -> Result<String, String> {
+        if depth > MAX_SCHEMA_DEPTH {
+            return Err(format!("nested deeper than {} levels", MAX_SCHEMA_DEPTH));
+        }
+        let obj = match schema {
+            Value::Bool(true) => return Ok(self.use_rule("value")),
+            Value::Bool(false) => return Err("`false` schema matches nothing".into()),
+            Value::Object(obj) => obj,
+            _ => return Err("schema must be an object".into()),
+        };
+
+        if let Some(target) = obj.get("$ref").and_then(Value::as_str) {
+            return self.compile_ref(target, depth);
+        }
+        if let Some(value) = obj.get("const") {
+            return Ok(self.add_rule(hint, literal(value)));
+        }
+        if let Some(values) = obj.get("enum") {
+            let values = values.as_array().ok_or("`enum` must be an array")?;
+            if values.is_empty() {
+                return Err("`enum` cannot be empty".into());
+            }
+            let body = values.iter().map(literal).collect::<Vec<_>>().join(" | ");
+            return Ok(self.add_rule(hint, body));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(options) = obj.get(key) {
+                let options = options.as_array().ok_or(format!("`{}` must be an array", key))?;
+                let mut alts = Vec::with_capacity(options.len());
+                for (i, option) in options.iter().enumerate() {
+                    alts.push(self.compile(option, &format!("{}-{}", hint, i), depth + 1)?);
+                }
+                if alts.is_empty() {
+                    return Err(format!("`{}` cannot be empty", key));
+                }
+                return Ok(self.add_rule(hint, alts.join(" | ")));
+            }
+        }
+        if let Some(all) = obj.get("allOf") {
+            return match all.as_array().map(Vec::as_slice) {
+                Some([only]) => self.compile(only, hint, depth + 1),
+                _ => Err("`allOf` with more than one schema is not supported".into()),
+            };
+        }
+
+        match obj.get("type") {
+            Some(Value::String(t)) => self.compile_type(t, obj, hint, depth),
+            Some(Value::Array(types)) => {
+                let mut alts = Vec::with_capacity(types.len());
+                for t in types {
+                    let t = t.as_str().ok_or("`type` entries must be strings")?;
+                    alts.push(self.compile_type(t, obj, &format!("{}-{}", hint, t), depth)?);
+                }
+                Ok(self.add_rule(hint, alts.join(" | ")))
+            }
+            Some(_) => Err("`type` must be a string or an array".into()),
+            None if obj.contains_key("properties") => self.compile_type("object", obj, hint, depth),
+            None if obj.contains_key("items") => self.compile_type("array", obj, hint, depth),
+            None => Ok(self.use_rule("value")),
+        }
+    }
+
+    fn compile_ref(&mut self, target: &str, depth: usize) -> Result<String, String> {
+        if self.unguarded.iter().any(|t| t == target) {
+            return Err(format!("$ref {:?} refers to itself outside any object or array", target));
+        }
+        if let Some(name) = self.refs.get(target) {
+            return Ok(name.clone());
+        }
+        let pointer = target
+            .strip_prefix('#')
+            .ok_or_else(|| format!("only local $refs are supported, got {:?}", target))?;
+        let root = self.root;
+        let resolved = root
+            .pointer(pointer)
+            .ok_or_else(|| format!("$ref {:?} does not resolve", target))?;
+
+        let hint = pointer.rsplit('/').next().unwrap_or("ref");
+        let name = self.fresh_name(hint);
+        self.refs.insert(target.to_string(), name.clone());
+        // Compile into a helper rule and alias it, so recursive references can
+        // point at `name` before its body is known.
+        self.unguarded.push(target.to_string());
+        let body = self.compile(resolved, &format!("{}-def", hint), depth + 1);
+        self.unguarded.pop();
+        self.rules.push((name.clone(), body?));
+        Ok(name)
+    }
+
+    /// Run `f` inside an object or array, whose opening bracket ends any `$ref` cycle.
+    fn guarded<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = std::mem::take(&mut self.unguarded);
+        let result = f(self);
+        self.unguarded = outer;
+        result
+    }
+
+    fn compile_type(
+        &mut self,
+        ty: &str,
+        obj: &serde_json::Map<String, Value>,
+        hint: &str,
+        depth: usize,
+    ) -> Result<String, String> {
+        match ty {
+            "string" => {
+                let min = obj.get("minLength").and_then(Value::as_u64).unwrap_or(0);
+                let max = obj.get("maxLength").and_then(Value::as_u64);
+                if min == 0 && max.is_none() {
+                    return Ok(self.use_rule("string"));
+                }
+                let chars = repeat(&self.use_rule("char"), min, max)?;
+                Ok(self.add_rule(hint, format!(r#""\"" {} "\"" ws"#, chars)))
+            }
+            "integer" | "number" | "boolean" | "null" => Ok(self.use_rule(ty)),
+            "array" => {
+                let item = match obj.get("items") {
+                    Some(items) => self.guarded(|c| c.compile(items, &format!("{}-item", hint), depth + 1))?,
+                    None => self.use_rule("value"),
+                };
+                let min = obj.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+                let max = obj.get("maxItems").and_then(Value::as_u64);
+                let list = match (min, max) {
+                    (_, Some(0)) => String::new(),
+                    (0, None) => format!(r#"( {item} ( "," ws {item} )* )?"#),
+                    _ => {
+                        let tail = repeat(&format!(r#""," ws {}"#, item), min.saturating_sub(1), max.map(|m| m - 1))?;
+                        let list = format!("{} {}", item, tail);
+                        if min == 0 { format!("( {} )?", list) } else { list }
+                    }
+                };
+                self.use_rule("ws");
+                Ok(self.add_rule(hint, format!(r#""[" ws {} "]" ws"#, list)))
+            }
+            "object" => self.guarded(|c| c.compile_object(obj, hint, depth)),
+            other => Err(format!("unknown type {:?}", other)),
+        }
+    }
+
+    fn compile_object(
+        &mut self,
+        obj: &serde_json::Map<String, Value>,
+        hint: &str,
+        depth: usize,
+    ) -> Result<String, String> {
+        self.use_rule("ws");
+        let properties = match obj.get("properties") {
+            Some(Value::Object(p)) if !p.is_empty() => p,
+            Some(Value::Object(_)) | None => {
+                // No declared properties: a map, typed by `additionalProperties` if given.
+                return match obj.get("additionalProperties") {
+                    Some(Value::Object(_)) => {
+                        let value = self.compile(&obj["additionalProperties"], &format!("{}-value", hint), depth + 1)?;
+                        let key = self.use_rule("string");
+                        let kv = format!(r#"{} ":" ws {}"#, key, value);
+                        Ok(self.add_rule(hint, format!(r#""{{" ws ( {kv} ( "," ws {kv} )* )? "}}" ws"#)))
+                    }
+                    Some(Value::Bool(false)) => Ok(self.add_rule(hint, r#""{" ws "}" ws"#.to_string())),
+                    _ => Ok(self.use_rule("object")),
+                };
+            }
+            Some(_) => return Err("`properties` must be an object".into()),
+        };
+        let required: Vec<&str> = obj
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let kv = |c: &mut Self, key: &str, schema: &Value| -> Result<String, String> {
+            let value = c.compile(schema, &format!("{}-{}", hint, key), depth + 1)?;
+            Ok(format!(r#"{} ":" ws {}"#, literal(&Value::String(key.to_string())), value))
+        };
+        let mut fixed = Vec::new();
+        for key in &required {
+            let schema = properties
+                .get(*key)
+                .ok_or_else(|| format!("required property {:?} is not in `properties`", key))?;
+            fixed.push(kv(self, key, schema)?);
+        }
+        let mut optional = Vec::new();
+        for (key, schema) in properties.iter().filter(|(k, _)| !required.contains(&k.as_str())) {
+            optional.push(kv(self, key, schema)?);
+        }
+
+        // Required properties come first, in the order `required` lists them, then
+        // any subset of the optional ones in key order. Extra keys are never produced.
+        let body = if fixed.is_empty() {
+            // Whichever optional property appears first carries no leading comma.
+            let alts: Vec<String> = (0..optional.len())
+                .map(|i| {
+                    let rest: String = optional[i + 1..]
+                        .iter()
+                        .map(|kv| format!(r#" ( "," ws {} )?"#, kv))
+                        .collect();
+                    format!("{}{}", optional[i], rest)
+                })
+                .collect();
+            format!(r#"( {} )?"#, alts.join(" | "))
+        } else {
+            let mut body = fixed.join(r#" "," ws "#);
+            for kv in &optional {
+                body.push_str(&format!(r#" ( "," ws {} )?"#, kv));
+            }
+            body
+        };
+        Ok(self.add_rule(hint, format!(r#""{{" ws {} "}}" ws"#, body)))
+    }
+}
+
+/// `item` repeated between `min` and `max` times (unbounded when `max` is `None`).
+/// GBNF here has no `{m,n}`, so bounds are spelled out.
+fn repeat(item: &str, min: u64, max: Option<u64>) -> Result<String, String> {
+    if max.is_some_and(|m| m < min) {
+        return Err("maximum length is below the minimum".into());
+    }
+    if min > MAX_EXPANDED_REPEAT {
+        return Err(format!("minimum lengths above {} are not supported", MAX_EXPANDED_REPEAT));
+    }
+    // A large upper bound is treated as unbounded.
+    let max = max.filter(|m| *m <= MAX_EXPANDED_REPEAT);
+    let mut parts: Vec<String> = (0..min).map(|_| format!("( {} )", item)).collect();
+    match max {
+        None => parts.push(format!("( {} )*", item)),
+        Some(max) => {
+            let mut optional = String::new();
+            for _ in min..max {
+                optional = if optional.is_empty() {
+                    format!("( {} )?", item)
+                } else {
+                    format!("( {} {} )?", item, optional)
+                };
+            }
+            if !optional.is_empty() {
+                parts.push(optional);
+            }
+        }
+    }
+    Ok(parts.join(" "))
+}
+
+/// A GBNF literal matching exactly the JSON encoding of `value`.
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut out = String::with_capacity(json.len() + 2);
+    out.push('"');
+    for c in json.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push_str("\" ws");
+    out
+}
+
+// ─── GBNF reference check ────────────────────────────────────────────────────
+
+/// Check that `root` and every referenced rule is defined.
+fn check_rule_refs(gbnf: &str) -> Result<(), String> {
+    let mut defined = HashSet::new();
+    let mut referenced = Vec::new();
+    let mut chars = gbnf.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '#' => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '"' | '[' => {
+                let close = if c == '"' { '"' } else { ']' };
+                let mut closed = false;
+                while let Some((_, c)) = chars.next() {
+                    if c == '\\' {
+                        chars.next();
+                    } else if c == close {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(format!("unterminated {}", if close == '"' { "literal" } else { "character class" }));
+                }
+            }
+            c if c.is_alphanumeric() || c == '-' || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '-' || c == '_') {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let name = &gbnf[start..end];
+                if gbnf[end..].trim_start().starts_with("::=") {
+                    defined.insert(name);
+                } else {
+                    referenced.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !defined.contains("root") {
+        return Err("no `root` rule".into());
+    }
+    match referenced.iter().find(|name| !defined.contains(*name)) {
+        Some(name) => Err(format!("undefined rule `{}`", name)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Compile `schema` the way `Grammar::from_json_schema` does, without llama.cpp.
+    fn gbnf(schema: &Value) -> String {
+        let mut c = SchemaCompiler::new(schema);
+        let root = c.compile(schema, "root", 0).unwrap();
+        let gbnf = c.finish(&root);
+        check_rule_refs(&gbnf).unwrap();
+        gbnf
+    }
+
+    fn rule<'g>(gbnf: &'g str, name: &str) -> &'g str {
+        let prefix = format!("{} ::= ", name);
+        gbnf.lines().find_map(|l| l.strip_prefix(prefix.as_str())).unwrap()
+    }
+
+    #[test]
+    fn required_properties_come_first_and_optional_ones_may_be_left_out() {
+        let g = gbnf(&json!({
+            "type": "object",
+            "properties": {
+                "age": { "type": "integer" },
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["name"],
+        }));
+        assert_eq!(rule(&g, "root"), "item");
+        assert_eq!(
+            rule(&g, "item"),
+            r#""{" ws "\"name\"" ws ":" ws string ( "," ws "\"age\"" ws ":" ws integer )? ( "," ws "\"tags\"" ws ":" ws root-tags )? "}" ws"#
+        );
+        assert_eq!(rule(&g, "root-tags"), r#""[" ws ( string ( "," ws string )* )? "]" ws"#);
+    }
+
+    #[test]
+    fn required_properties_keep_the_order_of_required() {
+        let g = gbnf(&json!({
+            "properties": { "a": { "type": "boolean" }, "b": { "type": "null" } },
+            "required": ["b", "a"],
+        }));
+        assert_eq!(rule(&g, "item"), r#""{" ws "\"b\"" ws ":" ws null "," ws "\"a\"" ws ":" ws boolean "}" ws"#);
+    }
+
+    #[test]
+    fn all_optional_properties_need_no_leading_comma() {
+        let g = gbnf(&json!({
+            "type": "object",
+            "properties": { "a": { "type": "integer" }, "b": { "const": "x" } },
+        }));
+        assert_eq!(rule(&g, "root-b"), r#""\"x\"" ws"#);
+        assert_eq!(
+            rule(&g, "item"),
+            r#""{" ws ( "\"a\"" ws ":" ws integer ( "," ws "\"b\"" ws ":" ws root-b )? | "\"b\"" ws ":" ws root-b )? "}" ws"#
+        );
+    }
+
+    #[test]
+    fn required_property_must_be_declared() {
+        let schema = json!({ "type": "object", "properties": { "a": {} }, "required": ["b"] });
+        let err = SchemaCompiler::new(&schema).compile(&schema, "root", 0).unwrap_err();
+        assert!(err.contains(r#""b""#), "{}", err);
+    }
+
+    #[test]
+    fn undefined_rules_are_rejected() {
+        assert!(check_rule_refs("root ::= \"a\" missing\n").is_err());
+        assert!(check_rule_refs("item ::= \"a\"\n").is_err());
+        assert!(check_rule_refs("root ::= \"b c\" [d]\n").is_ok());
+    }
+}
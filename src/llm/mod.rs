@@ -1,109 +1,325 @@
-use std::sync::Arc;
+mod grammar;
+mod output;
+mod sampling;
+mod scheduler;
+mod session_cache;
+mod template;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
-use tokio::time::timeout;
-use tracing::{error, info, instrument, warn};
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, error, info, instrument, warn};
 
+use crate::config::ModelConfig;
 use crate::errors::AppError;
+use crate::metrics;
+use self::output::{StopFilter, Utf8Decoder};
+use self::scheduler::Scheduler;
+use self::session_cache::{prime_session, SessionCache};
+use self::template::configured_template;
+pub use self::grammar::Grammar;
+pub use self::output::{FinishReason, TokenUsage};
+pub use self::sampling::{SamplingOverrides, SamplingParams};
+pub use self::scheduler::{Priority, QueueOptions, QueuePosition};
+pub use self::template::ChatTemplate;
 
-const QUEUE_CAPACITY: usize = 32;
-const DEFAULT_INFERENCE_TIMEOUT_SECS: u64 = 300;
-const N_CTX: u32 = 2048;
+/// Threads to use when the core count cannot be read.
 const DEFAULT_N_THREADS: u32 = 4;
-const MAX_GENERATION_TOKENS: u32 = 512;
 
-#[allow(dead_code)]
+/// Per-request generation settings passed from the API layer to the worker.
+#[derive(Debug, Clone)]
+pub struct InferParams {
+    pub max_tokens: u32,
+    pub sampling: SamplingParams,
+    /// Conversation key; requests that share it reuse the same KV cache.
+    pub session_id: Option<String>,
+    /// Client stop sequences; the template's end-of-turn markers are always added.
+    pub stop: Vec<String>,
+    /// Constrains the output, e.g. to JSON (`response_format`). Ignored in mock mode.
+    pub grammar: Option<Grammar>,
+    /// Priority class, fairness key and how long to wait for the worker.
+    pub queue: QueueOptions,
+}
+
 struct InferRequest {
     prompt: String,
-    max_tokens: u32,
-    temperature: f32,
-    reply: oneshot::Sender<Result<String, AppError>>,
+    params: InferParams,
+    events: mpsc::UnboundedSender<InferEvent>,
+    /// Set when the requester goes away; the worker stops at the next token.
+    cancelled: Arc<AtomicBool>,
+    /// Set by the scheduler when the worker takes the request.
+    started: Arc<AtomicBool>,
+}
+
+impl InferRequest {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Events emitted by the worker while serving one request, in order.
+/// Every request ends with exactly one `Done` or `Failed`.
+#[derive(Debug)]
+pub enum InferEvent {
+    /// The request's place in the queue changed; only sent before it starts.
+    Queued(QueuePosition),
+    /// A decoded piece of generated text.
+    Token(String),
+    Done { finish_reason: FinishReason, usage: TokenUsage },
+    Failed(AppError),
+}
+
+/// A finished, non-streamed answer.
+#[derive(Debug)]
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub usage: TokenUsage,
+    /// Where the request stood when it was queued.
+    pub queued: QueuePosition,
+}
+
+/// Receiving end of a queued inference request.
+/// Dropping it (e.g. because the HTTP client disconnected) cancels generation.
+pub struct InferStream {
+    events: mpsc::UnboundedReceiver<InferEvent>,
+    cancelled: Arc<AtomicBool>,
+    started: Arc<AtomicBool>,
+    /// The request fails if the worker hasn't started it by then (`max_wait`).
+    start_deadline: Option<Instant>,
+    deadline: Instant,
+    timeout_secs: u64,
+    queued: QueuePosition,
+}
+
+impl InferStream {
+    /// Wait for the next event, enforcing the queue wait and the overall inference timeout.
+    pub async fn next_event(&mut self) -> InferEvent {
+        loop {
+            let waiting = self.start_deadline.filter(|d| *d < self.deadline && !self.started.load(Ordering::Relaxed));
+            return match timeout_at(waiting.unwrap_or(self.deadline), self.events.recv()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => InferEvent::Failed(AppError::LlmError("LLM worker stopped".into())),
+                Err(_) if waiting.is_some() => {
+                    if self.started.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    // The scheduler skips cancelled requests.
+                    self.cancelled.store(true, Ordering::Relaxed);
+                    metrics::global().queue_rejected();
+                    InferEvent::Failed(AppError::QueueFull)
+                }
+                Err(_) => {
+                    metrics::global().inference_timeout();
+                    InferEvent::Failed(AppError::Timeout(self.timeout_secs))
+                }
+            };
+        }
+    }
+
+    /// Where the request stood when it was queued.
+    pub fn queued(&self) -> QueuePosition {
+        self.queued
+    }
+}
+
+impl Drop for InferStream {
+    fn drop(&mut self) {
+        // Harmless once the request has finished; otherwise frees the worker early.
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
 }
 
 #[derive(Clone)]
 pub struct LlmActor {
-    sender: mpsc::Sender<InferRequest>,
+    scheduler: Arc<Scheduler>,
     model_name: Arc<String>,
-    ready: Arc<std::sync::atomic::AtomicBool>,
+    ready: Arc<AtomicBool>,
+    /// Shared handle to the loaded model, used only for tokenization outside the worker.
+    /// Stays empty in mock mode or if loading failed.
+    model: Arc<OnceLock<llama_cpp::LlamaModel>>,
+    /// Prompt format, chosen by the worker once the model metadata is known.
+    template: Arc<OnceLock<ChatTemplate>>,
+    sampling_defaults: Arc<SamplingParams>,
+    config: Arc<ModelConfig>,
 }
 
 impl LlmActor {
-    pub fn spawn(model_path: String) -> Result<Self, AppError> {
-        let (tx, rx) = mpsc::channel::<InferRequest>(QUEUE_CAPACITY);
+    /// Start the worker thread with `queue_capacity` request slots in front of it.
+    /// `sampling` holds the configured sampler defaults.
+    pub fn spawn(config: ModelConfig, sampling: &SamplingOverrides, queue_capacity: usize) -> Result<Self, AppError> {
+        let sampling_defaults = Arc::new(sampling::load_defaults(&config.path, sampling)?);
+        let scheduler = Arc::new(Scheduler::new(queue_capacity));
+        let worker_scheduler = scheduler.clone();
         let model_name = Arc::new(
-            std::path::Path::new(&model_path)
+            std::path::Path::new(&config.path)
                 .file_name()
                 .and_then(|n| n.to_str())
                 .unwrap_or("unknown-model")
                 .to_string(),
         );
-        let ready = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let ready = Arc::new(AtomicBool::new(false));
         let ready_clone = ready.clone();
+        let model = Arc::new(OnceLock::new());
+        let model_clone = model.clone();
+        let template = Arc::new(OnceLock::new());
+        let template_clone = template.clone();
+        let config = Arc::new(config);
+        let worker_config = config.clone();
 
         std::thread::spawn(move || {
-            worker_loop(model_path, rx, ready_clone);
+            worker_loop(worker_config, worker_scheduler, ready_clone, model_clone, template_clone);
         });
 
         Ok(Self {
-            sender: tx,
+            scheduler,
             model_name,
             ready,
+            model,
+            template,
+            sampling_defaults,
+            config,
         })
     }
 
+    /// Run a request to completion and return the whole answer.
     #[instrument(skip(self, prompt))]
     pub async fn infer(
         &self,
         prompt: String,
-        max_tokens: u32,
-        temperature: f32,
-    ) -> Result<String, AppError> {
-        let (reply_tx, reply_rx) = oneshot::channel();
-        self.sender
-            .try_send(InferRequest {
+        params: InferParams,
+    ) -> Result<Completion, AppError> {
+        let mut stream = self.infer_stream(prompt, params).await?;
+        let mut output = String::new();
+        loop {
+            match stream.next_event().await {
+                InferEvent::Queued(_) => {}
+                InferEvent::Token(piece) => output.push_str(&piece),
+                InferEvent::Done { finish_reason, usage } => {
+                    return Ok(Completion {
+                        text: output.trim_end().to_string(),
+                        finish_reason,
+                        usage,
+                        queued: stream.queued(),
+                    })
+                }
+                InferEvent::Failed(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Queue a request and return a stream of its tokens as they are generated.
+    /// Fails with `QueueFull` if no slot frees up within `params.queue.max_wait`.
+    #[instrument(skip(self, prompt))]
+    pub async fn infer_stream(&self, prompt: String, params: InferParams) -> Result<InferStream, AppError> {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let started = Arc::new(AtomicBool::new(false));
+        let now = Instant::now();
+        let start_deadline = params.queue.max_wait.map(|wait| now + wait);
+        let queued = self
+            .scheduler
+            .push(InferRequest {
                 prompt,
-                max_tokens,
-                temperature,
-                reply: reply_tx,
+                params,
+                events: events_tx,
+                cancelled: cancelled.clone(),
+                started: started.clone(),
             })
-            .map_err(|_| AppError::QueueFull)?;
+            .await?;
 
-        let timeout_secs = inference_timeout_secs();
-        timeout(Duration::from_secs(timeout_secs), reply_rx)
-            .await
-            .map_err(|_| AppError::Timeout(timeout_secs))?
-            .map_err(|_| AppError::Cancelled)?
+        let timeout_secs = self.config.inference_timeout_secs;
+        Ok(InferStream {
+            events: events_rx,
+            cancelled,
+            started,
+            start_deadline,
+            deadline: now + Duration::from_secs(timeout_secs),
+            timeout_secs,
+            queued,
+        })
     }
 
     pub fn is_ready(&self) -> bool {
-        self.ready.load(std::sync::atomic::Ordering::Relaxed)
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Requests waiting for the worker, not counting the one it is running.
+    pub fn queue_depth(&self) -> usize {
+        self.scheduler.depth()
     }
 
     pub fn model_name(&self) -> String {
         (*self.model_name).clone()
     }
+
+    /// Number of tokens `text` occupies in the context, as the worker will see it.
+    /// Falls back to the mock tokenizer when no model is loaded.
+    pub fn count_tokens(&self, text: &str) -> Result<usize, AppError> {
+        match self.model.get() {
+            Some(model) => model
+                .tokenize_bytes(text, false, true)
+                .map(|tokens| tokens.len())
+                .map_err(|e| AppError::LlmError(format!("Failed to tokenize: {}", e))),
+            None => Ok(mock_token_count(text)),
+        }
+    }
+
+    /// Prompt format of the loaded model (the configured default until it has loaded).
+    pub fn template(&self) -> ChatTemplate {
+        self.template
+            .get()
+            .copied()
+            .unwrap_or_else(|| configured_template(self.config.chat_template, None, None))
+    }
+
+    /// Render chat turns as a prompt in the loaded model's format.
+    pub fn build_prompt<'a>(&self, messages: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+        self.template().render(messages)
+    }
+
+    /// Server-side sampler defaults that request fields override.
+    pub fn sampling_defaults(&self) -> &SamplingParams {
+        &self.sampling_defaults
+    }
+
+    /// Context window size of every session, in tokens.
+    pub fn context_size(&self) -> u32 {
+        self.config.context_size
+    }
+
+    /// Upper bound the worker applies to any request's `max_tokens`.
+    pub fn max_generation_tokens(&self) -> u32 {
+        self.config.max_generation_tokens
+    }
 }
 
 fn worker_loop(
-    model_path: String,
-    mut rx: mpsc::Receiver<InferRequest>,
-    ready: Arc<std::sync::atomic::AtomicBool>,
+    config: Arc<ModelConfig>,
+    scheduler: Arc<Scheduler>,
+    ready: Arc<AtomicBool>,
+    model_slot: Arc<OnceLock<llama_cpp::LlamaModel>>,
+    template_slot: Arc<OnceLock<ChatTemplate>>,
 ) {
+    let model_path = &config.path;
     info!(model_path = %model_path, "LLM worker starting");
 
-    if !std::path::Path::new(&model_path).exists() {
+    if !std::path::Path::new(model_path).exists() {
         warn!(
             "Model not found at '{}' — running in MOCK mode.",
             model_path
         );
-        ready.store(true, std::sync::atomic::Ordering::Relaxed);
+        let template = configured_template(config.chat_template, None, None);
+        let _ = template_slot.set(template);
+        ready.store(true, Ordering::Relaxed);
         info!("LLM worker ready (mock mode)");
-        while let Some(req) = rx.blocking_recv() {
-            if req.reply.send(mock_infer(&req.prompt)).is_err() {
-                warn!("Client disconnected before response was delivered");
-            }
+        loop {
+            let req = scheduler.next();
+            let result = mock_infer(&req, template);
+            finish(&req.events, result);
         }
-        return;
     }
 
     // --- Real llama.cpp inference ---
@@ -111,112 +327,228 @@ fn worker_loop(
 
     info!("Loading model from disk, please wait...");
 
-    let model = match LlamaModel::load_from_file(&model_path, LlamaParams::default()) {
+    let model = match LlamaModel::load_from_file(model_path, LlamaParams::default()) {
         Ok(m) => {
             info!("Model loaded successfully");
             m
         }
         Err(e) => {
             error!(error = %e, "Failed to load model");
-            ready.store(true, std::sync::atomic::Ordering::Relaxed);
-            while let Some(req) = rx.blocking_recv() {
-                let _ = req
-                    .reply
-                    .send(Err(AppError::LlmError(format!("Model load failed: {}", e))));
+            ready.store(true, Ordering::Relaxed);
+            loop {
+                let req = scheduler.next();
+                let _ = req.events.send(InferEvent::Failed(AppError::LlmError(format!(
+                    "Model load failed: {}",
+                    e
+                ))));
             }
-            return;
         }
     };
 
-    ready.store(true, std::sync::atomic::Ordering::Relaxed);
+    let template = configured_template(
+        config.chat_template,
+        model.metadata("tokenizer.chat_template").as_deref(),
+        model.metadata("general.architecture").as_deref(),
+    );
+    info!(template = template.name(), "Chat template selected");
+    let _ = template_slot.set(template);
+    let _ = model_slot.set(model.clone());
+    ready.store(true, Ordering::Relaxed);
     info!("LLM worker ready (real inference mode)");
 
-    while let Some(req) = rx.blocking_recv() {
-        let result = real_infer(&model, &req.prompt, req.max_tokens, req.temperature);
-        if req.reply.send(result).is_err() {
-            warn!("Client disconnected before response was delivered");
-        }
+    let mut sessions = SessionCache::new(config.session_cache_size);
+
+    loop {
+        let req = scheduler.next();
+        let result = real_infer(&model, template, &config, &mut sessions, &req);
+        finish(&req.events, result);
     }
+}
 
-    info!("LLM worker shutting down");
+/// Send the terminal event for a request.
+fn finish(
+    events: &mpsc::UnboundedSender<InferEvent>,
+    result: Result<(FinishReason, TokenUsage), AppError>,
+) {
+    let event = match result {
+        Ok((finish_reason, usage)) => InferEvent::Done { finish_reason, usage },
+        Err(AppError::Cancelled) => {
+            warn!(error = %AppError::Cancelled, "Client disconnected — generation aborted");
+            let _ = events.send(InferEvent::Failed(AppError::Cancelled));
+            return;
+        }
+        Err(e) => InferEvent::Failed(e),
+    };
+    if events.send(event).is_err() {
+        warn!("Client disconnected before response was delivered");
+    }
 }
 
 fn real_infer(
     model: &llama_cpp::LlamaModel,
-    prompt: &str,
-    max_tokens: u32,
-    temperature: f32,
-) -> Result<String, AppError> {
-    use llama_cpp::standard_sampler::{SamplerStage, StandardSampler};
+    template: ChatTemplate,
+    config: &ModelConfig,
+    sessions: &mut SessionCache,
+    req: &InferRequest,
+) -> Result<(FinishReason, TokenUsage), AppError> {
     use llama_cpp::SessionParams;
 
-    let n_threads = inference_threads();
-    let mut ctx = model
-        .create_session(SessionParams {
-            n_ctx: N_CTX,
-            n_threads,
-            n_threads_batch: n_threads,
-            ..Default::default()
-        })
-        .map_err(|e| AppError::LlmError(format!("Failed to create session: {}", e)))?;
-
-    ctx.advance_context(prompt)
-        .map_err(|e| AppError::LlmError(format!("Failed to advance context: {}", e)))?;
-
-    let requested_tokens = max_tokens.clamp(1, MAX_GENERATION_TOKENS) as usize;
-    let normalized_temperature = temperature.clamp(0.0, 2.0);
-
-    let sampler = StandardSampler::new_softmax(
-        vec![
-            SamplerStage::RepetitionPenalty {
-                repetition_penalty: 1.1,
-                frequency_penalty: 0.0,
-                presence_penalty: 0.0,
-                last_n: 64,
-            },
-            SamplerStage::TopK(40),
-            SamplerStage::TopP(0.95),
-            SamplerStage::MinP(0.05),
-            SamplerStage::Temperature(normalized_temperature),
-        ],
-        1,
+    // The client may have given up while the request sat in the queue.
+    if req.is_cancelled() {
+        return Err(AppError::Cancelled);
+    }
+
+    let session_id = req.params.session_id.as_deref();
+    let seed = req.params.sampling.seed;
+    let cached = session_id.and_then(|id| sessions.take(id));
+    // The RNG is seeded when a session is created, so a seeded request needs a fresh one.
+    let mut ctx = match cached.filter(|_| seed.is_none()) {
+        Some(cached) => cached,
+        None => {
+            let n_threads = inference_threads(config.threads);
+            let mut params = SessionParams {
+                n_ctx: config.context_size,
+                n_threads,
+                n_threads_batch: n_threads,
+                ..Default::default()
+            };
+            if let Some(seed) = seed {
+                params.seed = seed;
+            }
+            model
+                .create_session(params)
+                .map_err(|e| AppError::LlmError(format!("Failed to create session: {}", e)))?
+        }
+    };
+
+    let prompt_tokens = model
+        .tokenize_bytes(&req.prompt, false, true)
+        .map_err(|e| AppError::LlmError(format!("Failed to tokenize prompt: {}", e)))?;
+    let prompt_started = Instant::now();
+    let reused = prime_session(&mut ctx, &prompt_tokens)?;
+    let prompt_eval = prompt_started.elapsed();
+    debug!(
+        reused_tokens = reused,
+        new_tokens = prompt_tokens.len() - reused,
+        "Prompt evaluated"
     );
 
-    let completions = ctx
+    let requested_tokens = req.params.max_tokens.clamp(1, config.max_generation_tokens) as usize;
+    let sampler = req.params.sampling.build_sampler(req.params.grammar.as_ref());
+
+    let generation_started = Instant::now();
+    let mut completion = ctx
         .start_completing_with(sampler, requested_tokens)
-        .map_err(|e| AppError::LlmError(format!("Failed to start completion: {}", e)))?
-        .into_strings();
+        .map_err(|e| AppError::LlmError(format!("Failed to start completion: {}", e)))?;
 
-    // Apply a second .take() guard in case a downstream iterator ignores token bounds.
-    let output: String = completions.take(requested_tokens).collect();
+    let end_tokens = [model.eos(), model.eot()];
+    let mut filter = StopFilter::new(stop_sequences(req, template));
+    let mut decoder = Utf8Decoder::default();
+    let mut generated = 0;
 
-    Ok(output.trim().to_string())
+    // Bound the loop ourselves in case the completion thread ignores `requested_tokens`.
+    let finish_reason = loop {
+        if generated >= requested_tokens {
+            break FinishReason::Length;
+        }
+        let Some(token) = completion.next() else {
+            break FinishReason::Stop;
+        };
+        // Dropping `completion` on return also stops llama.cpp's decode thread.
+        if req.is_cancelled() {
+            return Err(AppError::Cancelled);
+        }
+        generated += 1;
+        if end_tokens.contains(&token) {
+            break FinishReason::Stop;
+        }
+
+        let (text, stopped) = filter.push(&decoder.push(&model.token_to_byte_piece(token)));
+        emit(req, text)?;
+        if stopped {
+            break FinishReason::Stop;
+        }
+    };
+    drop(completion);
+
+    if !filter.stopped() {
+        let (text, _) = filter.push(&decoder.flush());
+        emit(req, text + &filter.flush())?;
+    }
+    let generation = generation_started.elapsed();
+
+    metrics::global().set_session_memory(ctx.memory_size());
+    // Only a session that completed cleanly is worth keeping for the next turn.
+    if let Some(id) = session_id {
+        sessions.put(id.to_string(), ctx);
+    }
+
+    let usage = TokenUsage {
+        prompt_tokens: prompt_tokens.len() as u32,
+        completion_tokens: generated as u32,
+    };
+    metrics::global().record_inference(prompt_eval, generation, usage.prompt_tokens, usage.completion_tokens);
+    Ok((finish_reason, usage))
 }
 
-fn inference_timeout_secs() -> u64 {
-    std::env::var("INFERENCE_TIMEOUT_SECS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .filter(|v| *v > 0)
-        .unwrap_or(DEFAULT_INFERENCE_TIMEOUT_SECS)
+/// Client stop sequences plus the template's end-of-turn markers.
+fn stop_sequences(req: &InferRequest, template: ChatTemplate) -> Vec<String> {
+    let mut stops = req.params.stop.clone();
+    stops.extend(template.stop_markers().iter().map(|m| m.to_string()));
+    stops
 }
 
-fn inference_threads() -> u32 {
-    let auto_threads = std::thread::available_parallelism()
+/// Forward generated text to the requester; a closed channel means it went away.
+fn emit(req: &InferRequest, text: String) -> Result<(), AppError> {
+    if text.is_empty() {
+        return Ok(());
+    }
+    req.events
+        .send(InferEvent::Token(text))
+        .map_err(|_| AppError::Cancelled)
+}
+
+/// `configured` threads, or one per core when it is 0.
+fn inference_threads(configured: u32) -> u32 {
+    if configured > 0 {
+        return configured;
+    }
+    std::thread::available_parallelism()
         .map(|n| n.get() as u32)
-        .unwrap_or(DEFAULT_N_THREADS);
+        .unwrap_or(DEFAULT_N_THREADS)
+}
 
-    std::env::var("LLM_THREADS")
-        .ok()
-        .and_then(|v| v.parse::<u32>().ok())
-        .filter(|v| *v > 0)
-        .unwrap_or(auto_threads)
+/// Deterministic tokenizer stand-in for mock mode: one token per word.
+fn mock_token_count(text: &str) -> usize {
+    text.split_whitespace().count()
 }
 
-fn mock_infer(prompt: &str) -> Result<String, AppError> {
-    let words = prompt.split_whitespace().count();
-    Ok(format!(
+fn mock_infer(
+    req: &InferRequest,
+    template: ChatTemplate,
+) -> Result<(FinishReason, TokenUsage), AppError> {
+    let words = mock_token_count(&req.prompt);
+    let answer = format!(
         "[MOCK] Prompt had {} words. Set MODEL_PATH to a valid .gguf file for real inference.",
         words
-    ))
+    );
+    // Emit word by word so streaming clients see more than one chunk.
+    let started = Instant::now();
+    let mut filter = StopFilter::new(stop_sequences(req, template));
+    let mut usage = TokenUsage { prompt_tokens: words as u32, completion_tokens: 0 };
+    for (i, word) in answer.split(' ').enumerate() {
+        if req.is_cancelled() {
+            return Err(AppError::Cancelled);
+        }
+        usage.completion_tokens += 1;
+        let piece = if i == 0 { word.to_string() } else { format!(" {}", word) };
+        let (text, stopped) = filter.push(&piece);
+        emit(req, text)?;
+        if stopped {
+            break;
+        }
+    }
+    emit(req, filter.flush())?;
+    metrics::global().record_inference(Duration::ZERO, started.elapsed(), usage.prompt_tokens, usage.completion_tokens);
+    Ok((FinishReason::Stop, usage))
 }
@@ -0,0 +1,236 @@
+/// Why generation ended, reported to clients as OpenAI's `finish_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// End-of-sequence token, end-of-turn marker or a client stop sequence.
+    Stop,
+    /// Cut off at `max_tokens`.
+    Length,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Length => "length",
+        }
+    }
+}
+
+/// Tokens consumed by one request, as counted by the model's tokenizer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    /// Tokens of the rendered prompt, including any reused from the KV cache.
+    pub prompt_tokens: u32,
+    /// Tokens sampled by the model, including the one that ended generation.
+    pub completion_tokens: u32,
+}
+
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Releases generated text while watching for stop sequences.
+///
+/// Text that might be the beginning of a stop sequence is held back until the
+/// next piece disambiguates it, so a stop sequence is never partially streamed.
+/// Leading whitespace of the answer is dropped, as templates leave a newline
+/// after the assistant tag.
+pub(super) struct StopFilter {
+    stops: Vec<String>,
+    held: String,
+    started: bool,
+    stopped: bool,
+}
+
+impl StopFilter {
+    pub(super) fn new(stops: Vec<String>) -> Self {
+        let stops = stops.into_iter().filter(|s| !s.is_empty()).collect();
+        Self { stops, held: String::new(), started: false, stopped: false }
+    }
+
+    /// Whether a stop sequence has been reached.
+    pub(super) fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Add a decoded piece. Returns the text that is safe to emit and whether
+    /// a stop sequence was reached (everything from it onwards is discarded).
+    pub(super) fn push(&mut self, piece: &str) -> (String, bool) {
+        if self.stopped {
+            return (String::new(), true);
+        }
+        self.held.push_str(piece);
+
+        if !self.started {
+            let trimmed = self.held.trim_start();
+            if trimmed.is_empty() {
+                self.held.clear();
+                return (String::new(), false);
+            }
+            self.held = trimmed.to_string();
+            self.started = true;
+        }
+
+        if let Some(at) = self.stops.iter().filter_map(|s| self.held.find(s.as_str())).min() {
+            let out = self.held[..at].to_string();
+            self.held.clear();
+            self.stopped = true;
+            return (out, true);
+        }
+
+        let split = self.held.len() - self.partial_stop_len();
+        let out: String = self.held.drain(..split).collect();
+        (out, false)
+    }
+
+    /// Release whatever is still held back once generation has ended.
+    pub(super) fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+
+    /// Length of the longest suffix of the held text that starts a stop sequence.
+    fn partial_stop_len(&self) -> usize {
+        self.stops
+            .iter()
+            .flat_map(|stop| stop.char_indices().skip(1).map(move |(i, _)| &stop[..i]))
+            .filter(|prefix| self.held.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Turns token byte pieces into text, holding back incomplete UTF-8 sequences
+/// (emoji and accented characters often span several tokens).
+#[derive(Default)]
+pub(super) struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub(super) fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut out = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(text) => {
+                    out.push_str(text);
+                    self.pending.clear();
+                    return out;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    out.push_str(&String::from_utf8_lossy(&self.pending[..valid]));
+                    match e.error_len() {
+                        // Incomplete sequence at the end: keep it for the next piece.
+                        None => {
+                            self.pending.drain(..valid);
+                            return out;
+                        }
+                        // Invalid bytes: replace them and decode what follows.
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + len);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn flush(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `pieces` in order, collecting emitted text until a stop is reached.
+    fn run(stops: &[&str], pieces: &[&str]) -> (String, bool) {
+        let mut filter = StopFilter::new(stops.iter().map(|s| s.to_string()).collect());
+        let mut out = String::new();
+        for piece in pieces {
+            let (text, stopped) = filter.push(piece);
+            out.push_str(&text);
+            if stopped {
+                return (out, true);
+            }
+        }
+        out.push_str(&filter.flush());
+        (out, false)
+    }
+
+    #[test]
+    fn stop_split_across_pieces_is_never_emitted() {
+        let mut filter = StopFilter::new(vec!["</answer>".into()]);
+        assert_eq!(filter.push("Paris</"), ("Paris".to_string(), false));
+        assert_eq!(filter.push("ans"), (String::new(), false));
+        assert_eq!(filter.push("wer> trailing"), (String::new(), true));
+        assert!(filter.stopped());
+        assert_eq!(filter.push("more"), (String::new(), true));
+    }
+
+    #[test]
+    fn held_prefix_is_released_when_it_turns_out_not_to_stop() {
+        let mut filter = StopFilter::new(vec!["\n\nUser:".into()]);
+        assert_eq!(filter.push("one\n"), ("one".to_string(), false));
+        assert_eq!(filter.push("\nUs"), (String::new(), false));
+        assert_eq!(filter.push("e it"), ("\n\nUse it".to_string(), false));
+    }
+
+    #[test]
+    fn stop_in_one_piece_and_earliest_of_several() {
+        assert_eq!(run(&["###"], &["a###b"]), ("a".to_string(), true));
+        assert_eq!(run(&["bc", "ab"], &["x", "a", "bc"]), ("x".to_string(), true));
+        assert_eq!(run(&["END"], &["E", "N", "D"]), (String::new(), true));
+    }
+
+    #[test]
+    fn unfinished_prefix_is_flushed_at_the_end() {
+        assert_eq!(run(&["STOP"], &["go ", "ST", "O"]), ("go STO".to_string(), false));
+    }
+
+    #[test]
+    fn leading_whitespace_is_dropped_and_empty_stops_ignored() {
+        assert_eq!(run(&[""], &["\n", "  ", " hi"]), ("hi".to_string(), false));
+        assert_eq!(run(&["x"], &["\n", " x", "y"]), (String::new(), true));
+    }
+
+    #[test]
+    fn multibyte_prefixes_split_on_char_boundaries() {
+        assert_eq!(run(&["éé"], &["caf", "é", "é!"]), ("caf".to_string(), true));
+        assert_eq!(run(&["éé"], &["café", " ok"]), ("café ok".to_string(), false));
+    }
+
+    #[test]
+    fn characters_split_across_pieces_are_held_back() {
+        let smile = "😀".as_bytes();
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(b"hi "), "hi ");
+        assert_eq!(decoder.push(&smile[..1]), "");
+        assert_eq!(decoder.push(&smile[1..3]), "");
+        assert_eq!(decoder.push(&[smile[3], b'!']), "😀!");
+        assert_eq!(decoder.flush(), "");
+    }
+
+    #[test]
+    fn invalid_bytes_become_replacement_characters() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(b"a\xffb"), "a\u{fffd}b");
+        // A character starting after the invalid byte is still completed.
+        assert_eq!(decoder.push(b"\xff\xc3"), "\u{fffd}");
+        assert_eq!(decoder.push(b"\xa9"), "é");
+    }
+
+    #[test]
+    fn flush_replaces_an_unfinished_character() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(&"é".as_bytes()[..1]), "");
+        assert_eq!(decoder.flush(), "\u{fffd}");
+        assert_eq!(decoder.flush(), "");
+    }
+}
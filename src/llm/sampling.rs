@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use llama_cpp::standard_sampler::{SamplerStage, StandardSampler};
+use serde::de::{self, IgnoredAny};
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::info;
+
+use super::grammar::Grammar;
+use crate::errors::AppError;
+
+/// Tokens considered by the repetition / presence / frequency penalties.
+const PENALTY_LAST_N: i32 = 64;
+/// Candidate count used by Mirostat v1 to estimate `s_hat`.
+const MIROSTAT_M: i32 = 100;
+
+/// Fully resolved sampler settings for one request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingParams {
+    pub temperature: f32,
+    pub top_p: f32,
+    /// 0 disables top-k.
+    pub top_k: i32,
+    pub min_p: f32,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+    pub repeat_penalty: f32,
+    /// RNG seed; `None` draws a random one.
+    pub seed: Option<u32>,
+    /// 0 = off, 1 = Mirostat, 2 = Mirostat 2.0.
+    pub mirostat: u8,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.7,
+            top_p: 0.95,
+            top_k: 40,
+            min_p: 0.05,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+            repeat_penalty: 1.1,
+            seed: None,
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+        }
+    }
+}
+
+/// Optional sampler fields, as accepted on the chat API, in the config file and
+/// in the per-model defaults file. Unset fields keep the value they override.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SamplingOverrides {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub min_p: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub seed: Option<u32>,
+    pub mirostat: Option<u8>,
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+}
+
+impl SamplingOverrides {
+    /// Deserialize, refusing keys that are not sampler fields, for the config
+    /// and defaults files. The type can't say `deny_unknown_fields` itself: it
+    /// is flattened into `ChatRequest`, where that attribute doesn't work.
+    pub fn deserialize_strict<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Strict {
+            #[serde(flatten)]
+            overrides: SamplingOverrides,
+            #[serde(flatten)]
+            unknown: BTreeMap<String, IgnoredAny>,
+        }
+        let strict = Strict::deserialize(deserializer)?;
+        match strict.unknown.keys().next() {
+            Some(key) => Err(de::Error::custom(format!("unknown sampling field `{}`", key))),
+            None => Ok(strict.overrides),
+        }
+    }
+}
+
+impl From<&SamplingParams> for SamplingOverrides {
+    fn from(p: &SamplingParams) -> Self {
+        Self {
+            temperature: Some(p.temperature),
+            top_p: Some(p.top_p),
+            top_k: Some(p.top_k),
+            min_p: Some(p.min_p),
+            presence_penalty: Some(p.presence_penalty),
+            frequency_penalty: Some(p.frequency_penalty),
+            repeat_penalty: Some(p.repeat_penalty),
+            seed: p.seed,
+            mirostat: Some(p.mirostat),
+            mirostat_tau: Some(p.mirostat_tau),
+            mirostat_eta: Some(p.mirostat_eta),
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Apply `overrides` on top of `self` and check the result.
+    pub fn with_overrides(&self, o: &SamplingOverrides) -> Result<Self, AppError> {
+        let merged = Self {
+            temperature: o.temperature.unwrap_or(self.temperature),
+            top_p: o.top_p.unwrap_or(self.top_p),
+            top_k: o.top_k.unwrap_or(self.top_k),
+            min_p: o.min_p.unwrap_or(self.min_p),
+            presence_penalty: o.presence_penalty.unwrap_or(self.presence_penalty),
+            frequency_penalty: o.frequency_penalty.unwrap_or(self.frequency_penalty),
+            repeat_penalty: o.repeat_penalty.unwrap_or(self.repeat_penalty),
+            seed: o.seed.or(self.seed),
+            mirostat: o.mirostat.unwrap_or(self.mirostat),
+            mirostat_tau: o.mirostat_tau.unwrap_or(self.mirostat_tau),
+            mirostat_eta: o.mirostat_eta.unwrap_or(self.mirostat_eta),
+        };
+        merged.validate()?;
+        Ok(merged)
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        fn check(ok: bool, msg: &str) -> Result<(), AppError> {
+            if ok { Ok(()) } else { Err(AppError::InvalidRequest(msg.into())) }
+        }
+        check((0.0..=2.0).contains(&self.temperature), "temperature must be between 0 and 2")?;
+        check(self.top_p > 0.0 && self.top_p <= 1.0, "top_p must be in (0, 1]")?;
+        check(self.top_k >= 0, "top_k must be >= 0 (0 disables it)")?;
+        check((0.0..=1.0).contains(&self.min_p), "min_p must be between 0 and 1")?;
+        check((-2.0..=2.0).contains(&self.presence_penalty), "presence_penalty must be between -2 and 2")?;
+        check((-2.0..=2.0).contains(&self.frequency_penalty), "frequency_penalty must be between -2 and 2")?;
+        check(self.repeat_penalty > 0.0 && self.repeat_penalty <= 2.0, "repeat_penalty must be in (0, 2]")?;
+        check(self.mirostat <= 2, "mirostat must be 0, 1 or 2")?;
+        check(self.mirostat_tau > 0.0, "mirostat_tau must be > 0")?;
+        check(self.mirostat_eta > 0.0 && self.mirostat_eta <= 1.0, "mirostat_eta must be in (0, 1]")
+    }
+
+    /// Map the settings onto llama.cpp sampler stages. A grammar, if any, runs
+    /// first so the other stages only ever see tokens it allows.
+    pub(super) fn build_sampler(&self, grammar: Option<&Grammar>) -> StandardSampler {
+        // Temperature 0 means "always the most likely token". The greedy sampler
+        // takes no stages, so with a grammar keep only the top allowed token instead.
+        if self.temperature == 0.0 && self.mirostat == 0 {
+            return match grammar {
+                Some(grammar) => {
+                    StandardSampler::new_softmax(vec![grammar.sampler_stage(), SamplerStage::TopK(1)], 1)
+                }
+                None => StandardSampler::new_greedy(),
+            };
+        }
+
+        let mut stages: Vec<SamplerStage> = grammar.map(Grammar::sampler_stage).into_iter().collect();
+        stages.push(SamplerStage::RepetitionPenalty {
+            repetition_penalty: self.repeat_penalty,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            last_n: PENALTY_LAST_N,
+        });
+
+        match self.mirostat {
+            // Mirostat replaces top-k/top-p/min-p truncation with its own target-surprise control.
+            1 => {
+                stages.push(SamplerStage::Temperature(self.temperature));
+                StandardSampler::new_mirostat(stages, 1, self.mirostat_tau, self.mirostat_eta, MIROSTAT_M)
+            }
+            2 => {
+                stages.push(SamplerStage::Temperature(self.temperature));
+                StandardSampler::new_mirostat_v2(stages, 1, self.mirostat_tau, self.mirostat_eta)
+            }
+            _ => {
+                if self.top_k > 0 {
+                    stages.push(SamplerStage::TopK(self.top_k));
+                }
+                stages.push(SamplerStage::TopP(self.top_p));
+                stages.push(SamplerStage::MinP(self.min_p));
+                stages.push(SamplerStage::Temperature(self.temperature));
+                StandardSampler::new_softmax(stages, 1)
+            }
+        }
+    }
+}
+
+/// Server-side defaults for a model: `<model>.sampling.json` next to the GGUF
+/// file (e.g. `mistral-7b.Q4_K_M.sampling.json`), on top of the `configured`
+/// defaults, on top of the built-in values.
+pub(super) fn load_defaults(model_path: &str, configured: &SamplingOverrides) -> Result<SamplingParams, AppError> {
+    let base = SamplingParams::default()
+        .with_overrides(configured)
+        .map_err(|e| AppError::ConfigError(format!("Invalid [sampling] defaults: {}", e)))?;
+    let path = Path::new(model_path).with_extension("sampling.json");
+    if !path.exists() {
+        return Ok(base);
+    }
+
+    let text = std::fs::read_to_string(&path)?;
+    let mut json = serde_json::Deserializer::from_str(&text);
+    let overrides = SamplingOverrides::deserialize_strict(&mut json)
+        .and_then(|overrides| json.end().map(|()| overrides))
+        .map_err(|e| AppError::ConfigError(format!("Invalid sampling defaults in {}: {}", path.display(), e)))?;
+    let params = base.with_overrides(&overrides).map_err(|e| {
+        AppError::ConfigError(format!("Invalid sampling defaults in {}: {}", path.display(), e))
+    })?;
+
+    info!(file = %path.display(), "Loaded per-model sampling defaults");
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict(json: &str) -> Result<SamplingOverrides, serde_json::Error> {
+        SamplingOverrides::deserialize_strict(&mut serde_json::Deserializer::from_str(json))
+    }
+
+    #[test]
+    fn strict_refuses_unknown_fields() {
+        let err = strict(r#"{ "temperature": 0.2, "temprature": 0.5 }"#).unwrap_err();
+        assert!(err.to_string().contains("`temprature`"), "{}", err);
+    }
+
+    #[test]
+    fn strict_reads_known_fields() {
+        let o = strict(r#"{ "temperature": 1, "top_k": 20, "seed": null }"#).unwrap();
+        assert_eq!((o.temperature, o.top_k, o.seed), (Some(1.0), Some(20), None));
+        assert!(strict("{}").is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tracing::debug;
+
+use super::{InferEvent, InferRequest};
+use crate::errors::AppError;
+use crate::metrics;
+
+/// Weight of the latest request in the running average of service time.
+const SERVICE_TIME_WEIGHT: f64 = 0.2;
+
+// ─── Options ─────────────────────────────────────────────────────────────────
+
+/// Scheduling class of a request. A class only runs when every class above
+/// it has nothing queued.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Someone is waiting for the answer.
+    #[default]
+    Interactive,
+    /// Scripts and bulk jobs.
+    Batch,
+    /// Work nobody is waiting for.
+    Background,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Interactive => "interactive",
+            Self::Batch => "batch",
+            Self::Background => "background",
+        }
+    }
+}
+
+/// How a request is queued for the worker.
+#[derive(Debug, Clone, Default)]
+pub struct QueueOptions {
+    pub priority: Priority,
+    /// Requests with the same key take turns with the other keys of their class.
+    pub fair_key: String,
+    /// How long the request may wait for the worker to start it, first for a
+    /// free queue slot and then in the queue. `None` fails at once on a full queue.
+    pub max_wait: Option<Duration>,
+}
+
+/// Where a queued request stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueuePosition {
+    /// Requests that will run before this one, including the one running now.
+    pub ahead: usize,
+    /// Expected seconds until it starts; unknown until a request has finished.
+    pub wait_secs: Option<u64>,
+}
+
+// ─── Scheduler ───────────────────────────────────────────────────────────────
+
+/// Queue in front of the worker thread: strict priority between classes,
+/// round-robin between fair keys within a class, FIFO within a key.
+pub(super) struct Scheduler {
+    capacity: usize,
+    queues: Mutex<Queues>,
+    /// Wakes the worker when a request is queued.
+    arrived: Condvar,
+    /// Wakes requests waiting for a free slot.
+    freed: Notify,
+}
+
+#[derive(Default)]
+struct Queues {
+    classes: [Class; Priority::COUNT],
+    len: usize,
+    next_id: u64,
+    /// When the worker started its current request.
+    running_since: Option<Instant>,
+    /// Running average of how long a request keeps the worker, in seconds.
+    service_secs: Option<f64>,
+}
+
+#[derive(Default)]
+struct Class {
+    /// Keys with queued requests, in the order they get their next turn.
+    turns: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<Entry>>,
+}
+
+struct Entry {
+    id: u64,
+    req: InferRequest,
+    /// Last position sent to the client, to report only changes.
+    reported: Option<QueuePosition>,
+}
+
+impl Scheduler {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queues: Mutex::new(Queues::default()),
+            arrived: Condvar::new(),
+            freed: Notify::new(),
+        }
+    }
+
+    /// Queue `req`, waiting up to its `max_wait` for a free slot.
+    pub(super) async fn push(&self, req: InferRequest) -> Result<QueuePosition, AppError> {
+        let deadline = req.params.queue.max_wait.map(|wait| tokio::time::Instant::now() + wait);
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            {
+                let mut queues = self.lock();
+                queues.drop_cancelled();
+                if queues.len < self.capacity {
+                    let position = queues.insert(req);
+                    self.arrived.notify_one();
+                    return Ok(position);
+                }
+            }
+            let expired = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, freed).await.is_err(),
+                None => true,
+            };
+            if expired {
+                metrics::global().queue_rejected();
+                return Err(AppError::QueueFull);
+            }
+        }
+    }
+
+    /// Block until a request is due and hand it to the worker, which calls
+    /// this again once it is done. Requests whose client has gone are skipped.
+    pub(super) fn next(&self) -> InferRequest {
+        let mut queues = self.lock();
+        if let Some(since) = queues.running_since.take() {
+            queues.record(since.elapsed());
+        }
+        loop {
+            let Some(req) = queues.pop() else {
+                queues = self.arrived.wait(queues).unwrap_or_else(|e| e.into_inner());
+                continue;
+            };
+            self.freed.notify_waiters();
+            if req.is_cancelled() {
+                debug!("Dropping cancelled request from the queue");
+                continue;
+            }
+            req.started.store(true, Ordering::Relaxed);
+            queues.running_since = Some(Instant::now());
+            queues.reposition(None);
+            return req;
+        }
+    }
+
+    /// Requests waiting to start.
+    pub(super) fn depth(&self) -> usize {
+        self.lock().len
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        self.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Queues {
+    fn insert(&mut self, req: InferRequest) -> QueuePosition {
+        let id = self.next_id;
+        self.next_id += 1;
+        let class = &mut self.classes[req.params.queue.priority as usize];
+        let key = req.params.queue.fair_key.clone();
+        let waiting = class.waiting.entry(key.clone()).or_default();
+        if waiting.is_empty() {
+            class.turns.push_back(key);
+        }
+        waiting.push_back(Entry { id, req, reported: None });
+        self.len += 1;
+        self.reposition(Some(id)).unwrap_or(QueuePosition { ahead: 0, wait_secs: None })
+    }
+
+    /// The next request: first class with work, its next key in turn.
+    fn pop(&mut self) -> Option<InferRequest> {
+        let class = self.classes.iter_mut().find(|c| !c.turns.is_empty())?;
+        let key = class.turns.pop_front()?;
+        let waiting = class.waiting.get_mut(&key)?;
+        let entry = waiting.pop_front()?;
+        if waiting.is_empty() {
+            class.waiting.remove(&key);
+        } else {
+            class.turns.push_back(key);
+        }
+        self.len -= 1;
+        Some(entry.req)
+    }
+
+    /// Free the slots of requests whose client has gone.
+    fn drop_cancelled(&mut self) {
+        let mut dropped = 0;
+        for class in &mut self.classes {
+            for waiting in class.waiting.values_mut() {
+                let before = waiting.len();
+                waiting.retain(|e| !e.req.is_cancelled());
+                dropped += before - waiting.len();
+            }
+            class.waiting.retain(|_, waiting| !waiting.is_empty());
+            let waiting = &class.waiting;
+            class.turns.retain(|key| waiting.contains_key(key));
+        }
+        self.len -= dropped;
+    }
+
+    fn record(&mut self, took: Duration) {
+        let secs = took.as_secs_f64();
+        self.service_secs = Some(match self.service_secs {
+            Some(avg) => avg + SERVICE_TIME_WEIGHT * (secs - avg),
+            None => secs,
+        });
+    }
+
+    /// Work out every queued request's position in the order [`pop`] will
+    /// serve them, tell clients whose position changed, and return the
+    /// position of `new` (whose client learns it from `push`).
+    fn reposition(&mut self, new: Option<u64>) -> Option<QueuePosition> {
+        let running = self.running_since.is_some() as usize;
+        let service_secs = self.service_secs;
+        let mut ahead = running;
+        let mut new_position = None;
+        for class in &mut self.classes {
+            // Round r serves the r-th request of every key, in turn order.
+            let rounds = class.waiting.values().map(VecDeque::len).max().unwrap_or(0);
+            for round in 0..rounds {
+                for key in &class.turns {
+                    let Some(entry) = class.waiting.get_mut(key).and_then(|w| w.get_mut(round)) else {
+                        continue;
+                    };
+                    let position = QueuePosition {
+                        ahead,
+                        wait_secs: service_secs.map(|s| (ahead as f64 * s).ceil() as u64),
+                    };
+                    if Some(entry.id) == new {
+                        new_position = Some(position);
+                    } else if entry.reported.is_some_and(|r| r != position) {
+                        let _ = entry.req.events.send(InferEvent::Queued(position));
+                    }
+                    entry.reported = Some(position);
+                    ahead += 1;
+                }
+            }
+        }
+        new_position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::llm::{InferParams, SamplingParams};
+
+    fn request(priority: Priority, fair_key: &str, prompt: &str) -> (InferRequest, mpsc::UnboundedReceiver<InferEvent>) {
+        let (events, rx) = mpsc::unbounded_channel();
+        let params = InferParams {
+            max_tokens: 1,
+            sampling: SamplingParams::default(),
+            session_id: None,
+            stop: Vec::new(),
+            grammar: None,
+            queue: QueueOptions { priority, fair_key: fair_key.into(), max_wait: None },
+        };
+        let req = InferRequest {
+            prompt: prompt.into(),
+            params,
+            events,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            started: Arc::new(AtomicBool::new(false)),
+        };
+        (req, rx)
+    }
+
+    async fn push(scheduler: &Scheduler, priority: Priority, fair_key: &str, prompt: &str) -> QueuePosition {
+        scheduler.push(request(priority, fair_key, prompt).0).await.unwrap()
+    }
+
+    fn served(scheduler: &Scheduler) -> Vec<String> {
+        (0..scheduler.depth()).map(|_| scheduler.next().prompt).collect()
+    }
+
+    #[tokio::test]
+    async fn higher_classes_always_go_first() {
+        let scheduler = Scheduler::new(8);
+        push(&scheduler, Priority::Background, "a", "background").await;
+        push(&scheduler, Priority::Batch, "a", "batch").await;
+        push(&scheduler, Priority::Interactive, "b", "interactive 1").await;
+        push(&scheduler, Priority::Interactive, "a", "interactive 2").await;
+        assert_eq!(served(&scheduler), ["interactive 1", "interactive 2", "batch", "background"]);
+    }
+
+    #[tokio::test]
+    async fn keys_take_turns_within_a_class() {
+        let scheduler = Scheduler::new(8);
+        for prompt in ["a1", "a2", "a3"] {
+            push(&scheduler, Priority::Batch, "a", prompt).await;
+        }
+        push(&scheduler, Priority::Batch, "b", "b1").await;
+        push(&scheduler, Priority::Batch, "c", "c1").await;
+        assert_eq!(served(&scheduler), ["a1", "b1", "c1", "a2", "a3"]);
+    }
+
+    #[tokio::test]
+    async fn positions_follow_the_serving_order() {
+        let scheduler = Scheduler::new(8);
+        let (batch, mut batch_events) = request(Priority::Batch, "a", "batch");
+        assert_eq!(scheduler.push(batch).await.unwrap().ahead, 0);
+        push(&scheduler, Priority::Batch, "a", "a2").await;
+        assert_eq!(push(&scheduler, Priority::Batch, "b", "b1").await.ahead, 1);
+
+        // An interactive request jumps the queue and the batch one hears about it.
+        assert_eq!(push(&scheduler, Priority::Interactive, "c", "interactive").await.ahead, 0);
+        match batch_events.try_recv() {
+            Ok(InferEvent::Queued(position)) => assert_eq!(position.ahead, 1),
+            other => panic!("expected a queue update, got {:?}", other),
+        }
+
+        // The running request counts as ahead of everyone.
+        assert_eq!(scheduler.next().prompt, "interactive");
+        assert_eq!(push(&scheduler, Priority::Interactive, "c", "next").await.ahead, 1);
+    }
+
+    #[tokio::test]
+    async fn full_queue_refuses_and_cancelled_requests_free_their_slot() {
+        let scheduler = Scheduler::new(1);
+        let (gone, _events) = request(Priority::Interactive, "a", "gone");
+        let cancelled = gone.cancelled.clone();
+        scheduler.push(gone).await.unwrap();
+        let (refused, _) = request(Priority::Interactive, "b", "refused");
+        assert!(matches!(scheduler.push(refused).await, Err(AppError::QueueFull)));
+
+        cancelled.store(true, Ordering::Relaxed);
+        push(&scheduler, Priority::Interactive, "b", "kept").await;
+        assert_eq!(scheduler.depth(), 1);
+        assert_eq!(scheduler.next().prompt, "kept");
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+
+use llama_cpp::{LlamaSession, Token};
+use tracing::debug;
+
+use crate::errors::AppError;
+
+/// Small LRU of live llama.cpp sessions keyed by `session_id`.
+/// Keeping a session alive keeps its KV cache, so the next turn of the same
+/// conversation only has to evaluate the tokens that were not seen before.
+pub(super) struct SessionCache {
+    capacity: usize,
+    /// Most recently used first.
+    entries: VecDeque<(String, LlamaSession)>,
+}
+
+impl SessionCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Remove and return the cached session for `key`, if any.
+    pub(super) fn take(&mut self, key: &str) -> Option<LlamaSession> {
+        let pos = self.entries.iter().position(|(k, _)| k == key)?;
+        self.entries.remove(pos).map(|(_, session)| session)
+    }
+
+    /// Store `session` as the most recently used entry, evicting the oldest if full.
+    pub(super) fn put(&mut self, key: String, session: LlamaSession) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.retain(|(k, _)| *k != key);
+        while self.entries.len() >= self.capacity {
+            if let Some((evicted, _)) = self.entries.pop_back() {
+                debug!(session_id = %evicted, "Evicted cached LLM session");
+            }
+        }
+        self.entries.push_front((key, session));
+    }
+}
+
+/// Bring `session` to exactly `tokens`, evaluating only what is not already cached.
+///
+/// The shared prefix of the cached context is kept; anything after it (a
+/// diverging edit or the previous answer's tail) is dropped from the KV cache.
+/// At least one token is always re-evaluated so the session has fresh logits.
+pub(super) fn prime_session(session: &mut LlamaSession, tokens: &[Token]) -> Result<usize, AppError> {
+    let cached = session.context();
+    let shared = cached
+        .iter()
+        .zip(tokens)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(tokens.len().saturating_sub(1));
+
+    if shared < cached.len() {
+        session
+            .truncate_context(shared)
+            .map_err(|e| AppError::LlmError(format!("Failed to truncate context: {}", e)))?;
+    }
+
+    session
+        .advance_context_with_tokens(&tokens[shared..])
+        .map_err(|e| AppError::LlmError(format!("Failed to advance context: {}", e)))?;
+
+    Ok(shared)
+}
@@ -0,0 +1,297 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Prompt formats for the chat models BroAi is commonly run with.
+///
+/// Picked automatically from the GGUF metadata of the loaded model
+/// (`tokenizer.chat_template`, then `general.architecture`), or forced with
+/// `model.chat_template` in the config (`CHAT_TEMPLATE`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&'static str")]
+pub enum ChatTemplate {
+    /// `<|im_start|>role ... <|im_end|>` — Qwen, Hermes, many fine-tunes.
+    ChatMl,
+    /// `<|start_header_id|>role<|end_header_id|> ... <|eot_id|>`
+    Llama3,
+    /// `[INST] ... [/INST]` — Mistral / Mixtral instruct.
+    Mistral,
+    /// `<|user|> ... <|end|>`
+    Phi3,
+    /// `<start_of_turn>user ... <end_of_turn>`
+    Gemma,
+    /// `<|user|> ... </s>` — TinyLlama chat and other Zephyr fine-tunes.
+    Zephyr,
+}
+
+impl ChatTemplate {
+    /// Parse a config value such as `chatml` or `llama3`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().replace(['-', '_'], "").as_str() {
+            "chatml" | "qwen" => Some(Self::ChatMl),
+            "llama3" => Some(Self::Llama3),
+            "mistral" | "mistralinstruct" => Some(Self::Mistral),
+            "phi3" => Some(Self::Phi3),
+            "gemma" => Some(Self::Gemma),
+            "zephyr" | "tinyllama" => Some(Self::Zephyr),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ChatMl => "chatml",
+            Self::Llama3 => "llama3",
+            Self::Mistral => "mistral",
+            Self::Phi3 => "phi3",
+            Self::Gemma => "gemma",
+            Self::Zephyr => "zephyr",
+        }
+    }
+
+    /// Choose a format from model metadata. The Jinja chat template is checked
+    /// for each format's distinctive markers first; the architecture name is
+    /// only a fallback because e.g. Mistral and TinyLlama both report `llama`.
+    pub fn detect(chat_template: Option<&str>, architecture: Option<&str>) -> Self {
+        if let Some(t) = chat_template {
+            if t.contains("<|im_start|>") {
+                return Self::ChatMl;
+            }
+            if t.contains("<|start_header_id|>") {
+                return Self::Llama3;
+            }
+            if t.contains("<start_of_turn>") {
+                return Self::Gemma;
+            }
+            if t.contains("<|end|>") && t.contains("<|user|>") {
+                return Self::Phi3;
+            }
+            if t.contains("[INST]") {
+                return Self::Mistral;
+            }
+            if t.contains("<|user|>") {
+                return Self::Zephyr;
+            }
+        }
+
+        match architecture.unwrap_or("") {
+            "qwen" | "qwen2" | "qwen2moe" | "qwen3" => Self::ChatMl,
+            "phi3" => Self::Phi3,
+            "gemma" | "gemma2" | "gemma3" => Self::Gemma,
+            _ => Self::Zephyr,
+        }
+    }
+
+    /// Markers that end the assistant's turn: the format's end-of-turn tag and
+    /// the opener of the next turn, for models that skip the former and start
+    /// inventing the user's reply.
+    pub fn stop_markers(&self) -> &'static [&'static str] {
+        match self {
+            Self::ChatMl => &["<|im_end|>", "<|im_start|>"],
+            Self::Llama3 => &["<|eot_id|>", "<|start_header_id|>"],
+            Self::Mistral => &["</s>", "[INST]"],
+            Self::Phi3 => &["<|end|>", "<|user|>"],
+            Self::Gemma => &["<end_of_turn>", "<start_of_turn>"],
+            Self::Zephyr => &["</s>", "<|user|>"],
+        }
+    }
+
+    /// Render `(role, content)` pairs into a prompt that ends with an open
+    /// assistant turn.
+    pub fn render<'a>(&self, messages: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+        let mut p = String::new();
+        match self {
+            Self::ChatMl => {
+                for (role, content) in messages {
+                    p.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", role, content));
+                }
+                p.push_str("<|im_start|>assistant\n");
+            }
+            Self::Llama3 => {
+                p.push_str("<|begin_of_text|>");
+                for (role, content) in messages {
+                    p.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        role, content
+                    ));
+                }
+                p.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+            }
+            Self::Mistral => {
+                // No system role: it is folded into the next user instruction.
+                p.push_str("<s>");
+                let mut system = String::new();
+                for (role, content) in messages {
+                    match role {
+                        "system" => {
+                            system.push_str(content);
+                            system.push_str("\n\n");
+                        }
+                        "assistant" => p.push_str(&format!(" {}</s>", content)),
+                        _ => {
+                            p.push_str(&format!(
+                                "[INST] {}{} [/INST]",
+                                std::mem::take(&mut system),
+                                user_content(role, content)
+                            ));
+                        }
+                    }
+                }
+            }
+            Self::Phi3 => {
+                for (role, content) in messages {
+                    p.push_str(&format!("<|{}|>\n{}<|end|>\n", known_role(role), user_content(role, content)));
+                }
+                p.push_str("<|assistant|>\n");
+            }
+            Self::Gemma => {
+                // No system role: it is folded into the next user turn.
+                p.push_str("<bos>");
+                let mut system = String::new();
+                for (role, content) in messages {
+                    match role {
+                        "system" => {
+                            system.push_str(content);
+                            system.push_str("\n\n");
+                        }
+                        "assistant" => {
+                            p.push_str(&format!("<start_of_turn>model\n{}<end_of_turn>\n", content))
+                        }
+                        _ => p.push_str(&format!(
+                            "<start_of_turn>user\n{}{}<end_of_turn>\n",
+                            std::mem::take(&mut system),
+                            user_content(role, content)
+                        )),
+                    }
+                }
+                p.push_str("<start_of_turn>model\n");
+            }
+            Self::Zephyr => {
+                for (role, content) in messages {
+                    p.push_str(&format!("<|{}|>\n{}</s>\n", known_role(role), user_content(role, content)));
+                }
+                p.push_str("<|assistant|>\n");
+            }
+        }
+        p
+    }
+}
+
+impl FromStr for ChatTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!("unknown chat template `{}`; use chatml, llama3, mistral, phi3, gemma or zephyr", s)
+        })
+    }
+}
+
+impl TryFrom<String> for ChatTemplate {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ChatTemplate> for &'static str {
+    fn from(template: ChatTemplate) -> Self {
+        template.name()
+    }
+}
+
+/// Template to use: the configured one, else detected from the model metadata.
+pub(super) fn configured_template(
+    configured: Option<ChatTemplate>,
+    chat_template: Option<&str>,
+    architecture: Option<&str>,
+) -> ChatTemplate {
+    configured.unwrap_or_else(|| ChatTemplate::detect(chat_template, architecture))
+}
+
+/// Formats with a fixed role set render unknown roles as user turns.
+fn known_role(role: &str) -> &str {
+    match role {
+        "system" | "assistant" => role,
+        _ => "user",
+    }
+}
+
+/// Keep the original role visible when it is not a plain user turn.
+fn user_content(role: &str, content: &str) -> String {
+    match role {
+        "user" | "system" | "assistant" => content.to_string(),
+        _ => format!("{}: {}", role, content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_each_format_from_its_jinja_markers() {
+        let cases = [
+            ("{{ '<|im_start|>' + message['role'] }}", ChatTemplate::ChatMl),
+            ("{{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>' }}", ChatTemplate::Llama3),
+            ("{{ '<start_of_turn>' + role }}", ChatTemplate::Gemma),
+            ("{{ '<|user|>' + content + '<|end|>' }}", ChatTemplate::Phi3),
+            ("{{ bos_token + '[INST] ' + content + ' [/INST]' }}", ChatTemplate::Mistral),
+            ("{{ '<|user|>\n' + content + eos_token }}", ChatTemplate::Zephyr),
+        ];
+        for (jinja, expected) in cases {
+            // Mistral and TinyLlama both report `llama`; the template decides.
+            assert_eq!(ChatTemplate::detect(Some(jinja), Some("llama")), expected, "{}", jinja);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_architecture() {
+        assert_eq!(ChatTemplate::detect(None, Some("qwen2")), ChatTemplate::ChatMl);
+        assert_eq!(ChatTemplate::detect(None, Some("phi3")), ChatTemplate::Phi3);
+        assert_eq!(ChatTemplate::detect(None, Some("gemma2")), ChatTemplate::Gemma);
+        assert_eq!(ChatTemplate::detect(Some("{{ messages }}"), Some("llama")), ChatTemplate::Zephyr);
+        assert_eq!(ChatTemplate::detect(None, None), ChatTemplate::Zephyr);
+    }
+
+    #[test]
+    fn configured_template_wins_over_metadata() {
+        let chatml = Some("<|im_start|>");
+        assert_eq!(configured_template(Some(ChatTemplate::Gemma), chatml, Some("qwen2")), ChatTemplate::Gemma);
+        assert_eq!(configured_template(None, chatml, Some("gemma")), ChatTemplate::ChatMl);
+    }
+
+    #[test]
+    fn parses_names_and_aliases() {
+        assert_eq!("Llama-3".parse::<ChatTemplate>(), Ok(ChatTemplate::Llama3));
+        assert_eq!("qwen".parse::<ChatTemplate>(), Ok(ChatTemplate::ChatMl));
+        assert_eq!("tiny_llama".parse::<ChatTemplate>(), Ok(ChatTemplate::Zephyr));
+        assert!("vicuna".parse::<ChatTemplate>().is_err());
+        for template in [ChatTemplate::ChatMl, ChatTemplate::Mistral, ChatTemplate::Phi3] {
+            assert_eq!(ChatTemplate::from_name(template.name()), Some(template));
+        }
+    }
+
+    #[test]
+    fn formats_without_a_system_role_fold_it_into_the_user_turn() {
+        let messages = [("system", "Be brief."), ("user", "Hi"), ("assistant", "Hello"), ("user", "Bye")];
+        assert_eq!(
+            ChatTemplate::Mistral.render(messages),
+            "<s>[INST] Be brief.\n\nHi [/INST] Hello</s>[INST] Bye [/INST]"
+        );
+        assert_eq!(
+            ChatTemplate::Gemma.render(messages[..2].iter().copied()),
+            "<bos><start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n<start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn other_roles_render_as_labelled_user_turns() {
+        assert_eq!(
+            ChatTemplate::Zephyr.render([("tool", "42")]),
+            "<|user|>\ntool: 42</s>\n<|assistant|>\n"
+        );
+        assert_eq!(ChatTemplate::ChatMl.render([("user", "Hi")]), "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n");
+    }
+}
@@ -1,50 +1,34 @@
 mod api;
+mod cli;
+mod config;
 mod errors;
 mod llm;
 mod memory;
+mod metrics;
 mod plugins;
 mod security;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, EnvFilter};
 
 use crate::api::AppState;
+use crate::api::limits::RateLimiter;
+use crate::config::Config;
 use crate::llm::LlmActor;
 use crate::memory::MemoryStore;
-use crate::security::DeviceIdentity;
-
-/// Configuration loaded from environment variables with sensible defaults.
-struct Config {
-    host: String,
-    port: u16,
-    model_path: String,
-    db_path: String,
-    key_path: String,
-    plugin_dir: String,
-}
-
-impl Config {
-    fn from_env() -> Self {
-        Self {
-            host: std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".into()),
-            port: std::env::var("PORT")
-                .ok()
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(8080),
-            model_path: std::env::var("MODEL_PATH")
-                .unwrap_or_else(|_| "/opt/broai/models/model.gguf".into()),
-            db_path: std::env::var("DB_PATH").unwrap_or_else(|_| "/var/lib/broai/memory.db".into()),
-            key_path: std::env::var("KEY_PATH")
-                .unwrap_or_else(|_| "/var/lib/broai/device.key".into()),
-            plugin_dir: std::env::var("PLUGIN_DIR").unwrap_or_else(|_| "/opt/broai/plugins".into()),
-        }
-    }
-}
+use crate::plugins::{PluginHandle, PluginRegistry};
+use crate::security::{DeviceIdentity, SignaturePolicy};
 
 #[tokio::main]
 async fn main() {
+    // `broai <command> ...` runs a command-line tool instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args).await);
+    }
+
     // Initialize tracing
     let filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info,broai=debug"));
@@ -53,13 +37,37 @@ async fn main() {
 
     info!("🦀 BroAi v{} starting", env!("CARGO_PKG_VERSION"));
 
-    let config = Config::from_env();
+    // Defaults, then the config file, then environment variables
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, "Invalid configuration — run `broai config check` for details");
+            std::process::exit(1);
+        }
+    };
 
-    // Load plugin registry from manifests in plugin_dir
-    let plugins = crate::plugins::PluginRegistry::load(&config.plugin_dir);
+    // Fail now rather than on every plugin call if the sandbox can't work here
+    if config.plugins.sandbox {
+        if let Err(e) = crate::plugins::probe_sandbox() {
+            error!(
+                error = %e,
+                "The plugin sandbox does not work on this host — allow unprivileged user namespaces \
+                 (e.g. the AppArmor restriction on Ubuntu 24.04) or set PLUGIN_SANDBOX=off"
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // Load plugin registry from manifests in the plugin dir, checking signatures
+    // against the publisher keys in the trust dir
+    let policy = SignaturePolicy {
+        trust_dir: config.plugins.trust_dir.clone().into(),
+        require_signed: config.plugins.require_signed,
+    };
+    let plugins = PluginHandle::new(PluginRegistry::load(&config.plugins.dir, &policy, &config.plugin_settings()));
 
     // Initialize device identity (generates keypair if first boot)
-    let identity: Arc<DeviceIdentity> = match DeviceIdentity::load_or_generate(&config.key_path) {
+    let identity: Arc<DeviceIdentity> = match DeviceIdentity::load_or_generate(&config.security.key_path) {
         Ok(id) => {
             let hex: String = id.public_key_hex();
             info!(device_id = %hex, "Device identity loaded");
@@ -72,16 +80,16 @@ async fn main() {
     };
 
     // Initialize memory store
-    let memory: Arc<MemoryStore> = match MemoryStore::open(&config.db_path) {
+    let memory: Arc<MemoryStore> = match MemoryStore::open(&config.memory.db_path) {
         Ok(m) => Arc::new(m),
         Err(e) => {
-            error!(error = %e, db_path = %config.db_path, "Failed to open memory store");
+            error!(error = %e, db_path = %config.memory.db_path, "Failed to open memory store");
             std::process::exit(1);
         }
     };
 
     // Spawn LLM actor (runs on dedicated OS thread)
-    let llm: Arc<LlmActor> = match LlmActor::spawn(config.model_path.clone()) {
+    let llm: Arc<LlmActor> = match LlmActor::spawn(config.model.clone(), &config.sampling, config.queue.capacity) {
         Ok(actor) => Arc::new(actor),
         Err(e) => {
             error!(error = %e, "Failed to initialize LLM actor");
@@ -89,18 +97,41 @@ async fn main() {
         }
     };
 
+    if !config.plugins.sandbox {
+        warn!("PLUGIN_SANDBOX is off — plugins run with the full privileges of the server");
+    }
+    if !config.security.api_auth {
+        warn!("API_AUTH is off — anyone who can reach the server can chat, run plugins and read metrics");
+    } else if memory.list_api_keys().await.map(|keys| keys.iter().all(|k| k.revoked_at.is_some())).unwrap_or(false) {
+        warn!("No API keys yet — create one with `broai key create <name> --scopes chat,plugins:*`");
+    }
+
+    if config.memory.retention_days > 0 {
+        crate::memory::spawn_retention(memory.clone(), config.memory.retention_days);
+    }
+
+    let limits = RateLimiter::new(config.rate_limits(), memory.clone());
+    limits.spawn_pruning();
+    let policy = limits.policy();
+    info!(by = ?policy.by, requests_per_minute = policy.requests_per_minute, tokens_per_day = policy.tokens_per_day, "Rate limits (0 = off)");
+
+    // Pick up added, changed and removed plugins without a restart
+    crate::plugins::spawn_watchers(plugins.clone(), memory.clone());
+
     let state = AppState {
         llm,
         memory,
         device: identity,
-        plugins: std::sync::Arc::new(plugins),
+        plugins,
+        admin_token: config.security.admin_token.as_deref().map(Into::into),
+        plugin_sandbox: config.plugins.sandbox,
+        api_auth: config.security.api_auth,
+        limits,
     };
 
     let app = crate::api::router(state).layer(tower_http::cors::CorsLayer::permissive());
 
-    let addr: SocketAddr = format!("{}:{}", config.host, config.port)
-        .parse()
-        .expect("Invalid bind address");
+    let addr: SocketAddr = config.bind_addr().expect("bind address is validated with the config");
 
     info!(addr = %addr, "HTTP server listening");
     info!("OpenAI endpoint:  http://{}/v1/chat/completions", addr);
@@ -110,8 +141,8 @@ async fn main() {
         .await
         .expect("Failed to bind TCP listener");
 
-    // Explicit type annotation needed for axum::serve type inference
-    axum::serve(listener, app.into_make_service())
+    // Client addresses are needed for per-IP rate limits
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .expect("Server error");
@@ -1,10 +1,18 @@
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, OptionalExtension, params};
 use chrono::{DateTime, Utc};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::errors::AppError;
+use crate::metrics;
+
+/// `DB_PATH` when unset.
+pub const DEFAULT_DB_PATH: &str = "/var/lib/broai/memory.db";
+
+/// How often conversations past the retention period are deleted.
+const RETENTION_SWEEP: Duration = Duration::from_secs(3600);
 
 pub struct ConversationEntry {
     pub session_id: String,
@@ -14,6 +22,15 @@ pub struct ConversationEntry {
     pub timestamp: DateTime<Utc>,
 }
 
+/// An API key as stored; the key itself is never kept, only its hash.
+pub struct ApiKeyRecord {
+    pub name: String,
+    /// Space-separated scopes.
+    pub scopes: String,
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct MemoryStore {
     conn: Arc<Mutex<Connection>>,
@@ -42,18 +59,46 @@ impl MemoryStore {
             CREATE INDEX IF NOT EXISTS idx_session
                 ON conversations(session_id);
 
+            CREATE TABLE IF NOT EXISTS session_summaries (
+                session_id   TEXT PRIMARY KEY,
+                summary      TEXT NOT NULL,
+                last_covered TEXT NOT NULL,
+                updated_at   TEXT NOT NULL
+            );
+
             CREATE TABLE IF NOT EXISTS audit_log (
                 id         INTEGER PRIMARY KEY AUTOINCREMENT,
                 event_type TEXT NOT NULL,
                 payload    TEXT,
                 created_at TEXT NOT NULL DEFAULT (datetime('now'))
             );
+
+            CREATE TABLE IF NOT EXISTS api_keys (
+                id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                name       TEXT NOT NULL,
+                key_hash   TEXT NOT NULL UNIQUE,
+                scopes     TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                revoked_at TEXT
+            );
+
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_api_key_name
+                ON api_keys(name) WHERE revoked_at IS NULL;
+
+            CREATE TABLE IF NOT EXISTS rate_limits (
+                subject    TEXT NOT NULL,
+                bucket     TEXT NOT NULL,
+                level      REAL NOT NULL,
+                updated_at REAL NOT NULL,
+                PRIMARY KEY (subject, bucket)
+            );
         ")?;
         Ok(())
     }
 
     pub async fn save_conversation(&self, entry: ConversationEntry) -> Result<(), AppError> {
         let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
         conn.execute(
             "INSERT INTO conversations (session_id, user_msg, assistant_msg, model, created_at)
              VALUES (?1, ?2, ?3, ?4, ?5)",
@@ -68,7 +113,7 @@ impl MemoryStore {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Most recent `limit` turns of a session as (user, assistant) pairs, newest first.
     pub async fn get_session_history(
         &self,
         session_id: &str,
@@ -90,9 +135,46 @@ impl MemoryStore {
         Ok(rows)
     }
 
-    #[allow(dead_code)]
+    /// Rolling summary of the turns trimmed from a session's context window,
+    /// with the hash of the last turn it covers.
+    pub async fn get_session_summary(
+        &self,
+        session_id: &str,
+    ) -> Result<Option<(String, String)>, AppError> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT summary, last_covered FROM session_summaries WHERE session_id = ?1",
+        )?;
+        let mut rows = stmt.query(params![session_id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save_session_summary(
+        &self,
+        session_id: &str,
+        summary: &str,
+        last_covered: &str,
+    ) -> Result<(), AppError> {
+        let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
+        conn.execute(
+            "INSERT INTO session_summaries (session_id, summary, last_covered, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(session_id) DO UPDATE SET
+                summary = excluded.summary,
+                last_covered = excluded.last_covered,
+                updated_at = excluded.updated_at",
+            params![session_id, summary, last_covered, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
     pub async fn log_audit(&self, event_type: &str, payload: Option<&str>) -> Result<(), AppError> {
         let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
         conn.execute(
             "INSERT INTO audit_log (event_type, payload) VALUES (?1, ?2)",
             params![event_type, payload],
@@ -100,9 +182,131 @@ impl MemoryStore {
         Ok(())
     }
 
+    /// Store a new API key by its hash; `scopes` is space-separated.
+    pub async fn create_api_key(&self, name: &str, key_hash: &str, scopes: &str) -> Result<(), AppError> {
+        let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
+        conn.execute(
+            "INSERT INTO api_keys (name, key_hash, scopes) VALUES (?1, ?2, ?3)",
+            params![name, key_hash, scopes],
+        )?;
+        Ok(())
+    }
+
+    /// Name and scopes of the unrevoked key with this hash.
+    pub async fn find_api_key(&self, key_hash: &str) -> Result<Option<(String, String)>, AppError> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT name, scopes FROM api_keys WHERE key_hash = ?1 AND revoked_at IS NULL",
+        )?;
+        let mut rows = stmt.query(params![key_hash])?;
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Revoke the live key called `name`. Returns false if there is none.
+    pub async fn revoke_api_key(&self, name: &str) -> Result<bool, AppError> {
+        let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
+        let changed = conn.execute(
+            "UPDATE api_keys SET revoked_at = ?2 WHERE name = ?1 AND revoked_at IS NULL",
+            params![name, Utc::now().to_rfc3339()],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Every key ever created, oldest first.
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKeyRecord>, AppError> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT name, scopes, created_at, revoked_at FROM api_keys ORDER BY id",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(ApiKeyRecord {
+                    name: row.get(0)?,
+                    scopes: row.get(1)?,
+                    created_at: row.get(2)?,
+                    revoked_at: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Read-modify-write one rate-limit bucket under the store lock. `update`
+    /// gets the stored `(level, updated_at)` and returns the new level, which
+    /// is saved with `now` as its time, and a result to pass back.
+    pub async fn update_bucket<T>(
+        &self,
+        subject: &str,
+        bucket: &str,
+        now: f64,
+        update: impl FnOnce(Option<(f64, f64)>) -> (f64, T),
+    ) -> Result<T, AppError> {
+        let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
+        let stored = conn
+            .query_row(
+                "SELECT level, updated_at FROM rate_limits WHERE subject = ?1 AND bucket = ?2",
+                params![subject, bucket],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (level, result) = update(stored);
+        conn.execute(
+            "INSERT INTO rate_limits (subject, bucket, level, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (subject, bucket) DO UPDATE SET level = ?3, updated_at = ?4",
+            params![subject, bucket, level, now],
+        )?;
+        Ok(result)
+    }
+
+    /// Delete conversations saved before `before`, and the summaries of sessions
+    /// left with none. Returns the number of conversation turns removed.
+    pub async fn prune_conversations(&self, before: DateTime<Utc>) -> Result<usize, AppError> {
+        let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
+        let removed = conn.execute(
+            "DELETE FROM conversations WHERE created_at < ?1",
+            params![before.to_rfc3339()],
+        )?;
+        conn.execute(
+            "DELETE FROM session_summaries
+             WHERE session_id NOT IN (SELECT DISTINCT session_id FROM conversations)",
+            [],
+        )?;
+        Ok(removed)
+    }
+
+    /// Drop buckets untouched since `before`; they would have refilled anyway.
+    pub async fn prune_buckets(&self, before: f64) -> Result<usize, AppError> {
+        let conn = self.conn.lock().await;
+        let _timer = metrics::global().sqlite_write();
+        Ok(conn.execute("DELETE FROM rate_limits WHERE updated_at < ?1", params![before])?)
+    }
+
     pub async fn ping(&self) -> Result<(), AppError> {
         let conn = self.conn.lock().await;
         conn.execute_batch("SELECT 1")?;
         Ok(())
     }
 }
+
+/// Delete conversations older than `days` now and then every hour.
+pub fn spawn_retention(memory: Arc<MemoryStore>, days: u32) {
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(RETENTION_SWEEP);
+        loop {
+            sweep.tick().await;
+            let before = Utc::now() - chrono::Duration::days(days as i64);
+            match memory.prune_conversations(before).await {
+                Ok(0) => {}
+                Ok(n) => info!(removed = n, retention_days = days, "Deleted old conversations"),
+                Err(e) => warn!(error = %e, "Failed to delete old conversations"),
+            }
+        }
+    });
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Latency buckets for model calls, in seconds (prompt eval and generation on a Pi run long).
+const INFERENCE_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+/// Plugin runs, bounded by the 120s manifest maximum.
+const PLUGIN_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 30.0, 120.0];
+/// SQLite writes.
+const SQLITE_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Where Linux exposes the SoC temperature on Raspberry Pi boards, in millidegrees.
+const THERMAL_ZONE: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Process-wide metrics, recorded where things happen and rendered by GET /metrics.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    prompt_eval: Histogram,
+    generation: Histogram,
+    prompt_tokens: AtomicU64,
+    generated_tokens: AtomicU64,
+    /// f64 bits of the generation speed of the last request.
+    tokens_per_second: AtomicU64,
+    session_memory_bytes: AtomicU64,
+    queue_rejected: AtomicU64,
+    timeouts: AtomicU64,
+    sqlite_write: Histogram,
+    plugins: Mutex<BTreeMap<String, PluginStats>>,
+}
+
+struct PluginStats {
+    calls: u64,
+    failures: u64,
+    duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            prompt_eval: Histogram::new(INFERENCE_BUCKETS),
+            generation: Histogram::new(INFERENCE_BUCKETS),
+            prompt_tokens: AtomicU64::new(0),
+            generated_tokens: AtomicU64::new(0),
+            tokens_per_second: AtomicU64::new(0),
+            session_memory_bytes: AtomicU64::new(0),
+            queue_rejected: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            sqlite_write: Histogram::new(SQLITE_BUCKETS),
+            plugins: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// One model call: prompt evaluation and generation timed separately.
+    pub fn record_inference(&self, prompt_eval: Duration, generation: Duration, prompt_tokens: u32, generated: u32) {
+        self.prompt_eval.observe(prompt_eval);
+        self.generation.observe(generation);
+        self.prompt_tokens.fetch_add(prompt_tokens as u64, Ordering::Relaxed);
+        self.generated_tokens.fetch_add(generated as u64, Ordering::Relaxed);
+        if generated > 0 && !generation.is_zero() {
+            let speed = generated as f64 / generation.as_secs_f64();
+            self.tokens_per_second.store(speed.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Host memory held by the session that served the last request.
+    pub fn set_session_memory(&self, bytes: usize) {
+        self.session_memory_bytes.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A request turned away because the queue was full or it waited too long.
+    pub fn queue_rejected(&self) {
+        self.queue_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inference_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_plugin(&self, plugin: &str, took: Duration, ok: bool) {
+        let mut plugins = self.plugins.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = plugins.entry(plugin.to_string()).or_insert_with(|| PluginStats {
+            calls: 0,
+            failures: 0,
+            duration: Histogram::new(PLUGIN_BUCKETS),
+        });
+        stats.calls += 1;
+        stats.failures += (!ok) as u64;
+        stats.duration.observe(took);
+    }
+
+    /// Time a SQLite write until the returned guard is dropped.
+    pub fn sqlite_write(&self) -> Timer<'_> {
+        Timer { histogram: &self.sqlite_write, start: Instant::now() }
+    }
+
+    /// Everything in the Prometheus text format. `queue_depth` comes from the
+    /// scheduler at scrape time.
+    pub fn render(&self, queue_depth: usize) -> String {
+        let mut out = String::new();
+        let counter = |v: &AtomicU64| v.load(Ordering::Relaxed) as f64;
+
+        self.prompt_eval.render(&mut out, "broai_inference_prompt_eval_seconds", "Time to evaluate the prompt before the first token", "");
+        self.generation.render(&mut out, "broai_inference_generation_seconds", "Time spent generating tokens", "");
+        metric(&mut out, "broai_prompt_tokens_total", "counter", "Prompt tokens evaluated", &[("", counter(&self.prompt_tokens))]);
+        metric(&mut out, "broai_generated_tokens_total", "counter", "Tokens generated", &[("", counter(&self.generated_tokens))]);
+        let speed = f64::from_bits(self.tokens_per_second.load(Ordering::Relaxed));
+        metric(&mut out, "broai_generation_tokens_per_second", "gauge", "Generation speed of the last request", &[("", speed)]);
+        metric(&mut out, "broai_queue_depth", "gauge", "Requests waiting for the model", &[("", queue_depth as f64)]);
+        metric(&mut out, "broai_queue_rejected_total", "counter", "Requests refused because the queue was full", &[("", counter(&self.queue_rejected))]);
+        metric(&mut out, "broai_inference_timeouts_total", "counter", "Requests that hit the inference timeout", &[("", counter(&self.timeouts))]);
+
+        {
+            let plugins = self.plugins.lock().unwrap_or_else(|e| e.into_inner());
+            let labels: Vec<(String, &PluginStats)> =
+                plugins.iter().map(|(name, stats)| (format!("plugin=\"{}\"", escape(name)), stats)).collect();
+            let calls: Vec<(&str, f64)> = labels.iter().map(|(l, s)| (l.as_str(), s.calls as f64)).collect();
+            let failures: Vec<(&str, f64)> = labels.iter().map(|(l, s)| (l.as_str(), s.failures as f64)).collect();
+            metric(&mut out, "broai_plugin_calls_total", "counter", "Plugin invocations", &calls);
+            metric(&mut out, "broai_plugin_failures_total", "counter", "Plugin invocations that failed or reported an error", &failures);
+            header(&mut out, "broai_plugin_duration_seconds", "histogram", "Plugin run time");
+            for (label, stats) in &labels {
+                stats.duration.render_series(&mut out, "broai_plugin_duration_seconds", label);
+            }
+        }
+
+        self.sqlite_write.render(&mut out, "broai_sqlite_write_seconds", "SQLite write latency", "");
+        metric(&mut out, "broai_llm_session_memory_bytes", "gauge", "Host memory of the model session that served the last request", &[("", counter(&self.session_memory_bytes))]);
+        if let Some(rss) = resident_memory_bytes() {
+            metric(&mut out, "process_resident_memory_bytes", "gauge", "Resident memory size in bytes", &[("", rss as f64)]);
+        }
+        if let Some(celsius) = cpu_temperature() {
+            metric(&mut out, "broai_cpu_temperature_celsius", "gauge", "SoC temperature", &[("", celsius)]);
+        }
+        out
+    }
+}
+
+/// Records the time since it was created into a histogram when dropped.
+pub struct Timer<'a> {
+    histogram: &'a Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed());
+    }
+}
+
+// ─── Histograms ──────────────────────────────────────────────────────────────
+
+/// Cumulative-bucket histogram of durations in seconds.
+struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+struct HistogramState {
+    /// Per bucket, not cumulative; the last slot is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        let state = HistogramState { counts: vec![0; bounds.len() + 1], sum: 0.0, count: 0 };
+        Self { bounds, state: Mutex::new(state) }
+    }
+
+    fn observe(&self, took: Duration) {
+        let secs = took.as_secs_f64();
+        let slot = self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.counts[slot] += 1;
+        state.sum += secs;
+        state.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, labels: &str) {
+        header(out, name, "histogram", help);
+        self.render_series(out, name, labels);
+    }
+
+    fn render_series(&self, out: &mut String, name: &str, labels: &str) {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, state.count);
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, braces, state.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces, state.count);
+    }
+}
+
+// ─── Text format ─────────────────────────────────────────────────────────────
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// A counter or gauge with one sample per label set (`""` for none).
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+    header(out, name, kind, help);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// ─── System ──────────────────────────────────────────────────────────────────
+
+/// `VmRSS` from /proc/self/status; `None` off Linux.
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn cpu_temperature() -> Option<f64> {
+    let millis: f64 = std::fs::read_to_string(THERMAL_ZONE).ok()?.trim().parse().ok()?;
+    Some(millis / 1000.0)
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use super::{kill_group, PluginManifest, PluginRequest, PluginResponse, PluginRunner, MAX_STDOUT_BYTES};
+use crate::errors::AppError;
+
+/// Upper bound for a manifest's `daemon.max_concurrent`.
+const MAX_CONCURRENT: usize = 16;
+/// Calls waiting for a busy daemon before new ones are refused.
+const QUEUE_DEPTH: usize = 32;
+/// An idle daemon is pinged this often and must answer within `PING_TIMEOUT`.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before restarting a crashed daemon, doubling per crash up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Time a daemon gets to exit after its stdin is closed before it is killed.
+const STOP_GRACE: Duration = Duration::from_secs(2);
+
+// ─── Manifest ────────────────────────────────────────────────────────────────
+
+/// How the core talks to a plugin, `"mode"` in its manifest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginMode {
+    /// A fresh process per call: one JSON request on stdin, one response on stdout.
+    #[default]
+    Oneshot,
+    /// One long-lived process speaking newline-delimited JSON-RPC 2.0.
+    Daemon,
+}
+
+/// `"daemon"` in a manifest; only used with `"mode": "daemon"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonOptions {
+    /// Calls the daemon is sent at once; more wait their turn.
+    pub max_concurrent: usize,
+    /// Stop the daemon after this many seconds without a call.
+    pub idle_secs: u64,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> Self {
+        Self { max_concurrent: 1, idle_secs: 300 }
+    }
+}
+
+impl DaemonOptions {
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if !(1..=MAX_CONCURRENT).contains(&self.max_concurrent) {
+            return Err(format!("daemon.max_concurrent must be between 1 and {}", MAX_CONCURRENT));
+        }
+        if self.idle_secs == 0 {
+            return Err("daemon.idle_secs must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+// ─── Protocol ────────────────────────────────────────────────────────────────
+
+/// One line to the daemon. `run` carries a [`PluginRequest`] as `params` and
+/// expects a [`PluginResponse`] as `result`; `ping` expects any result.
+#[derive(Serialize)]
+struct RpcRequest<'a> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<&'a PluginRequest>,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    id: Option<u64>,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+// ─── Pool ────────────────────────────────────────────────────────────────────
+
+/// Daemon plugins by name. Each is owned by a supervisor task that starts the
+/// process on the first call and stops it when idle; dropping its entry here
+/// stops it for good once its calls are answered.
+#[derive(Default)]
+pub struct DaemonPool {
+    daemons: Mutex<HashMap<String, Daemon>>,
+}
+
+struct Daemon {
+    /// Binary the supervisor was started for; a different one gets a new supervisor.
+    binary_sha256: Option<[u8; 32]>,
+    calls: mpsc::Sender<Call>,
+}
+
+struct Call {
+    request: PluginRequest,
+    reply: oneshot::Sender<Result<PluginResponse, AppError>>,
+}
+
+impl DaemonPool {
+    /// Send `request` to the plugin's daemon, starting it if it is not running.
+    pub async fn call(
+        &self,
+        runner: &PluginRunner,
+        manifest: &PluginManifest,
+        request: &PluginRequest,
+    ) -> Result<PluginResponse, AppError> {
+        let (reply, response) = oneshot::channel();
+        let call = Call { request: request.clone(), reply };
+        self.calls(runner, manifest).try_send(call).map_err(|e| {
+            AppError::PluginError(match e {
+                TrySendError::Full(_) => format!("Plugin '{}' is busy; try again shortly", manifest.name),
+                TrySendError::Closed(_) => format!("Plugin '{}' is stopping; try again", manifest.name),
+            })
+        })?;
+        response
+            .await
+            .unwrap_or_else(|_| Err(AppError::PluginError(format!("Plugin '{}' stopped", manifest.name))))
+    }
+
+    /// Let go of the named daemons, e.g. plugins a reload changed or removed.
+    /// Each stops once its calls are answered, and the next call starts a
+    /// fresh one from the files now on disk.
+    pub fn retire<'a>(&self, names: impl IntoIterator<Item = &'a String>) {
+        let mut daemons = self.daemons.lock().unwrap_or_else(|e| e.into_inner());
+        for name in names {
+            daemons.remove(name);
+        }
+    }
+
+    fn calls(&self, runner: &PluginRunner, manifest: &PluginManifest) -> mpsc::Sender<Call> {
+        let mut daemons = self.daemons.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(daemon) = daemons.get(&manifest.name) {
+            if daemon.binary_sha256 == manifest.binary_sha256 && !daemon.calls.is_closed() {
+                return daemon.calls.clone();
+            }
+        }
+        let (calls, queue) = mpsc::channel(QUEUE_DEPTH);
+        let supervisor = Supervisor {
+            runner: runner.clone(),
+            manifest: manifest.clone(),
+            queue,
+            failures: 0,
+            retry_at: None,
+        };
+        tokio::spawn(supervisor.run());
+        daemons.insert(manifest.name.clone(), Daemon { binary_sha256: manifest.binary_sha256, calls: calls.clone() });
+        calls
+    }
+}
+
+// ─── Supervisor ──────────────────────────────────────────────────────────────
+
+/// Owns one daemon plugin: starts it on demand, passes it calls, pings it
+/// while idle, stops it after `idle_secs`, and after a crash refuses calls
+/// until its backoff has passed.
+struct Supervisor {
+    runner: PluginRunner,
+    manifest: PluginManifest,
+    queue: mpsc::Receiver<Call>,
+    /// Crashes since the last successful call.
+    failures: u32,
+    /// No restart before this.
+    retry_at: Option<Instant>,
+}
+
+/// A call or ping sent to the daemon and not yet answered.
+struct Pending {
+    /// `None` for a ping.
+    reply: Option<oneshot::Sender<Result<PluginResponse, AppError>>>,
+    deadline: Instant,
+}
+
+enum Stop {
+    Idle,
+    /// The pool let go of this supervisor.
+    Retired,
+    Crashed(String),
+}
+
+enum Event {
+    Call(Option<Call>),
+    Line(Option<Result<String, String>>),
+    Tick,
+}
+
+impl Supervisor {
+    async fn run(mut self) {
+        let plugin = self.manifest.name.clone();
+        while let Some(call) = self.queue.recv().await {
+            if let Some(wait) = self.retry_at.and_then(|at| at.checked_duration_since(Instant::now())) {
+                let _ = call.reply.send(Err(self.error(&format!("crashed; restarting in {}s", wait.as_secs() + 1))));
+                continue;
+            }
+            let process = match Process::start(&self.runner, &self.manifest).await {
+                Ok(process) => process,
+                Err(e) => {
+                    warn!(plugin = %plugin, error = %e, "Plugin daemon failed to start");
+                    let _ = call.reply.send(Err(e));
+                    self.crashed();
+                    continue;
+                }
+            };
+            match self.serve(process, call).await {
+                Stop::Idle => info!(plugin = %plugin, "Plugin daemon stopped after idling"),
+                Stop::Retired => info!(plugin = %plugin, "Plugin daemon retired"),
+                Stop::Crashed(reason) => {
+                    self.crashed();
+                    warn!(plugin = %plugin, reason = %reason, failures = self.failures, "Plugin daemon crashed");
+                }
+            }
+        }
+    }
+
+    /// Run one process until it goes idle, is retired or crashes. Calls still
+    /// waiting for an answer then fail.
+    async fn serve(&mut self, mut process: Process, first: Call) -> Stop {
+        let timeout = self.manifest.timeout();
+        let idle = Duration::from_secs(self.manifest.daemon.idle_secs);
+        let max_concurrent = self.manifest.daemon.max_concurrent;
+
+        let mut pending: HashMap<u64, Pending> = HashMap::new();
+        let mut next_id = 0;
+        let mut next_call = Some(first);
+        let mut last_call = Instant::now();
+        let mut last_ping = Instant::now();
+        let mut open = true;
+
+        let stop = loop {
+            if let Some(call) = next_call.take() {
+                next_id += 1;
+                if let Err(e) = process.send(next_id, "run", Some(&call.request)).await {
+                    let _ = call.reply.send(Err(self.error("stopped accepting calls")));
+                    break Stop::Crashed(format!("stdin write error: {}", e));
+                }
+                last_call = Instant::now();
+                pending.insert(next_id, Pending { reply: Some(call.reply), deadline: last_call + timeout });
+            }
+            if !open && pending.is_empty() {
+                break Stop::Retired;
+            }
+
+            let in_flight = pending.values().filter(|p| p.reply.is_some()).count();
+            let wake = match pending.values().map(|p| p.deadline).min() {
+                Some(deadline) => deadline,
+                None => (last_call + idle).min(last_ping + PING_INTERVAL),
+            };
+            let event = tokio::select! {
+                call = self.queue.recv(), if open && in_flight < max_concurrent => Event::Call(call),
+                line = process.lines.recv() => Event::Line(line),
+                _ = tokio::time::sleep_until(wake) => Event::Tick,
+            };
+
+            match event {
+                Event::Call(Some(call)) => next_call = Some(call),
+                Event::Call(None) => open = false,
+                Event::Line(Some(Ok(line))) => self.answer(&mut pending, &line),
+                Event::Line(Some(Err(e))) => break Stop::Crashed(e),
+                Event::Line(None) => break Stop::Crashed("exited".into()),
+                Event::Tick => {
+                    let now = Instant::now();
+                    if let Some(late) = pending.values().find(|p| p.deadline <= now) {
+                        break Stop::Crashed(match late.reply {
+                            Some(_) => format!("timed out after {}s", timeout.as_secs()),
+                            None => "did not answer a health ping".into(),
+                        });
+                    }
+                    if !pending.is_empty() {
+                        continue;
+                    }
+                    if now >= last_call + idle {
+                        break Stop::Idle;
+                    }
+                    if now >= last_ping + PING_INTERVAL {
+                        next_id += 1;
+                        if let Err(e) = process.send(next_id, "ping", None).await {
+                            break Stop::Crashed(format!("stdin write error: {}", e));
+                        }
+                        last_ping = now;
+                        pending.insert(next_id, Pending { reply: None, deadline: now + PING_TIMEOUT });
+                    }
+                }
+            }
+        };
+
+        let crashed = match &stop {
+            Stop::Crashed(reason) => Some(reason.as_str()),
+            _ => None,
+        };
+        for reply in pending.into_values().filter_map(|p| p.reply) {
+            let _ = reply.send(Err(self.error(crashed.unwrap_or("stopped"))));
+        }
+        process.stop(crashed.is_some()).await;
+        stop
+    }
+
+    /// Hand one line of daemon output to the call it answers.
+    fn answer(&mut self, pending: &mut HashMap<u64, Pending>, line: &str) {
+        let plugin = &self.manifest.name;
+        let response: RpcResponse = match serde_json::from_str(line) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(plugin = %plugin, error = %e, "Ignoring daemon output that is not a JSON-RPC response");
+                return;
+            }
+        };
+        let Some(call) = response.id.and_then(|id| pending.remove(&id)) else {
+            warn!(plugin = %plugin, id = ?response.id, "Ignoring daemon response to an unknown id");
+            return;
+        };
+        let Some(reply) = call.reply else {
+            return; // a ping
+        };
+
+        let result = match (response.result, response.error) {
+            (_, Some(e)) => Err(self.error(&format!("returned error {}: {}", e.code, e.message))),
+            (Some(result), None) => serde_json::from_value::<PluginResponse>(result)
+                .map_err(|e| self.error(&format!("returned an invalid result: {}", e))),
+            (None, None) => Err(self.error("returned neither result nor error")),
+        };
+        if result.is_ok() {
+            self.failures = 0;
+            self.retry_at = None;
+        }
+        let _ = reply.send(result);
+    }
+
+    fn crashed(&mut self) {
+        self.failures += 1;
+        let backoff = INITIAL_BACKOFF.saturating_mul(1 << self.failures.min(8).saturating_sub(1)).min(MAX_BACKOFF);
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+
+    fn error(&self, what: &str) -> AppError {
+        AppError::PluginError(format!("Plugin '{}' {}", self.manifest.name, what))
+    }
+}
+
+// ─── Process ─────────────────────────────────────────────────────────────────
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    /// stdout split into lines; ends when the daemon closes stdout.
+    lines: mpsc::Receiver<Result<String, String>>,
+}
+
+impl Process {
+    async fn start(runner: &PluginRunner, manifest: &PluginManifest) -> Result<Self, AppError> {
+        // No CPU limit: it would add up over the daemon's whole life
+        let (child, stdin, stdout) = runner.spawn(manifest, None).await?;
+        let (tx, lines) = mpsc::channel(16);
+        tokio::spawn(read_lines(stdout, tx));
+        info!(plugin = %manifest.name, pid = child.id(), "Plugin daemon started");
+        Ok(Self { child, stdin, lines })
+    }
+
+    async fn send(&mut self, id: u64, method: &'static str, params: Option<&PluginRequest>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&RpcRequest { jsonrpc: "2.0", id, method, params })?;
+        line.push(b'\n');
+        self.stdin.write_all(&line).await?;
+        self.stdin.flush().await
+    }
+
+    /// Close stdin, which asks the daemon to exit, and kill its process group
+    /// if it hasn't within `STOP_GRACE` — or right away with `kill`.
+    async fn stop(mut self, kill: bool) {
+        drop(self.stdin);
+        if !kill && matches!(tokio::time::timeout(STOP_GRACE, self.child.wait()).await, Ok(Ok(_))) {
+            return;
+        }
+        kill_group(&mut self.child).await;
+    }
+}
+
+/// Forward each line of stdout, refusing a line over `MAX_STDOUT_BYTES`.
+async fn read_lines(stdout: ChildStdout, lines: mpsc::Sender<Result<String, String>>) {
+    let mut reader = BufReader::new(stdout);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let line = match (&mut reader).take(MAX_STDOUT_BYTES + 1).read_until(b'\n', &mut buf).await {
+            Ok(0) => return,
+            Ok(_) if buf.len() as u64 > MAX_STDOUT_BYTES => {
+                Err(format!("wrote a line over {} KiB", MAX_STDOUT_BYTES >> 10))
+            }
+            Ok(_) => Ok(String::from_utf8_lossy(&buf).into_owned()),
+            Err(e) => Err(format!("stdout read error: {}", e)),
+        };
+        let last = line.is_err();
+        if lines.send(line).await.is_err() || last {
+            return;
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+// ─── Template ────────────────────────────────────────────────────────────────
+
+/// Markdown template for a plugin's result, declared as `format` in its
+/// manifest — one string, or an array of lines joined with newlines.
+///
+/// | Tag | Renders |
+/// |---|---|
+/// | `{{path}}` | A result field; `a.b` and `items.0` reach into objects and arrays |
+/// | `{{path:.1}}` | A number with a fixed count of decimals |
+/// | `{{path\|text}}` | `text` when the field is missing, null or empty |
+/// | `{{#each path}}…{{/each}}` | The body once per array item; `{{.}}` is the item itself |
+/// | `{{#if path}}…{{else}}…{{/if}}` | The first branch unless the field is missing, null, false or `""` |
+/// | `{{#code lang}}…{{/code}}` | The body in a code fence that its content cannot close |
+///
+/// Inside `each`, fields are looked up on the item first, then outward.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Field { path: String, decimals: Option<usize>, fallback: Option<String> },
+    Each { path: String, body: Vec<Node> },
+    If { path: String, then: Vec<Node>, otherwise: Vec<Node> },
+    Code { lang: String, body: Vec<Node> },
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Source {
+            Text(String),
+            Lines(Vec<String>),
+        }
+        let source = match Source::deserialize(deserializer)? {
+            Source::Text(text) => text,
+            Source::Lines(lines) => lines.join("\n"),
+        };
+        Template::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut tags = Tags { rest: source };
+        let (nodes, end) = parse_nodes(&mut tags)?;
+        match end {
+            None => Ok(Self { nodes }),
+            Some(tag) => Err(format!("unexpected {{{{{}}}}} in format", tag)),
+        }
+    }
+
+    pub fn render(&self, data: &Value) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, &mut vec![data], &mut out);
+        out
+    }
+}
+
+// ─── Parsing ─────────────────────────────────────────────────────────────────
+
+enum Token<'a> {
+    Text(&'a str),
+    Tag(&'a str),
+}
+
+struct Tags<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tags<'a> {
+    fn next(&mut self) -> Result<Option<Token<'a>>, String> {
+        if self.rest.is_empty() {
+            return Ok(None);
+        }
+        match self.rest.find("{{") {
+            Some(0) => {
+                let end = self.rest.find("}}").ok_or("unclosed {{ in format")?;
+                let tag = self.rest[2..end].trim();
+                self.rest = &self.rest[end + 2..];
+                Ok(Some(Token::Tag(tag)))
+            }
+            Some(start) => {
+                let text = &self.rest[..start];
+                self.rest = &self.rest[start..];
+                Ok(Some(Token::Text(text)))
+            }
+            None => {
+                let text = self.rest;
+                self.rest = "";
+                Ok(Some(Token::Text(text)))
+            }
+        }
+    }
+}
+
+/// Parse up to the end of input or a closing/`else` tag, which is returned.
+fn parse_nodes<'a>(tags: &mut Tags<'a>) -> Result<(Vec<Node>, Option<&'a str>), String> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tags.next()? {
+        let tag = match token {
+            Token::Text(text) => {
+                nodes.push(Node::Text(text.to_string()));
+                continue;
+            }
+            Token::Tag(tag) => tag,
+        };
+        if tag.starts_with('/') || tag == "else" {
+            return Ok((nodes, Some(tag)));
+        }
+        let Some(block) = tag.strip_prefix('#') else {
+            nodes.push(parse_field(tag)?);
+            continue;
+        };
+
+        let (kind, arg) = block.split_once(' ').map_or((block, ""), |(k, a)| (k, a.trim()));
+        let (body, end) = parse_nodes(tags)?;
+        let node = match (kind, end) {
+            ("each", Some("/each")) => Node::Each { path: path_arg(kind, arg)?, body },
+            ("code", Some("/code")) => Node::Code { lang: arg.to_string(), body },
+            ("if", Some("/if")) => Node::If { path: path_arg(kind, arg)?, then: body, otherwise: Vec::new() },
+            ("if", Some("else")) => match parse_nodes(tags)? {
+                (otherwise, Some("/if")) => Node::If { path: path_arg(kind, arg)?, then: body, otherwise },
+                _ => return Err("{{#if}} without {{/if}} in format".into()),
+            },
+            ("each" | "code" | "if", _) => return Err(format!("{{{{#{}}}}} without {{{{/{}}}}} in format", kind, kind)),
+            _ => return Err(format!("unknown block {{{{#{}}}}} in format", kind)),
+        };
+        nodes.push(node);
+    }
+    Ok((nodes, None))
+}
+
+fn path_arg(kind: &str, arg: &str) -> Result<String, String> {
+    if arg.is_empty() {
+        Err(format!("{{{{#{}}}}} needs a field", kind))
+    } else {
+        Ok(arg.to_string())
+    }
+}
+
+/// `path`, `path:.N`, `path|fallback`, `path:.N|fallback`
+fn parse_field(tag: &str) -> Result<Node, String> {
+    let (spec, fallback) = match tag.split_once('|') {
+        Some((spec, fallback)) => (spec.trim(), Some(fallback.to_string())),
+        None => (tag, None),
+    };
+    let (path, decimals) = match spec.split_once(':') {
+        Some((path, fmt)) => {
+            let decimals = fmt
+                .trim()
+                .strip_prefix('.')
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| format!("bad number format `{}` in format", fmt))?;
+            (path.trim(), Some(decimals))
+        }
+        None => (spec, None),
+    };
+    if path.is_empty() {
+        return Err("empty {{}} in format".into());
+    }
+    Ok(Node::Field { path: path.to_string(), decimals, fallback })
+}
+
+// ─── Rendering ───────────────────────────────────────────────────────────────
+
+fn render_nodes<'a>(nodes: &'a [Node], scopes: &mut Vec<&'a Value>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Field { path, decimals, fallback } => {
+                let text = lookup(scopes, path).map(|v| display(v, *decimals)).unwrap_or_default();
+                match fallback {
+                    Some(fallback) if text.is_empty() => out.push_str(fallback),
+                    _ => out.push_str(&text),
+                }
+            }
+            Node::Each { path, body } => {
+                for item in lookup(scopes, path).and_then(Value::as_array).into_iter().flatten() {
+                    scopes.push(item);
+                    render_nodes(body, scopes, out);
+                    scopes.pop();
+                }
+            }
+            Node::If { path, then, otherwise } => {
+                let branch = if lookup(scopes, path).is_some_and(truthy) { then } else { otherwise };
+                render_nodes(branch, scopes, out);
+            }
+            Node::Code { lang, body } => {
+                let mut code = String::new();
+                render_nodes(body, scopes, &mut code);
+                let code = code.strip_prefix('\n').unwrap_or(&code);
+                let code = code.strip_suffix('\n').unwrap_or(code);
+                let fence = "`".repeat(longest_backtick_run(code).max(2) + 1);
+                out.push_str(&format!("{}{}\n{}\n{}", fence, lang, code, fence));
+            }
+        }
+    }
+}
+
+/// Resolve `path` against the innermost scope that has its first segment.
+fn lookup<'a>(scopes: &[&'a Value], path: &str) -> Option<&'a Value> {
+    if path == "." {
+        return scopes.last().copied();
+    }
+    let mut segments = path.split('.');
+    let first = segments.next()?;
+    let mut value = scopes.iter().rev().find_map(|scope| child(scope, first))?;
+    for segment in segments {
+        value = child(value, segment)?;
+    }
+    Some(value).filter(|v| !v.is_null())
+}
+
+fn child<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    }
+}
+
+fn display(value: &Value, decimals: Option<usize>) -> String {
+    match (value, decimals) {
+        (Value::String(s), _) => s.clone(),
+        (Value::Number(n), Some(d)) => format!("{:.*}", d, n.as_f64().unwrap_or(0.0)),
+        (Value::Null, _) => String::new(),
+        (other, _) => other.to_string(),
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false)) && value.as_str() != Some("")
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, data: Value) -> String {
+        Template::parse(source).unwrap().render(&data)
+    }
+
+    #[test]
+    fn if_else_nests_inside_each() {
+        let out = render(
+            "{{#each days}}{{name}}: {{#if rain}}rain {{rain:.1}}mm{{else}}dry{{/if}}\n{{/each}}",
+            json!({ "days": [{ "name": "Mon", "rain": 2.25 }, { "name": "Tue", "rain": 0 }, { "name": "Wed" }] }),
+        );
+        // 0 is truthy; only missing, null, false and "" are not.
+        assert_eq!(out, "Mon: rain 2.2mm\nTue: rain 0.0mm\nWed: dry\n");
+    }
+
+    #[test]
+    fn each_nests_inside_if_else() {
+        let source = "{{#if items}}{{#each items}}- {{.}} ({{unit}})\n{{/each}}{{else}}{{#if note}}{{note}}{{else}}none{{/if}}{{/if}}";
+        let data = json!({ "items": ["a", "b"], "unit": "kg" });
+        assert_eq!(render(source, data), "- a (kg)\n- b (kg)\n");
+        assert_eq!(render(source, json!({ "note": "empty" })), "empty");
+        assert_eq!(render(source, json!({ "items": null })), "none");
+    }
+
+    #[test]
+    fn nested_each_looks_up_fields_outward() {
+        let out = render(
+            "{{#each groups}}{{#each members}}{{name}}@{{team}}{{#if lead}}*{{/if}} {{/each}}{{/each}}",
+            json!({ "groups": [
+                { "team": "x", "members": [{ "name": "a", "lead": true }, { "name": "b" }] },
+                { "team": "y", "members": [{ "name": "c", "team": "z" }] },
+            ] }),
+        );
+        assert_eq!(out, "a@x* b@x c@z ");
+    }
+
+    #[test]
+    fn mismatched_blocks_are_rejected() {
+        assert!(Template::parse("{{#if a}}{{#each b}}{{/if}}{{/each}}").is_err());
+        assert!(Template::parse("{{#if a}}{{else}}{{else}}{{/if}}").is_err());
+        assert!(Template::parse("{{#each a}}").is_err());
+        assert!(Template::parse("{{/if}}").is_err());
+        assert!(Template::parse("{{else}}").is_err());
+    }
+}
@@ -1,14 +1,37 @@
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
-use std::process::{Command, Stdio};
-use std::io::Write;
+use std::process::{ExitStatus, Stdio};
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout};
 use tracing::{info, warn, debug};
 
 use crate::errors::AppError;
-use crate::security::DeviceIdentity;
+use crate::security::{sha256, SignaturePolicy, TrustStore};
 
-const PLUGIN_TIMEOUT_SECS: u64 = 10;
+mod daemon;
+mod format;
+mod params;
+mod reload;
+mod sandbox;
+
+pub use daemon::{DaemonOptions, DaemonPool, PluginMode};
+pub use format::Template;
+pub use params::ParamSpec;
+pub use reload::{spawn_watchers, PluginHandle, ReloadReport};
+pub use sandbox::{probe as probe_sandbox, Capabilities};
+
+/// Run time allowed when neither the manifest nor the config sets one.
+pub const PLUGIN_TIMEOUT_SECS: u64 = 10;
+/// Upper bound for a manifest's `timeout_secs`.
+pub const MAX_PLUGIN_TIMEOUT_SECS: u64 = 120;
+/// A response larger than this is refused and the plugin killed.
+const MAX_STDOUT_BYTES: u64 = 1 << 20;
+/// Stderr beyond this is read and discarded instead of logged.
+const MAX_STDERR_BYTES: u64 = 16 << 10;
+/// Tool argument that picks one of a plugin's actions in agent mode.
+const TOOL_ACTION_ARG: &str = "action";
 
 // ─── Manifest ────────────────────────────────────────────────────────────────
 
@@ -24,36 +47,279 @@ pub struct PluginManifest {
     pub commands: Vec<String>,
     /// Which action string to send when the command is invoked
     pub default_action: String,
+    /// Commands and sub-commands with their own action, e.g.
+    /// {"file list": "list", "file tail": "tail"}. Each key starts with one of `commands`.
+    #[serde(default)]
+    pub actions: HashMap<String, String>,
     /// If true, everything after the command is forwarded as {"args": "..."}
-    /// If false, payload is always {}
     #[serde(default)]
     pub payload_from_args: bool,
+    /// Typed arguments, parsed by broai and sent as payload fields of the same name.
+    #[serde(default)]
+    pub parameters: Vec<ParamSpec>,
+    /// How successful results are shown in chat; pretty-printed JSON if unset.
+    #[serde(default)]
+    pub format: Option<Template>,
+    /// What the sandbox lets the plugin reach besides the base system.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Seconds before the plugin is killed; `plugins.timeout_secs` from the config if unset.
+    /// For a daemon, how long one call may take.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Start the binary per call (`oneshot`) or keep it running (`daemon`).
+    #[serde(default)]
+    pub mode: PluginMode,
+    /// Concurrency and idle shutdown of a `daemon` plugin.
+    #[serde(default)]
+    pub daemon: DaemonOptions,
+    /// SHA-256 of the binary as verified at load time, re-checked before each run.
+    #[serde(skip)]
+    pub binary_sha256: Option<[u8; 32]>,
+}
+
+impl PluginManifest {
+    /// Validate the manifest and normalise `actions` keys to lowercase,
+    /// single-spaced routes.
+    pub(crate) fn check(&mut self) -> Result<(), String> {
+        params::check(&self.parameters)?;
+        self.capabilities.check()?;
+        self.daemon.check()?;
+        if let Some(secs) = self.timeout_secs {
+            if !(1..=MAX_PLUGIN_TIMEOUT_SECS).contains(&secs) {
+                return Err(format!("timeout_secs must be between 1 and {}", MAX_PLUGIN_TIMEOUT_SECS));
+            }
+        }
+
+        if self.parameters.iter().any(|p| p.name == TOOL_ACTION_ARG) && self.tool_routes().len() > 1 {
+            return Err(format!("parameter `{}` is reserved for choosing between actions", TOOL_ACTION_ARG));
+        }
+
+        let mut actions = HashMap::with_capacity(self.actions.len());
+        for (route, action) in &self.actions {
+            let route = route.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+            let first = route.split(' ').next().unwrap_or("");
+            if !self.commands.iter().any(|c| c.eq_ignore_ascii_case(first)) {
+                return Err(format!("action route `{}` does not start with one of its commands", route));
+            }
+            if action.is_empty() {
+                return Err(format!("empty action for `{}`", route));
+            }
+            actions.insert(route, action.clone());
+        }
+        self.actions = actions;
+        Ok(())
+    }
+
+    /// How long one run may take before the plugin is killed.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(PLUGIN_TIMEOUT_SECS))
+    }
+
+    /// Action to send for a registered route (`file`, `file list`, ...).
+    pub fn action_for(&self, route: &str) -> &str {
+        self.actions.get(route).unwrap_or(&self.default_action)
+    }
+
+    /// Build the request payload for `/command args`. On bad input the error
+    /// is a message for the user that ends with the command's usage line.
+    pub fn payload(&self, command: &str, args: &str) -> Result<serde_json::Value, String> {
+        let fields = if self.parameters.is_empty() {
+            serde_json::Map::new()
+        } else {
+            params::parse(&self.parameters, args)
+                .map_err(|e| format!("{}\nUsage: {}", e, self.usage(command)))?
+        };
+        let raw = (self.payload_from_args && !args.is_empty()).then_some(args);
+        Ok(build_payload(command, raw, fields))
+    }
+
+    /// One route per distinct action, the default first, preferring the
+    /// shortest route to each: what a tool call can choose from.
+    pub fn tool_routes(&self) -> Vec<(&str, String)> {
+        let mut routes = Vec::new();
+        if let Some(first) = self.commands.first() {
+            routes.push((self.default_action.as_str(), first.to_lowercase()));
+        }
+        let mut keyed: Vec<(&String, &String)> = self.actions.iter().collect();
+        keyed.sort_by(|a, b| (a.0.len(), a.0).cmp(&(b.0.len(), b.0)));
+        for (route, action) in keyed {
+            if !routes.iter().any(|(a, _)| a == action) {
+                routes.push((action.as_str(), route.to_lowercase()));
+            }
+        }
+        routes
+    }
+
+    /// Route and action for a tool call. With several actions, the `action`
+    /// argument picks one and is taken out of `arguments`.
+    pub fn tool_route(&self, arguments: &mut serde_json::Value) -> Result<(String, &str), String> {
+        let mut routes = self.tool_routes();
+        if routes.is_empty() {
+            return Err("plugin has no commands".into());
+        }
+        let chosen = match arguments.as_object_mut().filter(|_| routes.len() > 1).and_then(|a| a.remove(TOOL_ACTION_ARG)) {
+            None => 0,
+            Some(value) => {
+                let name = value.as_str().unwrap_or_default();
+                routes.iter().position(|(action, _)| *action == name).ok_or_else(|| {
+                    let known: Vec<&str> = routes.iter().map(|(action, _)| *action).collect();
+                    format!("unknown action {}; use one of {}", value, known.join(", "))
+                })?
+            }
+        };
+        let (action, route) = routes.swap_remove(chosen);
+        Ok((route, action))
+    }
+
+    /// Build the request payload for a tool call with JSON `arguments`.
+    pub fn tool_payload(&self, command: &str, arguments: &serde_json::Value) -> Result<serde_json::Value, String> {
+        if self.parameters.is_empty() {
+            let raw = arguments["args"].as_str().filter(|a| self.payload_from_args && !a.is_empty());
+            return Ok(build_payload(command, raw, serde_json::Map::new()));
+        }
+        let fields = params::from_json(&self.parameters, arguments)?;
+        Ok(build_payload(command, None, fields))
+    }
+
+    /// Chat text for a successful response: the plugin's own `markdown` if it
+    /// sent one, else the manifest's `format`, else the raw JSON result.
+    pub fn render(&self, response: &PluginResponse) -> String {
+        if let Some(markdown) = &response.markdown {
+            return markdown.clone();
+        }
+        match &self.format {
+            Some(template) => template.render(&response.result),
+            None => serde_json::to_string_pretty(&response.result).unwrap_or_else(|_| response.result.to_string()),
+        }
+    }
+
+    /// One-line usage, e.g. `/file <path> [lines]`.
+    pub fn usage(&self, command: &str) -> String {
+        if self.parameters.is_empty() && self.payload_from_args {
+            format!("/{} [args]", command)
+        } else {
+            params::usage(command, &self.parameters)
+        }
+    }
+
+    /// JSON Schema of the arguments the plugin takes when called as a tool,
+    /// with an `action` enum when it has more than one.
+    pub fn tool_parameters(&self) -> serde_json::Value {
+        let mut schema = self.argument_schema();
+        let routes = self.tool_routes();
+        if routes.len() > 1 {
+            let actions: Vec<&str> = routes.iter().map(|(action, _)| *action).collect();
+            let commands: Vec<String> = routes.iter().map(|(_, route)| format!("/{}", route)).collect();
+            schema["properties"][TOOL_ACTION_ARG] = serde_json::json!({
+                "type": "string",
+                "enum": actions,
+                "default": self.default_action,
+                "description": format!("What to do, like {}", commands.join(", ")),
+            });
+        }
+        schema
+    }
+
+    fn argument_schema(&self) -> serde_json::Value {
+        if !self.parameters.is_empty() {
+            params::json_schema(&self.parameters)
+        } else if self.payload_from_args {
+            let command = self.commands.first().map(String::as_str).unwrap_or("");
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "args": {
+                        "type": "string",
+                        "description": format!("Arguments as typed after /{}", command),
+                    }
+                },
+                "required": ["args"],
+            })
+        } else {
+            serde_json::json!({ "type": "object", "properties": {} })
+        }
+    }
+}
+
+/// `{"command": ..., "args": ..., <typed fields>}`
+fn build_payload(
+    command: &str,
+    raw: Option<&str>,
+    mut fields: serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Value {
+    fields.insert("command".into(), command.into());
+    if let Some(args) = raw {
+        fields.insert("args".into(), args.into());
+    }
+    serde_json::Value::Object(fields)
 }
 
 // ─── Registry ────────────────────────────────────────────────────────────────
 
-/// Loaded at startup; maps command → manifest.
-/// Never changes at runtime — restart broai to pick up new plugins.
+/// Maps command → manifest. One registry is an immutable snapshot of the
+/// plugin directory; reloads build a new one and swap it in (see `PluginHandle`).
 #[derive(Debug, Clone)]
 pub struct PluginRegistry {
-    /// command (lowercase) → manifest
-    entries: std::collections::HashMap<String, PluginManifest>,
+    /// route (lowercase, e.g. "file" or "file list") → manifest
+    entries: HashMap<String, PluginManifest>,
+    /// Words in the longest route, bounding the prefix search in `resolve`.
+    max_route_words: usize,
+    /// plugin name → what was on disk when it was registered
+    sources: BTreeMap<String, PluginSource>,
     plugin_dir: PathBuf,
+    policy: SignaturePolicy,
+    settings: PluginSettings,
+}
+
+/// Device-wide plugin settings from the config file, applied to every manifest
+/// at load time.
+#[derive(Debug, Clone, Default)]
+pub struct PluginSettings {
+    /// Timeout for manifests without `timeout_secs`.
+    pub timeout_secs: u64,
+    /// Replaces a plugin's `fs_read` capability, by plugin name.
+    pub fs_read: BTreeMap<String, Vec<PathBuf>>,
+}
+
+/// Manifest text, binary hash and signer, to tell which plugins a reload changed.
+#[derive(Debug, Clone, PartialEq)]
+struct PluginSource {
+    version: String,
+    manifest: String,
+    binary_sha256: [u8; 32],
+    publisher: Option<String>,
+}
+
+/// A `/command` matched to a plugin.
+#[derive(Debug, Clone, Copy)]
+pub struct Route<'a> {
+    pub manifest: &'a PluginManifest,
+    /// The matched command or sub-command, e.g. `file list`.
+    pub command: &'a str,
+    pub action: &'a str,
+    /// Whatever followed the matched words.
+    pub args: &'a str,
 }
 
 impl PluginRegistry {
-    /// Scan `plugin_dir` for *.json manifests and build the registry.
-    pub fn load(plugin_dir: &str) -> Self {
+    /// Scan `plugin_dir` for *.json manifests and build the registry, keeping
+    /// only plugins whose signature `policy` accepts, with `settings` applied.
+    pub fn load(plugin_dir: &str, policy: &SignaturePolicy, settings: &PluginSettings) -> Self {
         let dir = PathBuf::from(plugin_dir);
-        let mut entries = std::collections::HashMap::new();
+        let mut entries = HashMap::new();
+        let mut sources = BTreeMap::new();
+        let policy = policy.clone();
+        let settings = settings.clone();
 
         let read = match std::fs::read_dir(&dir) {
             Ok(r) => r,
             Err(e) => {
                 warn!(dir = %plugin_dir, error = %e, "Cannot read plugin directory");
-                return Self { entries, plugin_dir: dir };
+                return Self { entries, max_route_words: 1, sources, plugin_dir: dir, policy, settings };
             }
         };
+        let trust = TrustStore::load(&policy.trust_dir);
 
         for entry in read.flatten() {
             let path = entry.path();
@@ -63,7 +329,12 @@ impl PluginRegistry {
 
             match std::fs::read_to_string(&path) {
                 Ok(text) => match serde_json::from_str::<PluginManifest>(&text) {
-                    Ok(manifest) => {
+                    Ok(mut manifest) => {
+                        if let Err(e) = manifest.check() {
+                            warn!(manifest = %path.display(), error = %e, "Invalid plugin manifest — skipping");
+                            continue;
+                        }
+
                         // Check the binary exists alongside the manifest
                         let bin = dir.join(&manifest.name);
                         if !bin.exists() {
@@ -75,15 +346,47 @@ impl PluginRegistry {
                             continue;
                         }
 
+                        let binary = match std::fs::read(&bin) {
+                            Ok(b) => b,
+                            Err(e) => {
+                                warn!(binary = %bin.display(), error = %e, "Cannot read plugin binary — skipping");
+                                continue;
+                            }
+                        };
+                        let publisher = match check_signature(&dir, &manifest.name, &binary, &text, &trust, &policy) {
+                            Ok(publisher) => publisher,
+                            Err(e) => {
+                                warn!(plugin = %manifest.name, error = %e, "Plugin signature rejected — skipping");
+                                continue;
+                            }
+                        };
+                        manifest.binary_sha256 = Some(sha256(&binary));
+                        manifest.timeout_secs.get_or_insert(settings.timeout_secs);
+                        if let Some(paths) = settings.fs_read.get(&manifest.name) {
+                            info!(plugin = %manifest.name, fs_read = ?paths, "Read access set by config");
+                            manifest.capabilities.fs_read = paths.clone();
+                        }
+
                         info!(
-                            plugin   = %manifest.name,
-                            commands = ?manifest.commands,
+                            plugin    = %manifest.name,
+                            version   = %manifest.version,
+                            commands  = ?manifest.commands,
+                            publisher = publisher.as_deref().unwrap_or("unsigned"),
                             "Registered plugin"
                         );
 
                         for cmd in &manifest.commands {
                             entries.insert(cmd.to_lowercase(), manifest.clone());
                         }
+                        for route in manifest.actions.keys() {
+                            entries.insert(route.clone(), manifest.clone());
+                        }
+                        sources.insert(manifest.name.clone(), PluginSource {
+                            version: manifest.version.clone(),
+                            manifest: text,
+                            binary_sha256: sha256(&binary),
+                            publisher,
+                        });
                     }
                     Err(e) => warn!(file = %path.display(), error = %e, "Invalid plugin manifest JSON"),
                 },
@@ -91,28 +394,110 @@ impl PluginRegistry {
             }
         }
 
+        let max_route_words = entries.keys().map(|r| r.split(' ').count()).max().unwrap_or(1);
         info!(total_commands = entries.len(), "Plugin registry loaded");
-        Self { entries, plugin_dir: dir }
+        Self { entries, max_route_words, sources, plugin_dir: dir, policy, settings }
+    }
+
+    /// Plugins added, removed or changed (manifest, binary or signer) in `newer`.
+    pub fn diff(&self, newer: &PluginRegistry) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        for (name, source) in &newer.sources {
+            match self.sources.get(name) {
+                None => report.added.push(name.clone()),
+                Some(old) if old != source => report.changed.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        report.removed = self.sources.keys().filter(|n| !newer.sources.contains_key(*n)).cloned().collect();
+        report.plugins = newer.sources.len();
+        report
     }
 
-    /// Returns the manifest for a slash-command, if registered.
-    pub fn resolve(&self, command: &str) -> Option<&PluginManifest> {
-        self.entries.get(&command.to_lowercase())
+    /// Registered version of a plugin.
+    pub fn version_of(&self, name: &str) -> Option<&str> {
+        self.sources.get(name).map(|s| s.version.as_str())
     }
 
-    /// List all registered commands (for /help or debug)
-    pub fn commands(&self) -> Vec<(&str, &str)> {
-        let mut list: Vec<(&str, &str)> = self.entries
+    /// Match `/command args` to a plugin, preferring the longest registered
+    /// route: with `file` and `file list` registered, `/file list /tmp` runs
+    /// the `list` action with args `/tmp`.
+    pub fn resolve<'a>(&'a self, command: &str, args: &'a str) -> Option<Route<'a>> {
+        let mut route = command.to_lowercase();
+        let mut rest = args.trim_start();
+        let mut best = self.route(&route, rest);
+
+        for _ in 1..self.max_route_words {
+            let Some(word) = rest.split_whitespace().next() else { break };
+            route.push(' ');
+            route.push_str(&word.to_lowercase());
+            rest = rest[word.len()..].trim_start();
+            best = self.route(&route, rest).or(best);
+        }
+        best
+    }
+
+    fn route<'a>(&'a self, route: &str, args: &'a str) -> Option<Route<'a>> {
+        let (command, manifest) = self.entries.get_key_value(route)?;
+        Some(Route { manifest, command, action: manifest.action_for(command), args })
+    }
+
+    /// List all registered commands with their manifest (for /help or debug)
+    pub fn commands(&self) -> Vec<(&str, &PluginManifest)> {
+        let mut list: Vec<(&str, &PluginManifest)> = self.entries
             .iter()
-            .map(|(cmd, m)| (cmd.as_str(), m.description.as_str()))
+            .map(|(cmd, m)| (cmd.as_str(), m))
             .collect();
         list.sort_by_key(|(cmd, _)| *cmd);
         list
     }
 
+    /// Every registered plugin once, sorted by name.
+    pub fn manifests(&self) -> Vec<&PluginManifest> {
+        let mut list: Vec<&PluginManifest> = self.entries.values().collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list.dedup_by(|a, b| a.name == b.name);
+        list
+    }
+
     pub fn plugin_dir(&self) -> &Path {
         &self.plugin_dir
     }
+
+    pub fn policy(&self) -> &SignaturePolicy {
+        &self.policy
+    }
+
+    pub fn settings(&self) -> &PluginSettings {
+        &self.settings
+    }
+}
+
+/// Verify `<name>.sig` over the plugin's binary and manifest text.
+///
+/// Returns the signing publisher, or `None` for an unsigned plugin that the
+/// policy lets through. A signature that is present but does not verify is
+/// always an error, even when unsigned plugins are allowed.
+pub(crate) fn check_signature(
+    dir: &Path,
+    name: &str,
+    binary: &[u8],
+    manifest: &str,
+    trust: &TrustStore,
+    policy: &SignaturePolicy,
+) -> Result<Option<String>, String> {
+    let sig_path = dir.join(format!("{}.sig", name));
+    match std::fs::read_to_string(&sig_path) {
+        Ok(sig) => trust.verify(binary, manifest.as_bytes(), &sig).map(|p| Some(p.to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if policy.require_signed {
+                return Err(format!("{} is missing and unsigned plugins are not allowed", sig_path.display()));
+            }
+            warn!(plugin = %name, "Plugin is unsigned — set REQUIRE_SIGNED_PLUGINS to refuse it");
+            Ok(None)
+        }
+        Err(e) => Err(format!("cannot read {}: {}", sig_path.display(), e)),
+    }
 }
 
 // ─── Request / Response ──────────────────────────────────────────────────────
@@ -128,25 +513,83 @@ pub struct PluginResponse {
     pub success: bool,
     pub result: serde_json::Value,
     pub error: Option<String>,
+    /// Pre-rendered chat text, shown instead of formatting `result`.
+    #[serde(default)]
+    pub markdown: Option<String>,
 }
 
 // ─── Runner ──────────────────────────────────────────────────────────────────
 
+#[derive(Clone)]
 pub struct PluginRunner {
     plugin_dir: PathBuf,
+    /// Run each plugin in a Linux sandbox limited to its manifest's capabilities.
+    sandbox: bool,
 }
 
 impl PluginRunner {
-    pub fn new(plugin_dir: String) -> Self {
-        Self { plugin_dir: PathBuf::from(plugin_dir) }
+    pub fn new(plugin_dir: String, sandbox: bool) -> Self {
+        Self { plugin_dir: PathBuf::from(plugin_dir), sandbox }
     }
 
-    pub fn run(
+    /// Send `request` to a one-shot plugin and parse its response. The plugin
+    /// leads its own process group, which is killed as a whole on timeout or
+    /// error so nothing it started outlives it.
+    pub async fn run(
         &self,
-        plugin_name: &str,
+        manifest: &PluginManifest,
         request: &PluginRequest,
-        _device: &DeviceIdentity,
     ) -> Result<PluginResponse, AppError> {
+        let plugin_name = manifest.name.as_str();
+        let input = serde_json::to_string(request)
+            .map_err(|e| AppError::PluginError(format!("Serialize error: {}", e)))?;
+
+        debug!(plugin = %plugin_name, input = %input, "Launching plugin");
+
+        let timeout = manifest.timeout();
+        let (mut child, mut stdin, stdout) = self.spawn(manifest, Some(timeout)).await?;
+
+        let exchange = async {
+            // Moving `stdin` in closes it once written, so the plugin sees EOF
+            let write = async move {
+                stdin.write_all(input.as_bytes()).await
+                    .map_err(|e| AppError::PluginError(format!("STDIN write error: {}", e)))
+            };
+            let ((), output) = tokio::try_join!(write, read_stdout(plugin_name, stdout))?;
+            let status = child.wait().await
+                .map_err(|e| AppError::PluginError(format!("wait() error: {}", e)))?;
+            Ok::<_, AppError>((status, output))
+        };
+        let outcome = tokio::time::timeout(timeout, exchange).await;
+
+        let (status, output) = match outcome {
+            Ok(Ok(done)) => done,
+            Ok(Err(e)) => {
+                kill_group(&mut child).await;
+                return Err(e);
+            }
+            Err(_) => {
+                kill_group(&mut child).await;
+                return Err(AppError::PluginError(format!(
+                    "Plugin '{}' timed out after {}s",
+                    plugin_name,
+                    timeout.as_secs()
+                )));
+            }
+        };
+
+        parse_response(plugin_name, status, &output)
+    }
+
+    /// Start the plugin in its own process group, with stderr going to the
+    /// log, once its binary is confirmed to be the one verified at load. The
+    /// bytes that were checked are the ones that run, never the file again.
+    pub(crate) async fn spawn(
+        &self,
+        manifest: &PluginManifest,
+        cpu_limit: Option<Duration>,
+    ) -> Result<(Child, ChildStdin, ChildStdout), AppError> {
+        let plugin_name = manifest.name.as_str();
         let binary = self.plugin_dir.join(plugin_name);
 
         if !binary.exists() {
@@ -156,52 +599,93 @@ impl PluginRunner {
             )));
         }
 
-        let input = serde_json::to_string(request)
-            .map_err(|e| AppError::PluginError(format!("Serialize error: {}", e)))?;
-
-        debug!(plugin = %plugin_name, input = %input, "Launching plugin");
+        // The binary must still be the one verified when the registry was loaded
+        let image = tokio::fs::read(&binary).await?;
+        if manifest.binary_sha256 != Some(sha256(&image)) {
+            warn!(plugin = %plugin_name, "Plugin binary changed since it was verified — refusing to run");
+            return Err(AppError::SecurityError(format!(
+                "Plugin '{}' binary changed since it was verified; reload plugins",
+                plugin_name
+            )));
+        }
 
-        let mut child = Command::new(&binary)
+        let command = if self.sandbox {
+            sandbox::command(&binary, image, &manifest.capabilities, cpu_limit)
+                .map_err(|e| AppError::PluginError(format!("Cannot sandbox '{}': {}", plugin_name, e)))?
+        } else {
+            sandbox::unsandboxed_command(&binary, &image)
+                .map_err(|e| AppError::PluginError(format!("Cannot load '{}': {}", plugin_name, e)))?
+        };
+        let mut command = tokio::process::Command::from(command);
+        // Plugins that check paths themselves, like the file reader, read their grants here.
+        if let Ok(dirs) = std::env::join_paths(&manifest.capabilities.fs_read) {
+            if !dirs.is_empty() {
+                command.env("BROAI_FS_READ", dirs);
+            }
+        }
+        command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
             .spawn()
             .map_err(|e| AppError::PluginError(format!("Failed to spawn '{}': {}", plugin_name, e)))?;
 
-        // Write request to STDIN
-        if let Some(stdin) = child.stdin.take() {
-            let mut stdin = stdin;
-            stdin.write_all(input.as_bytes())
-                .map_err(|e| AppError::PluginError(format!("STDIN write error: {}", e)))?;
-        }
-
-        // Wait with timeout
-        let deadline = std::time::Instant::now() + Duration::from_secs(PLUGIN_TIMEOUT_SECS);
-        loop {
-            match child.try_wait() {
-                Ok(Some(_)) => break,
-                Ok(None) => {
-                    if std::time::Instant::now() > deadline {
-                        let _ = child.kill();
-                        return Err(AppError::PluginError(format!(
-                            "Plugin '{}' timed out after {}s",
-                            plugin_name, PLUGIN_TIMEOUT_SECS
-                        )));
-                    }
-                    std::thread::sleep(Duration::from_millis(50));
-                }
-                Err(e) => return Err(AppError::PluginError(format!("wait() error: {}", e))),
-            }
-        }
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        tokio::spawn(log_stderr(plugin_name.to_string(), child.stderr.take().expect("stderr is piped")));
+        Ok((child, stdin, stdout))
+    }
+}
 
-        let output = child.wait_with_output()
-            .map_err(|e| AppError::PluginError(format!("Output read error: {}", e)))?;
+/// Read the whole response, refusing one over `MAX_STDOUT_BYTES`.
+async fn read_stdout(plugin_name: &str, stdout: ChildStdout) -> Result<Vec<u8>, AppError> {
+    let mut output = Vec::new();
+    stdout.take(MAX_STDOUT_BYTES + 1).read_to_end(&mut output).await
+        .map_err(|e| AppError::PluginError(format!("Output read error: {}", e)))?;
+    if output.len() as u64 > MAX_STDOUT_BYTES {
+        return Err(AppError::PluginError(format!(
+            "Plugin '{}' wrote more than {} KiB to stdout",
+            plugin_name,
+            MAX_STDOUT_BYTES >> 10
+        )));
+    }
+    Ok(output)
+}
+
+/// Log each stderr line, up to `MAX_STDERR_BYTES`, then drain the rest so
+/// the plugin never blocks on a full pipe.
+async fn log_stderr(plugin_name: String, stderr: ChildStderr) {
+    let mut lines = BufReader::new(stderr).take(MAX_STDERR_BYTES).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        warn!(plugin = %plugin_name, stderr = %line, "Plugin wrote to stderr");
+    }
+    let mut rest = lines.into_inner().into_inner();
+    if let Ok(skipped @ 1..) = tokio::io::copy(&mut rest, &mut tokio::io::sink()).await {
+        warn!(plugin = %plugin_name, bytes = skipped, "Plugin stderr truncated");
+    }
+}
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        serde_json::from_str::<PluginResponse>(&stdout)
-            .map_err(|e| AppError::PluginError(format!(
-                "Plugin '{}' returned invalid JSON: {} | raw: {}",
-                plugin_name, e, stdout.chars().take(200).collect::<String>()
-            )))
+/// Kill the plugin's process group, then reap the plugin. Killing before
+/// reaping matters: until then its pid cannot be reused as a group id.
+async fn kill_group(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: killpg has no memory effects; the group is still ours
+        // because the leader has not been reaped yet.
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
     }
+    let _ = child.kill().await;
+}
+
+fn parse_response(plugin_name: &str, status: ExitStatus, output: &[u8]) -> Result<PluginResponse, AppError> {
+    let stdout = String::from_utf8_lossy(output);
+    serde_json::from_str::<PluginResponse>(&stdout)
+        .map_err(|e| AppError::PluginError(format!(
+            "Plugin '{}' returned invalid JSON ({}): {} | raw: {}",
+            plugin_name, status, e, stdout.chars().take(200).collect::<String>()
+        )))
 }
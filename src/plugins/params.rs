@@ -0,0 +1,306 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+// ─── Schema ──────────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    /// Free text. The last string parameter takes the rest of the line.
+    #[default]
+    String,
+    /// Whole number, sent as a JSON number.
+    Int,
+    /// One of `values`, matched case-insensitively.
+    Enum,
+    /// Absolute filesystem path.
+    Path,
+}
+
+/// One named argument of a plugin command, declared in its manifest:
+///
+/// ```json
+/// { "name": "lines", "type": "int", "default": 20, "description": "Lines to show" }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParamSpec {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ParamType,
+    #[serde(default)]
+    pub required: bool,
+    /// Sent when the argument is left out.
+    #[serde(default)]
+    pub default: Option<Value>,
+    /// Allowed values of an `enum` parameter.
+    #[serde(default)]
+    pub values: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl ParamSpec {
+    /// Convert one argument typed after the command.
+    fn convert(&self, raw: &str) -> Result<Value, String> {
+        match self.kind {
+            ParamType::String => Ok(Value::String(raw.to_string())),
+            ParamType::Int => raw
+                .parse::<i64>()
+                .map(Value::from)
+                .map_err(|_| format!("`{}` must be a whole number, got `{}`", self.name, raw)),
+            ParamType::Enum => self
+                .values
+                .iter()
+                .find(|v| v.eq_ignore_ascii_case(raw))
+                .map(|v| Value::String(v.clone()))
+                .ok_or_else(|| format!("`{}` must be one of {}, got `{}`", self.name, self.values.join(", "), raw)),
+            ParamType::Path => {
+                if raw.starts_with('/') && !raw.contains('\0') {
+                    Ok(Value::String(raw.to_string()))
+                } else {
+                    Err(format!("`{}` must be an absolute path, got `{}`", self.name, raw))
+                }
+            }
+        }
+    }
+
+    /// Convert one argument of a tool call. Numbers may arrive as strings.
+    fn convert_json(&self, value: &Value) -> Result<Value, String> {
+        match (self.kind, value) {
+            (ParamType::Int, Value::Number(n)) if n.is_i64() => Ok(value.clone()),
+            (_, Value::String(s)) => self.convert(s),
+            _ => Err(format!("`{}` has the wrong type", self.name)),
+        }
+    }
+
+    /// How the parameter is shown in usage lines: `<city>`, `[lines]`, `[on|off|read]`.
+    fn placeholder(&self) -> String {
+        let inner = match self.kind {
+            ParamType::Enum => self.values.join("|"),
+            _ => self.name.clone(),
+        };
+        if self.required { format!("<{}>", inner) } else { format!("[{}]", inner) }
+    }
+}
+
+/// Check a manifest's parameter list once, at load time.
+pub fn check(specs: &[ParamSpec]) -> Result<(), String> {
+    for (i, spec) in specs.iter().enumerate() {
+        if spec.name.is_empty() || !spec.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid parameter name `{}`", spec.name));
+        }
+        if specs[..i].iter().any(|s| s.name == spec.name) {
+            return Err(format!("parameter `{}` declared twice", spec.name));
+        }
+        if spec.kind == ParamType::Enum && spec.values.is_empty() {
+            return Err(format!("enum parameter `{}` has no values", spec.name));
+        }
+        if spec.required && spec.default.is_some() {
+            return Err(format!("required parameter `{}` cannot have a default", spec.name));
+        }
+        if let Some(default) = &spec.default {
+            spec.convert_json(default).map_err(|e| format!("bad default: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+// ─── Parsing ─────────────────────────────────────────────────────────────────
+
+/// Parse the text after a `/command` against `specs`.
+///
+/// Arguments are matched by position, or by name as `name=value`; quotes group
+/// words (`path="/tmp/my notes.txt"`). If more words are left than parameters,
+/// a trailing string parameter takes all of them, so `/remind buy milk` works
+/// unquoted. Left-out optional parameters get their default.
+pub fn parse(specs: &[ParamSpec], args: &str) -> Result<Map<String, Value>, String> {
+    let mut values = Map::new();
+    let mut positional = Vec::new();
+
+    for word in split_words(args) {
+        if let Some((key, raw)) = word.split_once('=') {
+            if let Some(spec) = specs.iter().find(|s| s.name == key) {
+                if values.insert(spec.name.clone(), spec.convert(raw)?).is_some() {
+                    return Err(format!("`{}` given twice", spec.name));
+                }
+                continue;
+            }
+        }
+        positional.push(word);
+    }
+
+    let open: Vec<&ParamSpec> = specs.iter().filter(|s| !values.contains_key(&s.name)).collect();
+    let mut words = positional.into_iter();
+    for (i, spec) in open.iter().enumerate() {
+        let Some(word) = words.next() else { break };
+        let value = if i + 1 == open.len() && spec.kind == ParamType::String {
+            std::iter::once(word).chain(words.by_ref()).collect::<Vec<_>>().join(" ")
+        } else {
+            word
+        };
+        values.insert(spec.name.clone(), spec.convert(&value)?);
+    }
+    if let Some(extra) = words.next() {
+        return Err(format!("unexpected argument `{}`", extra));
+    }
+
+    fill_defaults(specs, values)
+}
+
+/// Check the JSON arguments of a tool call against `specs`.
+pub fn from_json(specs: &[ParamSpec], arguments: &Value) -> Result<Map<String, Value>, String> {
+    let mut values = Map::new();
+    match arguments {
+        Value::Object(given) => {
+            for (key, value) in given {
+                let spec = specs
+                    .iter()
+                    .find(|s| &s.name == key)
+                    .ok_or_else(|| format!("unknown argument `{}`", key))?;
+                values.insert(spec.name.clone(), spec.convert_json(value)?);
+            }
+        }
+        Value::Null => {}
+        _ => return Err("arguments must be a JSON object".into()),
+    }
+    fill_defaults(specs, values)
+}
+
+fn fill_defaults(specs: &[ParamSpec], mut values: Map<String, Value>) -> Result<Map<String, Value>, String> {
+    for spec in specs {
+        if values.contains_key(&spec.name) {
+            continue;
+        }
+        if spec.required {
+            return Err(format!("missing `{}`", spec.name));
+        }
+        if let Some(default) = &spec.default {
+            values.insert(spec.name.clone(), spec.convert_json(default)?);
+        }
+    }
+    Ok(values)
+}
+
+/// Split on whitespace. A word that starts with a quote, or whose value does
+/// (`name="..."`), runs to the matching quote; an unmatched quote is literal.
+fn split_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let mut word = String::new();
+        while let Some(c) = rest.chars().next().filter(|c| !c.is_whitespace()) {
+            let value_start = word.is_empty() || (word.ends_with('=') && word.matches('=').count() == 1);
+            if value_start && (c == '"' || c == '\'') {
+                if let Some(end) = rest[1..].find(c) {
+                    word.push_str(&rest[1..1 + end]);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+            word.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        words.push(word);
+        rest = rest.trim_start();
+    }
+    words
+}
+
+// ─── Presentation ────────────────────────────────────────────────────────────
+
+/// `/file <path> [lines]`
+pub fn usage(command: &str, specs: &[ParamSpec]) -> String {
+    std::iter::once(format!("/{}", command))
+        .chain(specs.iter().map(ParamSpec::placeholder))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// JSON Schema for the parameters, used when a plugin is offered as a tool.
+pub fn json_schema(specs: &[ParamSpec]) -> Value {
+    let properties: Map<String, Value> = specs
+        .iter()
+        .map(|spec| {
+            let mut prop = match spec.kind {
+                ParamType::String => json!({ "type": "string" }),
+                ParamType::Int => json!({ "type": "integer" }),
+                ParamType::Enum => json!({ "type": "string", "enum": spec.values }),
+                ParamType::Path => json!({ "type": "string", "description": "Absolute path" }),
+            };
+            if !spec.description.is_empty() {
+                prop["description"] = Value::String(spec.description.clone());
+            }
+            if let Some(default) = &spec.default {
+                prop["default"] = default.clone();
+            }
+            (spec.name.clone(), prop)
+        })
+        .collect();
+    let required: Vec<&str> = specs.iter().filter(|s| s.required).map(|s| s.name.as_str()).collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn specs(value: Value) -> Vec<ParamSpec> {
+        let specs: Vec<ParamSpec> = serde_json::from_value(value).unwrap();
+        check(&specs).unwrap();
+        specs
+    }
+
+    #[test]
+    fn quotes_group_words_by_position_and_by_name() {
+        let specs = specs(json!([
+            { "name": "path", "type": "path", "required": true },
+            { "name": "lines", "type": "int", "default": 20 },
+        ]));
+        let values = parse(&specs, r#""/tmp/my notes.txt" 5"#).unwrap();
+        assert_eq!(Value::Object(values), json!({ "path": "/tmp/my notes.txt", "lines": 5 }));
+
+        let values = parse(&specs, r#"lines=3 path='/tmp/a b'"#).unwrap();
+        assert_eq!(Value::Object(values), json!({ "path": "/tmp/a b", "lines": 3 }));
+    }
+
+    #[test]
+    fn unmatched_quote_is_literal() {
+        let specs = specs(json!([{ "name": "text", "required": true }]));
+        let values = parse(&specs, r#"say "hi"#).unwrap();
+        assert_eq!(values["text"], json!(r#"say "hi"#));
+    }
+
+    #[test]
+    fn trailing_string_takes_the_rest_of_the_line() {
+        let specs = specs(json!([
+            { "name": "when", "type": "enum", "values": ["today", "tomorrow"], "required": true },
+            { "name": "text", "required": true },
+        ]));
+        let values = parse(&specs, "Tomorrow buy  milk and eggs").unwrap();
+        assert_eq!(Value::Object(values), json!({ "when": "tomorrow", "text": "buy milk and eggs" }));
+
+        let values = parse(&specs, r#"text="call mum" today"#).unwrap();
+        assert_eq!(Value::Object(values), json!({ "when": "today", "text": "call mum" }));
+    }
+
+    #[test]
+    fn only_a_trailing_string_swallows_extra_words() {
+        let specs = specs(json!([
+            { "name": "text", "required": true },
+            { "name": "lines", "type": "int", "default": 20 },
+        ]));
+        assert_eq!(parse(&specs, "hello 5").unwrap()["lines"], json!(5));
+        assert_eq!(parse(&specs, "hello 5 6").unwrap_err(), "unexpected argument `6`");
+    }
+
+    #[test]
+    fn missing_and_repeated_arguments_are_rejected() {
+        let specs = specs(json!([
+            { "name": "city", "required": true },
+            { "name": "days", "type": "int", "default": 1 },
+        ]));
+        assert_eq!(parse(&specs, "").unwrap_err(), "missing `city`");
+        assert_eq!(parse(&specs, "days=2 days=3 Oslo").unwrap_err(), "`days` given twice");
+        assert_eq!(parse(&specs, "Oslo").unwrap()["days"], json!(1));
+    }
+}
@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn};
+
+use super::{DaemonPool, PluginRegistry};
+use crate::memory::MemoryStore;
+
+/// Wait this long after a change in the plugin directory before reloading,
+/// so copying a binary and its manifest triggers one reload, not several.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// ─── Handle ──────────────────────────────────────────────────────────────────
+
+/// The live plugin registry, swapped atomically on reload.
+///
+/// Requests take a snapshot with [`current`](Self::current) and use it to the
+/// end, so a plugin that is running while a reload removes or replaces it
+/// finishes normally.
+#[derive(Clone)]
+pub struct PluginHandle {
+    current: Arc<RwLock<Arc<PluginRegistry>>>,
+    plugin_dir: PathBuf,
+    /// One reload at a time, so each diff is against the registry it replaces.
+    reloading: Arc<Mutex<()>>,
+    /// Running `daemon` plugins; a reload retires those it changed or removed.
+    daemons: Arc<DaemonPool>,
+}
+
+/// What a reload changed, by plugin name.
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// Plugins registered after the reload.
+    pub plugins: usize,
+}
+
+impl PluginHandle {
+    pub fn new(registry: PluginRegistry) -> Self {
+        Self {
+            plugin_dir: registry.plugin_dir().to_path_buf(),
+            current: Arc::new(RwLock::new(Arc::new(registry))),
+            reloading: Arc::new(Mutex::new(())),
+            daemons: Arc::new(DaemonPool::default()),
+        }
+    }
+
+    /// Long-lived plugin processes, shared across reloads.
+    pub fn daemons(&self) -> &DaemonPool {
+        &self.daemons
+    }
+
+    /// The registry as of now.
+    pub fn current(&self) -> Arc<PluginRegistry> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Rescan the plugin directory, swap in the new registry and record every
+    /// added, removed or changed plugin in the audit log. `trigger` says what
+    /// asked for the reload (`watch`, `sighup`, `admin`).
+    pub async fn reload(&self, memory: &MemoryStore, trigger: &str) -> ReloadReport {
+        let _guard = self.reloading.lock().await;
+
+        let dir = self.plugin_dir.to_string_lossy().to_string();
+        let current = self.current();
+        let (policy, settings) = (current.policy().clone(), current.settings().clone());
+        let fresh = match tokio::task::spawn_blocking(move || PluginRegistry::load(&dir, &policy, &settings)).await {
+            Ok(registry) => Arc::new(registry),
+            Err(e) => {
+                error!(error = %e, "Plugin reload task failed");
+                return ReloadReport { plugins: self.current().manifests().len(), ..Default::default() };
+            }
+        };
+
+        let old = {
+            let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+            std::mem::replace(&mut *current, fresh.clone())
+        };
+        let report = old.diff(&fresh);
+        self.daemons.retire(report.changed.iter().chain(&report.removed));
+
+        let changes = [
+            ("plugin_added", &report.added, &fresh),
+            ("plugin_changed", &report.changed, &fresh),
+            ("plugin_removed", &report.removed, &old),
+        ];
+        for (event, names, registry) in changes {
+            for name in names {
+                let payload = serde_json::json!({
+                    "plugin": name,
+                    "version": registry.version_of(name),
+                    "trigger": trigger,
+                });
+                if let Err(e) = memory.log_audit(event, Some(&payload.to_string())).await {
+                    warn!(error = %e, event, plugin = %name, "Failed to write audit log");
+                }
+            }
+        }
+
+        info!(
+            trigger,
+            added   = ?report.added,
+            removed = ?report.removed,
+            changed = ?report.changed,
+            plugins = report.plugins,
+            "Plugin registry reloaded"
+        );
+        report
+    }
+}
+
+// ─── Triggers ────────────────────────────────────────────────────────────────
+
+/// Reload the registry when files in the plugin directory change (inotify) and
+/// on SIGHUP. The admin endpoint calls [`PluginHandle::reload`] directly.
+pub fn spawn_watchers(handle: PluginHandle, memory: Arc<MemoryStore>) {
+    let (tx, mut rx) = mpsc::channel::<()>(1);
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                // A reload is already pending if the channel is full.
+                let _ = tx.try_send(());
+            }
+        }
+    })
+    .and_then(|mut w| w.watch(&handle.plugin_dir, RecursiveMode::NonRecursive).map(|_| w));
+
+    match watcher {
+        Ok(watcher) => {
+            info!(dir = %handle.plugin_dir.display(), "Watching plugin directory for changes");
+            let handle = handle.clone();
+            let memory = memory.clone();
+            tokio::spawn(async move {
+                // Dropping the watcher stops the notifications.
+                let _watcher = watcher;
+                while rx.recv().await.is_some() {
+                    tokio::time::sleep(WATCH_DEBOUNCE).await;
+                    while rx.try_recv().is_ok() {}
+                    handle.reload(&memory, "watch").await;
+                }
+            });
+        }
+        Err(e) => warn!(
+            dir = %handle.plugin_dir.display(),
+            error = %e,
+            "Cannot watch plugin directory — reload with SIGHUP or the admin API"
+        ),
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(error = %e, "Cannot install SIGHUP handler");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP — reloading plugins");
+            handle.reload(&memory, "sighup").await;
+        }
+    });
+}
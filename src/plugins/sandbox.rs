@@ -0,0 +1,605 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+// ─── Capabilities ────────────────────────────────────────────────────────────
+
+/// What a plugin may reach beyond the base system, declared as `capabilities`
+/// in its manifest. The sandbox grants exactly this and nothing more:
+///
+/// ```json
+/// "capabilities": { "network": true, "fs_read": ["/proc"], "fs_write": ["/var/lib/broai/data"] }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Capabilities {
+    /// Share the host network. Without it the plugin has no network interfaces.
+    #[serde(default)]
+    pub network: bool,
+    /// Paths visible read-only. `/proc` is a fresh procfs showing only the plugin's own processes.
+    #[serde(default)]
+    pub fs_read: Vec<PathBuf>,
+    /// Paths visible read-write.
+    #[serde(default)]
+    pub fs_write: Vec<PathBuf>,
+    /// GPIO devices (`/dev/gpiomem`, `/dev/gpiochip*`, sysfs GPIO).
+    #[serde(default)]
+    pub gpio: bool,
+}
+
+impl Capabilities {
+    /// Check the declared paths once, at load time.
+    pub fn check(&self) -> Result<(), String> {
+        for path in self.fs_read.iter().chain(&self.fs_write) {
+            if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+                return Err(format!("capability path `{}` must be absolute without `..`", path.display()));
+            }
+            if path == Path::new("/") {
+                return Err("capabilities cannot grant `/`".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build the command that runs `image`, the verified contents of `binary`,
+/// inside a sandbox granting `caps`, with its CPU time capped at `cpu_limit`
+/// if given. The sandbox runs its own copy of `image`, never the file.
+#[cfg(target_os = "linux")]
+pub fn command(binary: &Path, image: Vec<u8>, caps: &Capabilities, cpu_limit: Option<Duration>) -> io::Result<Command> {
+    linux::command(binary, image, caps, cpu_limit)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn command(
+    _binary: &Path,
+    _image: Vec<u8>,
+    _caps: &Capabilities,
+    _cpu_limit: Option<Duration>,
+) -> io::Result<Command> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the plugin sandbox needs Linux; set PLUGIN_SANDBOX=off"))
+}
+
+/// Build the command that runs `image`, the verified contents of `binary`,
+/// without a sandbox: from a sealed in-memory copy, so replacing `binary`
+/// after it was read changes nothing.
+#[cfg(target_os = "linux")]
+pub fn unsandboxed_command(binary: &Path, image: &[u8]) -> io::Result<Command> {
+    linux::sealed_command(binary, image)
+}
+
+/// Without memfds the file is run by path, so a swap after the check goes unnoticed.
+#[cfg(not(target_os = "linux"))]
+pub fn unsandboxed_command(binary: &Path, _image: &[u8]) -> io::Result<Command> {
+    Ok(Command::new(binary))
+}
+
+/// Run `true` in a sandbox with no capabilities, to find out at startup
+/// whether this host allows what the sandbox needs: unprivileged user
+/// namespaces (often restricted by AppArmor or sysctls) and seccomp.
+pub fn probe() -> io::Result<()> {
+    let binary = ["/usr/bin/true", "/bin/true"]
+        .into_iter()
+        .map(Path::new)
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no `true` binary to test the sandbox with"))?;
+    let status = command(binary, std::fs::read(binary)?, &Capabilities::default(), None)?
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("sandboxed test run failed with {}", status)));
+    }
+    Ok(())
+}
+
+// ─── Linux ───────────────────────────────────────────────────────────────────
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::BTreeMap;
+    use std::ffi::{CStr, CString};
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter, SeccompRule,
+        TargetArch,
+    };
+    use super::Capabilities;
+
+    /// Always visible read-only: the loader, shared libraries, interpreters
+    /// and the local timezone. Paths missing on this system are skipped.
+    const BASE_READ: &[&str] = &[
+        "/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc/alternatives", "/etc/ld.so.cache", "/etc/localtime",
+    ];
+    /// Device nodes every program may expect.
+    const BASE_DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/full", "/dev/random", "/dev/urandom"];
+    /// Name resolution and TLS roots, added with `network`.
+    const NETWORK_READ: &[&str] = &[
+        "/etc/resolv.conf", "/etc/hosts", "/etc/nsswitch.conf", "/etc/gai.conf", "/etc/ssl", "/etc/ca-certificates",
+        "/etc/pki",
+    ];
+    /// Added with `gpio`, besides every `/dev/gpiochip*`.
+    const GPIO_WRITE: &[&str] = &["/dev/gpiomem", "/sys/class/gpio", "/sys/devices/platform"];
+    const GPIO_READ: &[&str] = &["/sys/firmware/devicetree"];
+
+    const LIMIT_ADDRESS_SPACE: u64 = 1 << 30;
+    const LIMIT_FILE_SIZE: u64 = 64 << 20;
+    const LIMIT_OPEN_FILES: u64 = 64;
+    /// Size of the plugin's private, writable `/tmp`.
+    const TMP_SIZE: &CStr = c"mode=1777,size=16m";
+    /// Directory, inside the sandbox, holding the copy of the plugin that runs.
+    const EXE_DIR: &str = ".broai";
+
+    /// Everything the child needs, computed before `fork` so that the code
+    /// running between `fork` and `exec` makes plain syscalls and never allocates.
+    struct Plan {
+        root: CString,
+        tmp: CString,
+        /// `EXE_DIR`, the options of the tmpfs mounted there, and the plugin's path in it.
+        exe_dir: CString,
+        exe_mount: CString,
+        exe: CString,
+        /// The verified binary, written to `exe`.
+        image: Vec<u8>,
+        proc: Option<CString>,
+        binds: Vec<Bind>,
+        namespaces: libc::c_int,
+        uid_map: Vec<u8>,
+        gid_map: Vec<u8>,
+        rlimits: Vec<(libc::__rlimit_resource_t, libc::rlimit)>,
+        /// Highest fd that may be open, for closing inherited fds without `close_range`.
+        max_fd: libc::c_int,
+        filters: &'static [BpfProgram],
+    }
+
+    struct Bind {
+        src: CString,
+        dst: CString,
+        /// Directories above `dst` inside the new root, outermost first.
+        parents: Vec<CString>,
+        is_dir: bool,
+        writable: bool,
+        /// Flags the source mount already has; a remount must keep them.
+        locked: libc::c_ulong,
+    }
+
+    pub fn command(binary: &Path, image: Vec<u8>, caps: &Capabilities, cpu_limit: Option<Duration>) -> io::Result<Command> {
+        let name = binary.file_name().ok_or_else(|| io::Error::other("plugin binary has no file name"))?;
+        let exe = Path::new(EXE_DIR).join(name);
+        let plan = Plan::new(&exe, image, caps, cpu_limit)?;
+
+        let mut command = Command::new(Path::new("/").join(&exe));
+        command.arg0(binary);
+        command.env_clear().env("PATH", "/usr/local/bin:/usr/bin:/bin").env("HOME", "/tmp");
+        for var in ["LANG", "LC_ALL", "TZ"] {
+            if let Some(value) = std::env::var_os(var) {
+                command.env(var, value);
+            }
+        }
+        // SAFETY: `enter` only makes async-signal-safe syscalls on data
+        // prepared above; it does not allocate or take locks.
+        unsafe {
+            command.pre_exec(move || enter(&plan));
+        }
+        Ok(command)
+    }
+
+    /// A sealed memfd holding `image`, run through `/proc/self/fd`.
+    pub fn sealed_command(binary: &Path, image: &[u8]) -> io::Result<Command> {
+        // SAFETY: memfd_create returns a new fd or -1; the File takes ownership.
+        let memfd = unsafe { libc::memfd_create(c"broai-plugin".as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+        check(memfd)?;
+        let mut writer = unsafe { File::from_raw_fd(memfd) };
+        writer.write_all(image)?;
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        // SAFETY: fcntl on an fd we own.
+        check(unsafe { libc::fcntl(memfd, libc::F_ADD_SEALS, seals) })?;
+        // exec refuses a file that is open for writing, so keep a read-only fd only.
+        let exe = File::open(format!("/proc/self/fd/{}", memfd))?;
+        drop(writer);
+
+        let mut command = Command::new(format!("/proc/self/fd/{}", exe.as_raw_fd()));
+        command.arg0(binary);
+        // SAFETY: fcntl is async-signal-safe. Clearing close-on-exec in the
+        // child lets a script's interpreter open the path it is given; `exe`
+        // itself lives, and is closed, with the command.
+        unsafe {
+            command.pre_exec(move || check(libc::fcntl(exe.as_raw_fd(), libc::F_SETFD, 0)));
+        }
+        Ok(command)
+    }
+
+    impl Plan {
+        fn new(exe: &Path, image: Vec<u8>, caps: &Capabilities, cpu_limit: Option<Duration>) -> io::Result<Self> {
+            let root = sandbox_root()?;
+
+            // path → writable; a path granted both ways is writable
+            let mut grants: BTreeMap<PathBuf, bool> = BTreeMap::new();
+            let mut grant = |path: &Path, writable: bool| {
+                if path.exists() {
+                    *grants.entry(path.to_path_buf()).or_default() |= writable;
+                }
+            };
+            BASE_READ.iter().for_each(|p| grant(Path::new(p), false));
+            BASE_DEVICES.iter().for_each(|p| grant(Path::new(p), true));
+            if caps.network {
+                NETWORK_READ.iter().for_each(|p| grant(Path::new(p), false));
+            }
+            if caps.gpio {
+                GPIO_WRITE.iter().for_each(|p| grant(Path::new(p), true));
+                GPIO_READ.iter().for_each(|p| grant(Path::new(p), false));
+                for entry in std::fs::read_dir("/dev")?.flatten() {
+                    if entry.file_name().as_bytes().starts_with(b"gpiochip") {
+                        grant(&entry.path(), true);
+                    }
+                }
+            }
+            let proc = caps.gpio || caps.fs_read.iter().any(|p| p == Path::new("/proc"));
+            caps.fs_read.iter().filter(|p| *p != Path::new("/proc")).for_each(|p| grant(p, false));
+            caps.fs_write.iter().for_each(|p| grant(p, true));
+
+            // Parents before children, so nested grants mount on top
+            let mut grants: Vec<(PathBuf, bool)> = grants.into_iter().collect();
+            grants.sort_by_key(|(path, _)| path.components().count());
+            let binds = grants
+                .into_iter()
+                .map(|(path, writable)| Bind::new(&root, &path, writable))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            let mut namespaces = libc::CLONE_NEWUSER
+                | libc::CLONE_NEWNS
+                | libc::CLONE_NEWPID
+                | libc::CLONE_NEWIPC
+                | libc::CLONE_NEWUTS;
+            if !caps.network {
+                namespaces |= libc::CLONE_NEWNET;
+            }
+
+            // SAFETY: getuid/getgid cannot fail; getrlimit writes into `open`.
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            let mut open = libc::rlimit { rlim_cur: 1024, rlim_max: 1024 };
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut open) };
+            let limit = |n: u64| libc::rlimit { rlim_cur: n, rlim_max: n };
+            let mut rlimits = vec![
+                (libc::RLIMIT_AS, limit(LIMIT_ADDRESS_SPACE)),
+                (libc::RLIMIT_FSIZE, limit(LIMIT_FILE_SIZE)),
+                (libc::RLIMIT_NOFILE, limit(LIMIT_OPEN_FILES)),
+                (libc::RLIMIT_CORE, limit(0)),
+            ];
+            if let Some(cpu) = cpu_limit {
+                let secs = cpu.as_secs().max(1);
+                rlimits.push((libc::RLIMIT_CPU, libc::rlimit { rlim_cur: secs, rlim_max: secs + 1 }));
+            }
+
+            let exe_mount = format!("mode=0755,size={}", image.len() + (1 << 20));
+            Ok(Self {
+                tmp: cstring(&root.join("tmp"))?,
+                exe_dir: cstring(&root.join(EXE_DIR))?,
+                exe_mount: CString::new(exe_mount).map_err(io::Error::other)?,
+                exe: cstring(&root.join(exe))?,
+                image,
+                proc: proc.then(|| cstring(&root.join("proc"))).transpose()?,
+                root: cstring(&root)?,
+                binds,
+                namespaces,
+                // The same ids inside as outside, so file ownership looks normal
+                uid_map: format!("{0} {0} 1\n", uid).into_bytes(),
+                gid_map: format!("{0} {0} 1\n", gid).into_bytes(),
+                rlimits,
+                max_fd: open.rlim_cur.min(65536) as libc::c_int,
+                filters: seccomp_filters()?,
+            })
+        }
+    }
+
+    impl Bind {
+        fn new(root: &Path, path: &Path, writable: bool) -> io::Result<Self> {
+            let inside = root.join(path.strip_prefix("/").unwrap_or(path));
+            let parents = inside
+                .ancestors()
+                .skip(1)
+                .take_while(|p| *p != root)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+                .map(cstring)
+                .collect::<io::Result<Vec<_>>>()?;
+
+            let src = cstring(path)?;
+            // SAFETY: `stat` is a plain output buffer.
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            let locked = if unsafe { libc::statvfs(src.as_ptr(), &mut stat) } == 0 {
+                [
+                    (libc::ST_RDONLY, libc::MS_RDONLY),
+                    (libc::ST_NOSUID, libc::MS_NOSUID),
+                    (libc::ST_NODEV, libc::MS_NODEV),
+                    (libc::ST_NOEXEC, libc::MS_NOEXEC),
+                    (libc::ST_NOATIME, libc::MS_NOATIME),
+                    (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+                    (libc::ST_RELATIME, libc::MS_RELATIME),
+                ]
+                .iter()
+                .filter(|(st, _)| stat.f_flag & st != 0)
+                .fold(0, |acc, (_, ms)| acc | ms)
+            } else {
+                0
+            };
+
+            Ok(Self {
+                src,
+                dst: cstring(&inside)?,
+                parents,
+                is_dir: path.is_dir(),
+                writable,
+                locked,
+            })
+        }
+    }
+
+    /// Empty directory that each sandbox mounts its own root over, inside its
+    /// private mount namespace.
+    fn sandbox_root() -> io::Result<PathBuf> {
+        let root = std::env::temp_dir().join("broai-sandbox");
+        if let Err(e) = std::fs::create_dir(&root) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+        let meta = std::fs::symlink_metadata(&root)?;
+        // SAFETY: geteuid cannot fail.
+        if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } {
+            return Err(io::Error::other(format!("{} is not a directory owned by broai", root.display())));
+        }
+        Ok(root)
+    }
+
+    fn cstring(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+    }
+
+    // ─── Child side ──────────────────────────────────────────────────────────
+
+    fn check(rc: libc::c_int) -> io::Result<()> {
+        if rc == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    /// Runs in the forked child, between `fork` and `exec`.
+    fn enter(plan: &Plan) -> io::Result<()> {
+        // SAFETY: every call below is a raw syscall on NUL-terminated strings
+        // and buffers owned by `plan`.
+        unsafe {
+            check(libc::unshare(plan.namespaces))?;
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", &plan.uid_map)?;
+            write_file(c"/proc/self/gid_map", &plan.gid_map)?;
+
+            // The new PID namespace applies to children: fork once more so the
+            // plugin runs as its pid 1, and stay behind to pass on its exit status.
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {}
+                child => supervise(child, plan.max_fd),
+            }
+            // Killing the supervisor (on timeout) takes the whole sandbox with it
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0))?;
+
+            let none = std::ptr::null();
+            check(libc::mount(none, c"/".as_ptr(), none, libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()))?;
+            let tmpfs = c"tmpfs".as_ptr();
+            let flags = libc::MS_NOSUID | libc::MS_NODEV;
+            check(libc::mount(tmpfs, plan.root.as_ptr(), tmpfs, flags, c"mode=0755,size=1m".as_ptr().cast()))?;
+            libc::mkdir(plan.tmp.as_ptr(), 0o1777);
+            check(libc::mount(tmpfs, plan.tmp.as_ptr(), tmpfs, flags, TMP_SIZE.as_ptr().cast()))?;
+            if let Some(proc) = &plan.proc {
+                libc::mkdir(proc.as_ptr(), 0o555);
+                let proc_fs = c"proc".as_ptr();
+                check(libc::mount(proc_fs, proc.as_ptr(), proc_fs, flags | libc::MS_NOEXEC, std::ptr::null()))?;
+            }
+
+            for bind in &plan.binds {
+                for dir in &bind.parents {
+                    libc::mkdir(dir.as_ptr(), 0o755);
+                }
+                if bind.is_dir {
+                    libc::mkdir(bind.dst.as_ptr(), 0o755);
+                } else {
+                    let fd = libc::open(bind.dst.as_ptr(), libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC, 0o644);
+                    if fd >= 0 {
+                        libc::close(fd);
+                    }
+                }
+                let bind_flags = libc::MS_BIND | libc::MS_REC;
+                check(libc::mount(bind.src.as_ptr(), bind.dst.as_ptr(), none, bind_flags, std::ptr::null()))?;
+                restrict(bind)?;
+            }
+
+            // The plugin runs from its own copy of the verified bytes, so the
+            // file on disk being replaced meanwhile changes nothing.
+            libc::mkdir(plan.exe_dir.as_ptr(), 0o755);
+            check(libc::mount(tmpfs, plan.exe_dir.as_ptr(), tmpfs, flags, plan.exe_mount.as_ptr().cast()))?;
+            write_image(plan)?;
+            let read_only = libc::MS_REMOUNT | libc::MS_RDONLY | flags;
+            check(libc::mount(none, plan.exe_dir.as_ptr(), none, read_only, std::ptr::null()))?;
+
+            let remount = libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV;
+            check(libc::mount(none, plan.root.as_ptr(), none, remount, std::ptr::null()))?;
+            check(libc::chdir(plan.root.as_ptr()))?;
+            check(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int)?;
+            check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+            check(libc::chdir(c"/tmp".as_ptr()))?;
+
+            // Only now, so the limits don't cut the copy above short.
+            for (resource, limit) in &plan.rlimits {
+                check(libc::setrlimit(*resource, limit))?;
+            }
+            for filter in plan.filters {
+                seccompiler::apply_filter(filter).map_err(|_| io::Error::last_os_error())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make a bind mount and everything below it nosuid, and read-only unless
+    /// writable. Kernels before 5.12 lack `mount_setattr`; there only the top
+    /// mount is remounted.
+    unsafe fn restrict(bind: &Bind) -> io::Result<()> {
+        let attr = libc::mount_attr {
+            attr_set: libc::MOUNT_ATTR_NOSUID | if bind.writable { 0 } else { libc::MOUNT_ATTR_RDONLY },
+            attr_clr: 0,
+            propagation: 0,
+            userns_fd: 0,
+        };
+        let rc = libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            bind.dst.as_ptr(),
+            libc::AT_RECURSIVE,
+            &attr as *const libc::mount_attr,
+            std::mem::size_of::<libc::mount_attr>(),
+        );
+        if rc == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ENOSYS) {
+            return Err(err);
+        }
+        let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_NOSUID | bind.locked;
+        if !bind.writable {
+            flags |= libc::MS_RDONLY;
+        }
+        check(libc::mount(std::ptr::null(), bind.dst.as_ptr(), std::ptr::null(), flags, std::ptr::null()))
+    }
+
+    unsafe fn write_image(plan: &Plan) -> io::Result<()> {
+        let flags = libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY | libc::O_CLOEXEC;
+        let fd = libc::open(plan.exe.as_ptr(), flags, 0o555);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut rest = plan.image.as_slice();
+        while !rest.is_empty() {
+            let written = libc::write(fd, rest.as_ptr().cast(), rest.len());
+            if written <= 0 {
+                let err = match written {
+                    0 => io::ErrorKind::WriteZero.into(),
+                    _ => io::Error::last_os_error(),
+                };
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                libc::close(fd);
+                return Err(err);
+            }
+            rest = &rest[written as usize..];
+        }
+        check(libc::close(fd))
+    }
+
+    unsafe fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        libc::close(fd);
+        if written != data.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait for the sandboxed plugin and exit with its status.
+    unsafe fn supervise(child: libc::pid_t, max_fd: libc::c_int) -> ! {
+        // Close everything but stdio — in particular the pipe std uses to
+        // report exec errors, or spawn() would wait until the plugin exits.
+        if libc::syscall(libc::SYS_close_range, 3u32, u32::MAX, 0u32) != 0 {
+            for fd in 3..max_fd {
+                libc::close(fd);
+            }
+        }
+        let mut status = 0;
+        while libc::waitpid(child, &mut status, 0) == -1
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+        {}
+        let code = if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 128 + libc::WTERMSIG(status) };
+        libc::_exit(code)
+    }
+
+    // ─── Seccomp ─────────────────────────────────────────────────────────────
+
+    /// Syscalls a plugin never needs: mounting, namespaces, tracing other
+    /// processes, kernel modules and keyrings, clocks and reboot.
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_mount, libc::SYS_umount2, libc::SYS_pivot_root, libc::SYS_chroot,
+        libc::SYS_fsopen, libc::SYS_fsconfig, libc::SYS_fsmount, libc::SYS_fspick,
+        libc::SYS_move_mount, libc::SYS_open_tree, libc::SYS_mount_setattr,
+        libc::SYS_unshare, libc::SYS_setns,
+        libc::SYS_ptrace, libc::SYS_process_vm_readv, libc::SYS_process_vm_writev, libc::SYS_pidfd_getfd,
+        libc::SYS_init_module, libc::SYS_finit_module, libc::SYS_delete_module,
+        libc::SYS_kexec_load, libc::SYS_kexec_file_load, libc::SYS_reboot,
+        libc::SYS_bpf, libc::SYS_perf_event_open, libc::SYS_userfaultfd,
+        libc::SYS_keyctl, libc::SYS_add_key, libc::SYS_request_key,
+        libc::SYS_swapon, libc::SYS_swapoff, libc::SYS_acct, libc::SYS_quotactl, libc::SYS_syslog,
+        libc::SYS_settimeofday, libc::SYS_clock_settime, libc::SYS_clock_adjtime, libc::SYS_adjtimex,
+        libc::SYS_open_by_handle_at, libc::SYS_name_to_handle_at,
+        libc::SYS_sethostname, libc::SYS_setdomainname, libc::SYS_vhangup,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    const DENIED_ARCH: &[libc::c_long] = &[libc::SYS_iopl, libc::SYS_ioperm, libc::SYS_uselib];
+    #[cfg(not(target_arch = "x86_64"))]
+    const DENIED_ARCH: &[libc::c_long] = &[];
+
+    /// `clone` flags that would create namespaces.
+    const NAMESPACE_FLAGS: &[libc::c_int] = &[
+        libc::CLONE_NEWUSER, libc::CLONE_NEWNS, libc::CLONE_NEWPID, libc::CLONE_NEWNET,
+        libc::CLONE_NEWIPC, libc::CLONE_NEWUTS, libc::CLONE_NEWCGROUP,
+    ];
+
+    /// Two filters: denied syscalls fail with EPERM, and `clone3` — whose
+    /// flags a filter cannot inspect — fails with ENOSYS so libc falls back
+    /// to `clone`, which is checked. Without them no plugin runs sandboxed.
+    fn seccomp_filters() -> io::Result<&'static [BpfProgram]> {
+        static FILTERS: OnceLock<Result<Vec<BpfProgram>, String>> = OnceLock::new();
+        match FILTERS.get_or_init(|| build_filters().map_err(|e| e.to_string())) {
+            Ok(filters) => Ok(filters),
+            Err(e) => Err(io::Error::other(format!("cannot build the seccomp filter: {}", e))),
+        }
+    }
+
+    // `c_long` is only `i64` on 64-bit targets
+    #[allow(clippy::unnecessary_cast)]
+    fn build_filters() -> Result<Vec<BpfProgram>, Box<dyn std::error::Error>> {
+        let arch = TargetArch::try_from(std::env::consts::ARCH)?;
+
+        let mut denied: BTreeMap<i64, Vec<SeccompRule>> = DENIED.iter().chain(DENIED_ARCH).map(|&nr| (nr as i64, Vec::new())).collect();
+        let clone_rules = NAMESPACE_FLAGS
+            .iter()
+            .map(|&flag| {
+                let flag = flag as u64;
+                SeccompRule::new(vec![SeccompCondition::new(0, SeccompCmpArgLen::Qword, SeccompCmpOp::MaskedEq(flag), flag)?])
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        denied.insert(libc::SYS_clone as i64, clone_rules);
+        let deny = SeccompFilter::new(denied, SeccompAction::Allow, SeccompAction::Errno(libc::EPERM as u32), arch)?;
+
+        let clone3 = BTreeMap::from([(libc::SYS_clone3 as i64, Vec::new())]);
+        let no_clone3 = SeccompFilter::new(clone3, SeccompAction::Allow, SeccompAction::Errno(libc::ENOSYS as u32), arch)?;
+
+        Ok(vec![deny.try_into()?, no_clone3.try_into()?])
+    }
+}
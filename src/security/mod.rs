@@ -5,9 +5,14 @@ use tracing::info;
 
 use crate::errors::AppError;
 
+mod trust;
+
+pub use trust::{sha256, sign_plugin, verify_self_signed, SignaturePolicy, TrustStore, DEFAULT_TRUST_DIR};
+
 /// Device cryptographic identity using Ed25519.
 /// The keypair is generated once and persisted to disk.
 /// This gives each edge device a unique, stable identity.
+/// Plugin publishers use the same keypair type to sign their plugins.
 pub struct DeviceIdentity {
     signing_key: SigningKey,
 }
@@ -22,7 +27,7 @@ impl DeviceIdentity {
         }
     }
 
-    fn generate(key_path: &str) -> Result<Self, AppError> {
+    pub fn generate(key_path: &str) -> Result<Self, AppError> {
         let signing_key = SigningKey::generate(&mut OsRng);
         let bytes = signing_key.to_bytes();
 
@@ -31,7 +36,7 @@ impl DeviceIdentity {
             std::fs::create_dir_all(parent)?;
         }
 
-        std::fs::write(key_path, &bytes)?;
+        std::fs::write(key_path, bytes)?;
 
         // Set restrictive permissions (owner read-only)
         #[cfg(unix)]
@@ -44,7 +49,7 @@ impl DeviceIdentity {
         Ok(Self { signing_key })
     }
 
-    fn load(key_path: &str) -> Result<Self, AppError> {
+    pub fn load(key_path: &str) -> Result<Self, AppError> {
         let bytes = std::fs::read(key_path)?;
         let arr: [u8; 32] = bytes
             .try_into()
@@ -60,23 +65,14 @@ impl DeviceIdentity {
         hex::encode(vk.as_bytes())
     }
 
-    /// Sign arbitrary bytes (e.g., for plugin signature verification)
-    #[allow(dead_code)]
+    /// Sign arbitrary bytes (e.g., a plugin's signed message)
     pub fn sign(&self, data: &[u8]) -> Vec<u8> {
         use ed25519_dalek::Signer;
         self.signing_key.sign(data).to_bytes().to_vec()
     }
+}
 
-    /// Verify a plugin signature against this device's public key
-    #[allow(dead_code)]
-    pub fn verify_plugin_signature(&self, binary: &[u8], signature: &[u8]) -> Result<(), AppError> {
-        use ed25519_dalek::Verifier;
-        let vk: VerifyingKey = (&self.signing_key).into();
-        let sig_bytes: [u8; 64] = signature
-            .try_into()
-            .map_err(|_| AppError::SecurityError("Invalid signature length".into()))?;
-        let sig = ed25519_dalek::Signature::from_bytes(&sig_bytes);
-        vk.verify(binary, &sig)
-            .map_err(|e| AppError::SecurityError(format!("Plugin signature invalid: {}", e)))
-    }
+/// Compare secrets without leaking where they differ through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
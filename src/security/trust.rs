@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use super::DeviceIdentity;
+
+/// Prefix of every signed message, so a plugin signature can never be
+/// mistaken for a signature over anything else.
+const SIGNATURE_CONTEXT: &str = "broai-plugin-signature-v1";
+
+/// `TRUST_DIR` when unset.
+pub const DEFAULT_TRUST_DIR: &str = "/etc/broai/trusted-keys";
+
+/// Where publisher keys are kept and whether plugins must be signed.
+#[derive(Debug, Clone)]
+pub struct SignaturePolicy {
+    /// Directory of `<publisher>.pub` files, each holding a hex Ed25519 public key.
+    pub trust_dir: PathBuf,
+    /// Refuse plugins without a `.sig` file instead of loading them with a warning.
+    pub require_signed: bool,
+}
+
+/// Detached signature shipped next to a plugin as `<plugin>.sig`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PluginSignature {
+    /// Hex public key of the publisher that signed.
+    pub key: String,
+    /// Hex Ed25519 signature over [`signed_message`].
+    pub signature: String,
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// The bytes a publisher signs: the context string and the SHA-256 of the
+/// binary and of the manifest, one per line.
+pub fn signed_message(binary: &[u8], manifest: &[u8]) -> Vec<u8> {
+    format!(
+        "{}\n{}\n{}\n",
+        SIGNATURE_CONTEXT,
+        hex::encode(sha256(binary)),
+        hex::encode(sha256(manifest))
+    )
+    .into_bytes()
+}
+
+/// Sign a plugin's binary and manifest with a publisher key.
+pub fn sign_plugin(key: &DeviceIdentity, binary: &[u8], manifest: &[u8]) -> PluginSignature {
+    PluginSignature {
+        key: key.public_key_hex(),
+        signature: hex::encode(key.sign(&signed_message(binary, manifest))),
+    }
+}
+
+/// Check a `.sig` file against the key it names, trusted or not. Publishers
+/// use this to catch a stale signature before shipping a plugin.
+pub fn verify_self_signed(binary: &[u8], manifest: &[u8], sig: &str) -> Result<PluginSignature, String> {
+    let sig = parse_signature(sig)?;
+    check(&parse_key(&sig.key)?, &sig, binary, manifest).map_err(|e| format!("signature {}", e))?;
+    Ok(sig)
+}
+
+// ─── Trust store ─────────────────────────────────────────────────────────────
+
+/// Publisher public keys that plugin signatures are checked against.
+pub struct TrustStore {
+    publishers: Vec<(String, VerifyingKey)>,
+}
+
+impl TrustStore {
+    /// Read every `*.pub` file in `dir`. Unreadable or malformed keys are
+    /// skipped with a warning; a missing directory is an empty store.
+    pub fn load(dir: &Path) -> Self {
+        let mut publishers = Vec::new();
+        let read = match std::fs::read_dir(dir) {
+            Ok(r) => r,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!(dir = %dir.display(), error = %e, "Cannot read trust store");
+                }
+                return Self { publishers };
+            }
+        };
+
+        for entry in read.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("pub") {
+                continue;
+            }
+            let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|t| parse_key(&t)) {
+                Ok(key) => publishers.push((name, key)),
+                Err(e) => warn!(key = %path.display(), error = %e, "Invalid publisher key — skipping"),
+            }
+        }
+
+        info!(dir = %dir.display(), publishers = publishers.len(), "Trust store loaded");
+        Self { publishers }
+    }
+
+    /// Check a `.sig` file's contents against the binary and manifest it
+    /// claims to cover. Returns the name of the trusted publisher that signed.
+    pub fn verify(&self, binary: &[u8], manifest: &[u8], sig: &str) -> Result<&str, String> {
+        let sig = parse_signature(sig)?;
+        let key = parse_key(&sig.key)?;
+        let (publisher, _) = self
+            .publishers
+            .iter()
+            .find(|(_, trusted)| trusted == &key)
+            .ok_or_else(|| format!("signed by untrusted key {}", sig.key.trim()))?;
+        check(&key, &sig, binary, manifest).map_err(|e| format!("signature by `{}` {}", publisher, e))?;
+        Ok(publisher)
+    }
+}
+
+fn parse_signature(text: &str) -> Result<PluginSignature, String> {
+    serde_json::from_str(text).map_err(|e| format!("malformed signature file: {}", e))
+}
+
+fn check(key: &VerifyingKey, sig: &PluginSignature, binary: &[u8], manifest: &[u8]) -> Result<(), String> {
+    let bytes: [u8; 64] = hex::decode(sig.signature.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or("is not 128 hex characters")?;
+    key.verify_strict(&signed_message(binary, manifest), &Signature::from_bytes(&bytes))
+        .map_err(|_| "does not match the binary and manifest".to_string())
+}
+
+/// Parse a hex Ed25519 public key.
+fn parse_key(text: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = hex::decode(text.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or("public key must be 64 hex characters")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("invalid public key: {}", e))
+}